@@ -0,0 +1,729 @@
+use crate::pager::{Page, Pager};
+use crate::util::read_varint;
+use anyhow::{bail, Result};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageType {
+    InteriorIndex,
+    InteriorTable,
+    LeafIndex,
+    LeafTable,
+}
+
+impl PageType {
+    pub fn from_byte(byte: u8) -> Result<Self> {
+        match byte {
+            0x02 => Ok(Self::InteriorIndex),
+            0x05 => Ok(Self::InteriorTable),
+            0x0a => Ok(Self::LeafIndex),
+            0x0d => Ok(Self::LeafTable),
+            _ => bail!("Invalid b-tree page type {:#04x}", byte),
+        }
+    }
+
+    pub fn to_byte(&self) -> u8 {
+        match self {
+            Self::InteriorIndex => 0x02,
+            Self::InteriorTable => 0x05,
+            Self::LeafIndex => 0x0a,
+            Self::LeafTable => 0x0d,
+        }
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        matches!(self, Self::LeafIndex | Self::LeafTable)
+    }
+}
+
+#[derive(Debug)]
+pub struct PageHeader {
+    pub page_type: PageType,
+    pub first_freeblock: u16,
+    pub num_cells: u16,
+    pub cell_content_offset: u16,
+    pub fragmented_free_bytes: u8,
+    pub right_most_pointer: Option<u32>,
+}
+
+impl PageHeader {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let page_type = PageType::from_byte(bytes[0])?;
+        let right_most_pointer = if page_type.is_leaf() {
+            None
+        } else {
+            Some(u32::from_be_bytes(bytes[8..12].try_into()?))
+        };
+
+        Ok(Self {
+            page_type,
+            first_freeblock: u16::from_be_bytes([bytes[1], bytes[2]]),
+            num_cells: u16::from_be_bytes([bytes[3], bytes[4]]),
+            cell_content_offset: u16::from_be_bytes([bytes[5], bytes[6]]),
+            fragmented_free_bytes: bytes[7],
+            right_most_pointer,
+        })
+    }
+
+    /// Leaf pages have an 8-byte header, interior pages a 12-byte one
+    pub fn size(&self) -> usize {
+        match self.right_most_pointer {
+            Some(_) => 12,
+            None => 8,
+        }
+    }
+}
+
+/// Page 1 starts with the 100-byte database header, every other b-tree page starts with its page header
+pub fn header_offset(page_num: u64) -> usize {
+    if page_num == 1 {
+        100
+    } else {
+        0
+    }
+}
+
+pub fn parse_page(page: &[u8], page_num: u64) -> Result<(PageHeader, Vec<u16>)> {
+    let offset = header_offset(page_num);
+    let header = PageHeader::from_bytes(&page[offset..])?;
+    let array_start = offset + header.size();
+    let array_end = array_start + header.num_cells as usize * 2;
+    if array_end > page.len() {
+        bail!(
+            "Cell pointer array of page {} runs past the page end",
+            page_num
+        );
+    }
+    let cell_pointer_array = page[array_start..array_end]
+        .chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+        .collect();
+    Ok((header, cell_pointer_array))
+}
+
+/// Walk a table b-tree rooted at `page_num` in rowid order, calling `visit` with the rowid and payload of every leaf cell
+pub fn visit_table_cells<F>(pager: &mut Pager, page_num: u64, visit: &mut F) -> Result<()>
+where
+    F: FnMut(u64, &[u8]) -> Result<()>,
+{
+    let page = pager.read_page(page_num)?;
+    let (header, cell_pointer_array) = parse_page(&page, page_num)?;
+
+    match header.page_type {
+        PageType::LeafTable => {
+            for cell_pointer in cell_pointer_array {
+                let (payload_size, row_id, cell_content) =
+                    read_table_leaf_cell(&page, cell_pointer)?;
+                let payload = read_payload(pager, PageType::LeafTable, payload_size, cell_content)?;
+                visit(row_id, &payload)?;
+            }
+        }
+        PageType::InteriorTable => {
+            for cell_pointer in cell_pointer_array {
+                let left_child = read_left_child_pointer(&page, cell_pointer)?;
+                visit_table_cells(pager, left_child.into(), visit)?;
+            }
+            if let Some(right_most_pointer) = header.right_most_pointer {
+                visit_table_cells(pager, right_most_pointer.into(), visit)?;
+            }
+        }
+        page_type => bail!(
+            "Expected a table b-tree page at page {}, found {:?}",
+            page_num,
+            page_type
+        ),
+    }
+    Ok(())
+}
+
+/// Count the rows of a table b-tree from the leaf page headers, without decoding any cell.
+/// Index b-trees, which WITHOUT ROWID tables are stored in, also have an entry in every interior cell.
+pub fn count_table_cells(pager: &mut Pager, page_num: u64) -> Result<u64> {
+    let page = pager.read_page(page_num)?;
+    let (header, cell_pointer_array) = parse_page(&page, page_num)?;
+
+    match header.page_type {
+        PageType::LeafTable | PageType::LeafIndex => Ok(header.num_cells.into()),
+        PageType::InteriorTable | PageType::InteriorIndex => {
+            let mut count = match header.page_type {
+                PageType::InteriorIndex => header.num_cells.into(),
+                _ => 0,
+            };
+            for cell_pointer in cell_pointer_array {
+                let left_child = read_left_child_pointer(&page, cell_pointer)?;
+                count += count_table_cells(pager, left_child.into())?;
+            }
+            if let Some(right_most_pointer) = header.right_most_pointer {
+                count += count_table_cells(pager, right_most_pointer.into())?;
+            }
+            Ok(count)
+        }
+    }
+}
+
+/// Find the leaf cell with the given rowid by following the interior page keys,
+/// calling `visit` with its rowid and payload if the table has such a row
+pub fn visit_table_row<F>(
+    pager: &mut Pager,
+    page_num: u64,
+    row_id: u64,
+    visit: &mut F,
+) -> Result<()>
+where
+    F: FnMut(u64, &[u8]) -> Result<()>,
+{
+    let page = pager.read_page(page_num)?;
+    let (header, cell_pointer_array) = parse_page(&page, page_num)?;
+
+    match header.page_type {
+        PageType::LeafTable => {
+            let position = lower_bound(cell_pointer_array.len(), |i| {
+                let (_payload_size, cell_row_id, _rest) =
+                    read_table_leaf_cell(&page, cell_pointer_array[i])?;
+                Ok(compare_row_ids(cell_row_id, row_id))
+            })?;
+            if let Some(&cell_pointer) = cell_pointer_array.get(position) {
+                let (payload_size, cell_row_id, cell_content) =
+                    read_table_leaf_cell(&page, cell_pointer)?;
+                if cell_row_id == row_id {
+                    let payload =
+                        read_payload(pager, PageType::LeafTable, payload_size, cell_content)?;
+                    visit(row_id, &payload)?;
+                }
+            }
+        }
+        PageType::InteriorTable => {
+            // Each interior key is the largest rowid of its left subtree
+            let position = lower_bound(cell_pointer_array.len(), |i| {
+                Ok(compare_row_ids(
+                    read_interior_table_key(&page, cell_pointer_array[i])?,
+                    row_id,
+                ))
+            })?;
+            let child = match cell_pointer_array.get(position) {
+                Some(&cell_pointer) => read_left_child_pointer(&page, cell_pointer)?,
+                None => match header.right_most_pointer {
+                    Some(right_most_pointer) => right_most_pointer,
+                    None => bail!("Interior page {} has no right-most pointer", page_num),
+                },
+            };
+            visit_table_row(pager, child.into(), row_id, visit)?;
+        }
+        page_type => bail!(
+            "Expected a table b-tree page at page {}, found {:?}",
+            page_num,
+            page_type
+        ),
+    }
+    Ok(())
+}
+
+/// A page on the path of a `TableCursor`, with the cell or child the cursor is at.
+/// On interior pages, `index` equal to the number of cells stands for the right-most pointer.
+struct CursorPage {
+    page_num: u64,
+    page: Page,
+    header: PageHeader,
+    cell_pointers: Vec<u16>,
+    index: usize,
+}
+
+/// A cursor over the rows of a table b-tree in rowid order. It keeps the path of pages from the root
+/// down to the current leaf, so `seek` only reads the pages on the way to one row and `next` steps
+/// to the following row without going back to the root.
+pub struct TableCursor {
+    root: u64,
+    path: Vec<CursorPage>,
+}
+
+impl TableCursor {
+    pub fn new(root: u64) -> Self {
+        Self {
+            root,
+            path: Vec::new(),
+        }
+    }
+
+    /// Move to the first row with a rowid of at least `row_id`, returning whether there is one
+    pub fn seek(&mut self, pager: &mut Pager, row_id: i64) -> Result<bool> {
+        self.path.clear();
+        let mut page_num = self.root;
+        loop {
+            let page = pager.read_page(page_num)?;
+            let (header, cell_pointers) = parse_page(&page, page_num)?;
+            let index = match header.page_type {
+                PageType::LeafTable => lower_bound(cell_pointers.len(), |i| {
+                    Ok(compare_row_ids(
+                        read_table_leaf_cell(&page, cell_pointers[i])?.1,
+                        row_id as u64,
+                    ))
+                })?,
+                // Each interior key is the largest rowid of its left subtree
+                PageType::InteriorTable => lower_bound(cell_pointers.len(), |i| {
+                    Ok(compare_row_ids(
+                        read_interior_table_key(&page, cell_pointers[i])?,
+                        row_id as u64,
+                    ))
+                })?,
+                page_type => bail!(
+                    "Expected a table b-tree page at page {}, found {:?}",
+                    page_num,
+                    page_type
+                ),
+            };
+            let is_leaf = header.page_type.is_leaf();
+            self.path.push(CursorPage {
+                page_num,
+                page,
+                header,
+                cell_pointers,
+                index,
+            });
+            if is_leaf {
+                break;
+            }
+            page_num = self.child()?;
+        }
+        // The leaf may only hold smaller rowids, the row is then the first one of the next leaf
+        if self.is_valid() {
+            Ok(true)
+        } else {
+            self.next_leaf(pager)
+        }
+    }
+
+    /// Move to the first row of the table, returning whether the table has any
+    pub fn rewind(&mut self, pager: &mut Pager) -> Result<bool> {
+        self.seek(pager, i64::MIN)
+    }
+
+    /// Step to the next row, returning whether there is one
+    pub fn next(&mut self, pager: &mut Pager) -> Result<bool> {
+        match self.path.last_mut() {
+            Some(leaf) => leaf.index += 1,
+            None => return Ok(false),
+        }
+        if self.is_valid() {
+            Ok(true)
+        } else {
+            self.next_leaf(pager)
+        }
+    }
+
+    /// Whether the cursor is on a row
+    pub fn is_valid(&self) -> bool {
+        matches!(self.path.last(), Some(leaf) if leaf.header.page_type.is_leaf() && leaf.index < leaf.cell_pointers.len())
+    }
+
+    /// The rowid of the current row
+    pub fn row_id(&self) -> Result<i64> {
+        let (_payload_size, row_id, _cell_content) = self.current_cell()?;
+        Ok(row_id as i64)
+    }
+
+    /// The payload of the current row, read from its overflow pages when it doesn't fit on the leaf
+    pub fn payload(&self, pager: &mut Pager) -> Result<Cow<'_, [u8]>> {
+        let (payload_size, _row_id, cell_content) = self.current_cell()?;
+        read_payload(pager, PageType::LeafTable, payload_size, cell_content)
+    }
+
+    fn current_cell(&self) -> Result<(u64, u64, &[u8])> {
+        match self.path.last() {
+            Some(leaf) if self.is_valid() => {
+                read_table_leaf_cell(&leaf.page, leaf.cell_pointers[leaf.index])
+            }
+            _ => bail!("Table cursor on page {} is not on a row", self.root),
+        }
+    }
+
+    /// The page number of the child the interior page at the end of the path points to
+    fn child(&self) -> Result<u64> {
+        let Some(parent) = self.path.last() else {
+            bail!("Table cursor on page {} has no page", self.root);
+        };
+        let child = match parent.cell_pointers.get(parent.index) {
+            Some(&cell_pointer) => read_left_child_pointer(&parent.page, cell_pointer)?,
+            None => match parent.header.right_most_pointer {
+                Some(right_most_pointer) => right_most_pointer,
+                None => bail!(
+                    "Interior page {} has no right-most pointer",
+                    parent.page_num
+                ),
+            },
+        };
+        Ok(child.into())
+    }
+
+    /// Climb to the closest ancestor with a child after the current one, then go down the left
+    /// edge of that child's subtree to the next leaf with a row
+    fn next_leaf(&mut self, pager: &mut Pager) -> Result<bool> {
+        loop {
+            self.path.pop();
+            let Some(parent) = self.path.last_mut() else {
+                return Ok(false);
+            };
+            parent.index += 1;
+            if parent.index > parent.cell_pointers.len() {
+                continue;
+            }
+            loop {
+                let page_num = self.child()?;
+                let page = pager.read_page(page_num)?;
+                let (header, cell_pointers) = parse_page(&page, page_num)?;
+                let is_leaf = header.page_type.is_leaf();
+                self.path.push(CursorPage {
+                    page_num,
+                    page,
+                    header,
+                    cell_pointers,
+                    index: 0,
+                });
+                if is_leaf {
+                    break;
+                }
+            }
+            if self.is_valid() {
+                return Ok(true);
+            }
+        }
+    }
+}
+
+/// Walk an index b-tree in key order, calling `visit` with the payload of every entry for which `compare` returns `Equal`.
+/// `compare` orders an entry's payload against the searched key, so subtrees holding only smaller or larger keys are skipped.
+pub fn search_index<C, F>(
+    pager: &mut Pager,
+    page_num: u64,
+    compare: &C,
+    visit: &mut F,
+) -> Result<()>
+where
+    C: Fn(&[u8]) -> Result<Ordering>,
+    F: FnMut(&[u8]) -> Result<()>,
+{
+    let page = pager.read_page(page_num)?;
+    let (header, cell_pointer_array) = parse_page(&page, page_num)?;
+    let start = lower_bound(cell_pointer_array.len(), |i| {
+        compare(&read_index_payload(
+            pager,
+            &page,
+            header.page_type,
+            cell_pointer_array[i],
+        )?)
+    })?;
+
+    match header.page_type {
+        PageType::LeafIndex => {
+            for &cell_pointer in &cell_pointer_array[start..] {
+                let payload = read_index_payload(pager, &page, header.page_type, cell_pointer)?;
+                if compare(&payload)? != Ordering::Equal {
+                    break;
+                }
+                visit(&payload)?;
+            }
+        }
+        PageType::InteriorIndex => {
+            // Interior index cells hold entries of their own, between those of their left subtree and the next cell
+            for &cell_pointer in &cell_pointer_array[start..] {
+                let left_child = read_left_child_pointer(&page, cell_pointer)?;
+                search_index(pager, left_child.into(), compare, visit)?;
+                let payload = read_index_payload(pager, &page, header.page_type, cell_pointer)?;
+                if compare(&payload)? != Ordering::Equal {
+                    return Ok(());
+                }
+                visit(&payload)?;
+            }
+            if let Some(right_most_pointer) = header.right_most_pointer {
+                search_index(pager, right_most_pointer.into(), compare, visit)?;
+            }
+        }
+        page_type => bail!(
+            "Expected an index b-tree page at page {}, found {:?}",
+            page_num,
+            page_type
+        ),
+    }
+    Ok(())
+}
+
+/// Index cells are the payload size varint followed by the payload, preceded by the left child pointer on interior pages
+pub(crate) fn read_index_payload<'a>(
+    pager: &mut Pager,
+    page: &'a [u8],
+    page_type: PageType,
+    cell_pointer: u16,
+) -> Result<Cow<'a, [u8]>> {
+    let cell_content = match page_type {
+        PageType::InteriorIndex => &page[cell_pointer as usize + 4..],
+        _ => &page[cell_pointer as usize..],
+    };
+    let (payload_size, cell_content) = read_varint(cell_content)?;
+    read_payload(pager, page_type, payload_size, cell_content)
+}
+
+/// Index of the first of `len` sorted cells for which `compare` does not return `Less`
+pub(crate) fn lower_bound<C>(len: usize, mut compare: C) -> Result<usize>
+where
+    C: FnMut(usize) -> Result<Ordering>,
+{
+    let (mut low, mut high) = (0, len);
+    while low < high {
+        let mid = (low + high) / 2;
+        if compare(mid)? == Ordering::Less {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    Ok(low)
+}
+
+/// Order two rowids. They are signed 64-bit integers stored as varints of their bit pattern,
+/// so negative rowids sort before the others.
+pub(crate) fn compare_row_ids(left: u64, right: u64) -> Ordering {
+    (left as i64).cmp(&(right as i64))
+}
+
+/// Table leaf cells are the payload size and rowid varints followed by the payload
+pub(crate) fn read_table_leaf_cell(page: &[u8], cell_pointer: u16) -> Result<(u64, u64, &[u8])> {
+    let (payload_size, cell_content) = read_varint(&page[cell_pointer as usize..])?;
+    let (row_id, cell_content) = read_varint(cell_content)?;
+    Ok((payload_size, row_id, cell_content))
+}
+
+/// Interior table cells are the left child pointer followed by the rowid key varint
+pub(crate) fn read_interior_table_key(page: &[u8], cell_pointer: u16) -> Result<u64> {
+    Ok(read_varint(&page[cell_pointer as usize + 4..])?.0)
+}
+
+/// Size in bytes of the cell at `cell_pointer`, including the first overflow page number if the payload spills
+pub fn cell_size(
+    page: &[u8],
+    page_type: PageType,
+    cell_pointer: u16,
+    usable_size: u32,
+) -> Result<usize> {
+    let cell = &page[cell_pointer as usize..];
+    let (payload_size, rest) = match page_type {
+        PageType::InteriorTable => {
+            let (_row_id, rest) = read_varint(&cell[4..])?;
+            return Ok(cell.len() - rest.len());
+        }
+        PageType::LeafTable => {
+            let (payload_size, rest) = read_varint(cell)?;
+            (payload_size, read_varint(rest)?.1)
+        }
+        PageType::LeafIndex => read_varint(cell)?,
+        PageType::InteriorIndex => read_varint(&cell[4..])?,
+    };
+    let local_size = local_payload_size(usable_size, page_type, payload_size);
+    let overflow_pointer_size = if (local_size as u64) < payload_size {
+        4
+    } else {
+        0
+    };
+    Ok(cell.len() - rest.len() + local_size + overflow_pointer_size)
+}
+
+/// Number of payload bytes stored on the b-tree page itself, the rest spills onto overflow pages
+pub fn local_payload_size(usable_size: u32, page_type: PageType, payload_size: u64) -> usize {
+    let usable_size = usable_size as u64;
+    let max_local = match page_type {
+        PageType::LeafTable => usable_size - 35,
+        _ => (usable_size - 12) * 64 / 255 - 23,
+    };
+    if payload_size <= max_local {
+        return payload_size as usize;
+    }
+    let min_local = (usable_size - 12) * 32 / 255 - 23;
+    let local = min_local + (payload_size - min_local) % (usable_size - 4);
+    if local <= max_local {
+        local as usize
+    } else {
+        min_local as usize
+    }
+}
+
+/// Assemble the full payload of a cell whose content (after the size and rowid varints) starts at `cell_content`.
+/// The payload is borrowed from the page unless it continues on overflow pages.
+pub fn read_payload<'a>(
+    pager: &mut Pager,
+    page_type: PageType,
+    payload_size: u64,
+    cell_content: &'a [u8],
+) -> Result<Cow<'a, [u8]>> {
+    let usable_size = pager.usable_size();
+    let local_size = local_payload_size(usable_size, page_type, payload_size);
+    if local_size as u64 == payload_size {
+        return Ok(Cow::Borrowed(&cell_content[..local_size]));
+    }
+
+    let mut payload = Vec::with_capacity(payload_size as usize);
+    payload.extend_from_slice(&cell_content[..local_size]);
+    // Overflow pages start with the 4-byte page number of the next page in the chain, 0 for the last one
+    let mut overflow_page_num =
+        u32::from_be_bytes(cell_content[local_size..local_size + 4].try_into()?);
+    while payload.len() < payload_size as usize {
+        if overflow_page_num == 0 {
+            bail!(
+                "Overflow chain ended before the payload of size {} was read",
+                payload_size
+            );
+        }
+        let overflow_page = pager.read_page(overflow_page_num.into())?;
+        let remaining = payload_size as usize - payload.len();
+        let content_size = remaining.min(usable_size as usize - 4);
+        payload.extend_from_slice(&overflow_page[4..4 + content_size]);
+        overflow_page_num = u32::from_be_bytes(overflow_page[..4].try_into()?);
+    }
+    Ok(Cow::Owned(payload))
+}
+
+/// Interior cells start with the 4-byte page number of their left child
+pub(crate) fn read_left_child_pointer(page: &[u8], cell_pointer: u16) -> Result<u32> {
+    let start = cell_pointer as usize;
+    Ok(u32::from_be_bytes(page[start..start + 4].try_into()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_page_header_from_bytes() {
+        let leaf = [0x0d, 0, 0, 0, 3, 0x0f, 0x80, 0];
+        let header = PageHeader::from_bytes(&leaf).unwrap();
+        assert_eq!(header.page_type, PageType::LeafTable);
+        assert_eq!(header.num_cells, 3);
+        assert_eq!(header.cell_content_offset, 0x0f80);
+        assert_eq!(header.size(), 8);
+
+        let interior = [0x05, 0, 0, 0, 1, 0x0f, 0xf0, 0, 0, 0, 0, 7];
+        let header = PageHeader::from_bytes(&interior).unwrap();
+        assert_eq!(header.page_type, PageType::InteriorTable);
+        assert_eq!(header.right_most_pointer, Some(7));
+        assert_eq!(header.size(), 12);
+    }
+
+    #[test]
+    fn test_local_payload_size() {
+        // Small payloads stay on the page
+        assert_eq!(local_payload_size(4096, PageType::LeafTable, 100), 100);
+        assert_eq!(local_payload_size(4096, PageType::LeafTable, 4061), 4061);
+        // M = 489, K = 489 + (10000 - 489) % 4092 = 1816
+        assert_eq!(local_payload_size(4096, PageType::LeafTable, 10000), 1816);
+        // Index pages keep less locally: X = 1002
+        assert_eq!(local_payload_size(4096, PageType::LeafIndex, 1002), 1002);
+        assert_eq!(
+            local_payload_size(4096, PageType::LeafIndex, 5000),
+            489 + (5000 - 489) % 4092
+        );
+    }
+
+    #[test]
+    fn test_count_table_cells_sample_db() {
+        let mut pager = Pager::open("sample.db").unwrap();
+        // apples lives on page 2
+        assert_eq!(count_table_cells(&mut pager, 2).unwrap(), 4);
+    }
+
+    #[test]
+    fn test_table_cursor_seek_and_next() -> Result<()> {
+        let mut pager = Pager::open("sample.db")?;
+        let mut cursor = TableCursor::new(2);
+        assert!(cursor.seek(&mut pager, 2)?);
+        let mut row_ids = vec![cursor.row_id()?];
+        while cursor.next(&mut pager)? {
+            row_ids.push(cursor.row_id()?);
+        }
+        assert_eq!(row_ids, vec![2, 3, 4]);
+        assert!(!cursor.is_valid());
+        assert!(!cursor.seek(&mut pager, 5)?);
+        assert!(cursor.rewind(&mut pager)?);
+        assert_eq!(cursor.row_id()?, 1);
+        assert!(!cursor.payload(&mut pager)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_table_cursor_crosses_leaves() -> Result<()> {
+        let db_path = crate::util::tests::copy_sample_db("table-cursor");
+        let mut pager = Pager::open(&db_path)?;
+        // Only even rowids past the 4 apples, spread over several leaves
+        for row_id in (10..3000).step_by(2) {
+            crate::btree_write::insert_table_row(&mut pager, 2, row_id as u64, &[b'x'; 100])?;
+        }
+        let mut cursor = TableCursor::new(2);
+        assert!(cursor.seek(&mut pager, 5)?);
+        assert_eq!(cursor.row_id()?, 10);
+        assert!(cursor.seek(&mut pager, 1001)?);
+        let mut row_ids = vec![cursor.row_id()?];
+        while cursor.next(&mut pager)? && cursor.row_id()? <= 2000 {
+            row_ids.push(cursor.row_id()?);
+        }
+        assert_eq!(row_ids, (1002..=2000).step_by(2).collect::<Vec<i64>>());
+        assert!(!cursor.seek(&mut pager, 2999)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_table_cursor_negative_rowids() -> Result<()> {
+        let db_path = crate::util::tests::copy_sample_db("table-cursor-negative");
+        let mut pager = Pager::open(&db_path)?;
+        for row_id in [-5_i64, -1, 0, i64::MIN, i64::MAX] {
+            crate::btree_write::insert_table_row(&mut pager, 2, row_id as u64, &[b'x'; 10])?;
+        }
+        let mut cursor = TableCursor::new(2);
+        let mut row_ids = Vec::new();
+        let mut has_row = cursor.rewind(&mut pager)?;
+        while has_row {
+            row_ids.push(cursor.row_id()?);
+            has_row = cursor.next(&mut pager)?;
+        }
+        assert_eq!(row_ids, vec![i64::MIN, -5, -1, 0, 1, 2, 3, 4, i64::MAX]);
+        assert!(cursor.seek(&mut pager, -4)?);
+        assert_eq!(cursor.row_id()?, -1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_visit_table_row_sample_db() {
+        let mut pager = Pager::open("sample.db").unwrap();
+        let mut found = Vec::new();
+        for row_id in [3, 42] {
+            visit_table_row(&mut pager, 2, row_id, &mut |row_id, _payload| {
+                found.push(row_id);
+                Ok(())
+            })
+            .unwrap();
+        }
+        assert_eq!(found, vec![3]);
+    }
+
+    #[test]
+    fn test_visit_table_row_negative_rowids() -> Result<()> {
+        let db_path = crate::util::tests::copy_sample_db("negative-rowids");
+        let mut pager = Pager::open(&db_path)?;
+        // Enough rows below the 4 apples to need interior pages
+        for row_id in -1000..=0_i64 {
+            crate::btree_write::insert_table_row(&mut pager, 2, row_id as u64, &[b'x'; 100])?;
+        }
+        let mut found = Vec::new();
+        for row_id in [-1000_i64, -5, 0, 3, 5] {
+            visit_table_row(&mut pager, 2, row_id as u64, &mut |row_id, _payload| {
+                found.push(row_id as i64);
+                Ok(())
+            })?;
+        }
+        assert_eq!(found, vec![-1000, -5, 0, 3]);
+
+        let mut row_ids = Vec::new();
+        visit_table_cells(&mut pager, 2, &mut |row_id, _payload| {
+            row_ids.push(row_id as i64);
+            Ok(())
+        })?;
+        assert_eq!(row_ids, (-1000..=4).collect::<Vec<i64>>());
+        Ok(())
+    }
+}
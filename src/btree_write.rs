@@ -0,0 +1,1098 @@
+use crate::btree::{
+    cell_size, compare_row_ids, header_offset, local_payload_size, lower_bound, parse_page,
+    read_index_payload, read_interior_table_key, read_left_child_pointer, read_payload,
+    read_table_leaf_cell, PageHeader, PageType,
+};
+use crate::pager::Pager;
+use crate::util::{read_varint, write_varint};
+use anyhow::{bail, Result};
+use std::cmp::Ordering;
+
+/// Pages from the root down to a leaf, each with the position of the cell or child followed on it
+pub type Path = Vec<(u64, usize)>;
+
+/// Follow the interior table pages down to the leaf that holds, or would hold, `row_id`
+pub fn find_table_leaf(pager: &mut Pager, root: u64, row_id: u64) -> Result<Path> {
+    let mut path = Vec::new();
+    let mut page_num = root;
+    loop {
+        let page = pager.read_page(page_num)?;
+        let (header, cell_pointer_array) = parse_page(&page, page_num)?;
+        match header.page_type {
+            PageType::LeafTable => {
+                let position = lower_bound(cell_pointer_array.len(), |i| {
+                    Ok(compare_row_ids(
+                        read_table_leaf_cell(&page, cell_pointer_array[i])?.1,
+                        row_id,
+                    ))
+                })?;
+                path.push((page_num, position));
+                return Ok(path);
+            }
+            PageType::InteriorTable => {
+                let position = lower_bound(cell_pointer_array.len(), |i| {
+                    Ok(compare_row_ids(
+                        read_interior_table_key(&page, cell_pointer_array[i])?,
+                        row_id,
+                    ))
+                })?;
+                path.push((page_num, position));
+                page_num = child_at(&page, &header, &cell_pointer_array, position)?.into();
+            }
+            page_type => bail!(
+                "Expected a table b-tree page at page {}, found {:?}",
+                page_num,
+                page_type
+            ),
+        }
+    }
+}
+
+/// Follow the interior index pages down to the leaf where an entry ordered by `compare` belongs.
+/// `compare` orders an existing entry's payload against the new one.
+pub fn find_index_leaf<C>(pager: &mut Pager, root: u64, compare: &C) -> Result<Path>
+where
+    C: Fn(&[u8]) -> Result<Ordering>,
+{
+    let mut path = Vec::new();
+    let mut page_num = root;
+    loop {
+        let page = pager.read_page(page_num)?;
+        let (header, cell_pointer_array) = parse_page(&page, page_num)?;
+        if !matches!(
+            header.page_type,
+            PageType::LeafIndex | PageType::InteriorIndex
+        ) {
+            bail!(
+                "Expected an index b-tree page at page {}, found {:?}",
+                page_num,
+                header.page_type
+            );
+        }
+        let position = lower_bound(cell_pointer_array.len(), |i| {
+            compare(&read_index_payload(
+                pager,
+                &page,
+                header.page_type,
+                cell_pointer_array[i],
+            )?)
+        })?;
+        path.push((page_num, position));
+        if header.page_type == PageType::LeafIndex {
+            return Ok(path);
+        }
+        page_num = child_at(&page, &header, &cell_pointer_array, position)?.into();
+    }
+}
+
+/// The left child of the cell at `position`, or the right-most pointer past the last cell
+pub(crate) fn child_at(
+    page: &[u8],
+    header: &PageHeader,
+    cell_pointer_array: &[u16],
+    position: usize,
+) -> Result<u32> {
+    match cell_pointer_array.get(position) {
+        Some(&cell_pointer) => read_left_child_pointer(page, cell_pointer),
+        None => match header.right_most_pointer {
+            Some(right_most_pointer) => Ok(right_most_pointer),
+            None => bail!("Expected an interior page with a right-most pointer"),
+        },
+    }
+}
+
+/// The largest rowid in a table b-tree, 0 for an empty table
+pub fn max_row_id(pager: &mut Pager, root: u64) -> Result<i64> {
+    let mut page_num = root;
+    loop {
+        let page = pager.read_page(page_num)?;
+        let (header, cell_pointer_array) = parse_page(&page, page_num)?;
+        match (header.right_most_pointer, cell_pointer_array.last()) {
+            (Some(right_most_pointer), _) => page_num = right_most_pointer.into(),
+            (None, Some(&cell_pointer)) => {
+                return Ok(read_table_leaf_cell(&page, cell_pointer)?.1 as i64)
+            }
+            (None, None) => return Ok(0),
+        }
+    }
+}
+
+/// The part of `payload` stored in the cell, followed by the first overflow page number
+/// if the rest has to be written to a chain of newly allocated overflow pages
+fn build_local_payload(pager: &mut Pager, page_type: PageType, payload: &[u8]) -> Result<Vec<u8>> {
+    let usable_size = pager.usable_size();
+    let local_size = local_payload_size(usable_size, page_type, payload.len() as u64);
+    let mut local = payload[..local_size].to_vec();
+    if local_size == payload.len() {
+        return Ok(local);
+    }
+
+    let chunks = payload[local_size..]
+        .chunks(usable_size as usize - 4)
+        .collect::<Vec<&[u8]>>();
+    let mut overflow_pages = Vec::new();
+    for _ in &chunks {
+        overflow_pages.push(pager.allocate_page()?);
+    }
+    for (i, chunk) in chunks.iter().enumerate() {
+        // Overflow pages start with the next page of the chain, 0 for the last one
+        let next = overflow_pages.get(i + 1).copied().unwrap_or(0) as u32;
+        let mut page = vec![0; pager.page_size() as usize];
+        page[..4].copy_from_slice(&next.to_be_bytes());
+        page[4..4 + chunk.len()].copy_from_slice(chunk);
+        pager.write_page(overflow_pages[i], page)?;
+    }
+    local.extend_from_slice(&(overflow_pages[0] as u32).to_be_bytes());
+    Ok(local)
+}
+
+/// A table leaf cell: payload size and rowid varints, then the payload
+pub fn build_table_leaf_cell(pager: &mut Pager, row_id: u64, payload: &[u8]) -> Result<Vec<u8>> {
+    let mut cell = write_varint(payload.len() as u64);
+    cell.extend(write_varint(row_id));
+    cell.extend(build_local_payload(pager, PageType::LeafTable, payload)?);
+    Ok(cell)
+}
+
+/// An index cell: the left child pointer on interior pages, then the payload size varint and the payload
+pub fn build_index_cell(
+    pager: &mut Pager,
+    left_child: Option<u32>,
+    payload: &[u8],
+) -> Result<Vec<u8>> {
+    let (mut cell, page_type) = match left_child {
+        Some(left_child) => (left_child.to_be_bytes().to_vec(), PageType::InteriorIndex),
+        None => (Vec::new(), PageType::LeafIndex),
+    };
+    cell.extend(write_varint(payload.len() as u64));
+    cell.extend(build_local_payload(pager, page_type, payload)?);
+    Ok(cell)
+}
+
+/// Insert `cell` at `position` of the cell pointer array. Returns false, leaving the page untouched,
+/// if the unallocated space between the cell pointer array and the cell content area is too small.
+pub fn insert_cell(page: &mut [u8], page_num: u64, position: usize, cell: &[u8]) -> Result<bool> {
+    let (header, mut cell_pointer_array) = parse_page(page, page_num)?;
+    let offset = header_offset(page_num);
+    let array_end = offset + header.size() + cell_pointer_array.len() * 2;
+    // A cell content offset of 0 stands for 65536
+    let content_start = match header.cell_content_offset {
+        0 => 65536,
+        content_offset => content_offset as usize,
+    };
+    if array_end + 2 + cell.len() > content_start {
+        return Ok(false);
+    }
+
+    let cell_start = content_start - cell.len();
+    page[cell_start..content_start].copy_from_slice(cell);
+    cell_pointer_array.insert(position, cell_start as u16);
+    write_cell_pointer_array(page, page_num, &header, &cell_pointer_array);
+    page[offset + 5..offset + 7].copy_from_slice(&(cell_start as u16).to_be_bytes());
+    Ok(true)
+}
+
+/// Write the cell count and cell pointer array of a page
+pub(crate) fn write_cell_pointer_array(
+    page: &mut [u8],
+    page_num: u64,
+    header: &PageHeader,
+    cell_pointer_array: &[u16],
+) {
+    let offset = header_offset(page_num);
+    page[offset + 3..offset + 5].copy_from_slice(&(cell_pointer_array.len() as u16).to_be_bytes());
+    let array_start = offset + header.size();
+    for (i, cell_pointer) in cell_pointer_array.iter().enumerate() {
+        page[array_start + i * 2..array_start + i * 2 + 2]
+            .copy_from_slice(&cell_pointer.to_be_bytes());
+    }
+}
+
+/// Add a row to a table b-tree, failing if the rowid is taken
+pub fn insert_table_row(pager: &mut Pager, root: u64, row_id: u64, payload: &[u8]) -> Result<()> {
+    let path = find_table_leaf(pager, root, row_id)?;
+    let (leaf, position) = path[path.len() - 1];
+    let mut page = pager.read_page(leaf)?.to_vec();
+
+    let (_header, cell_pointer_array) = parse_page(&page, leaf)?;
+    if let Some(&cell_pointer) = cell_pointer_array.get(position) {
+        if read_table_leaf_cell(&page, cell_pointer)?.1 == row_id {
+            bail!("UNIQUE constraint failed: rowid {} already exists", row_id);
+        }
+    }
+
+    let cell = build_table_leaf_cell(pager, row_id, payload)?;
+    if insert_cell(&mut page, leaf, position, &cell)? {
+        return pager.write_page(leaf, page);
+    }
+    balance(pager, &path, path.len() - 1, vec![cell])
+}
+
+/// Add an entry to an index b-tree, `compare` orders existing entries against the new one
+pub fn insert_index_entry<C>(
+    pager: &mut Pager,
+    root: u64,
+    payload: &[u8],
+    compare: &C,
+) -> Result<()>
+where
+    C: Fn(&[u8]) -> Result<Ordering>,
+{
+    let cell = build_index_cell(pager, None, payload)?;
+    insert_index_cell(pager, root, cell, compare)
+}
+
+fn insert_index_cell<C>(pager: &mut Pager, root: u64, cell: Vec<u8>, compare: &C) -> Result<()>
+where
+    C: Fn(&[u8]) -> Result<Ordering>,
+{
+    let path = find_index_leaf(pager, root, compare)?;
+    let (leaf, position) = path[path.len() - 1];
+    let mut page = pager.read_page(leaf)?.to_vec();
+    if insert_cell(&mut page, leaf, position, &cell)? {
+        return pager.write_page(leaf, page);
+    }
+    balance(pager, &path, path.len() - 1, vec![cell])
+}
+
+/// Replace the payload of a row. The new cell overwrites the old one when it is not larger,
+/// otherwise it takes the old cell's place in the page like an insert, splitting the page if needed.
+pub fn update_table_row(pager: &mut Pager, root: u64, row_id: u64, payload: &[u8]) -> Result<()> {
+    let path = find_table_leaf(pager, root, row_id)?;
+    let (leaf, position) = path[path.len() - 1];
+    let mut page = pager.read_page(leaf)?.to_vec();
+    let (header, cell_pointer_array) = parse_page(&page, leaf)?;
+    let cell_pointer = match cell_pointer_array.get(position) {
+        Some(&cell_pointer) if read_table_leaf_cell(&page, cell_pointer)?.1 == row_id => {
+            cell_pointer
+        }
+        _ => bail!("No row with rowid {} to update", row_id),
+    };
+    let usable_size = pager.usable_size();
+    let old_size = cell_size(&page, PageType::LeafTable, cell_pointer, usable_size)?;
+    free_overflow_chain(pager, &page, PageType::LeafTable, cell_pointer)?;
+    let cell = build_table_leaf_cell(pager, row_id, payload)?;
+
+    if cell.len() <= old_size {
+        let start = cell_pointer as usize;
+        page[start..start + cell.len()].copy_from_slice(&cell);
+        if cell.len() < old_size {
+            free_space(
+                &mut page,
+                leaf,
+                &header,
+                start + cell.len(),
+                old_size - cell.len(),
+            )?;
+        }
+        return pager.write_page(leaf, page);
+    }
+    remove_cell(&mut page, leaf, position, usable_size)?;
+    if insert_cell(&mut page, leaf, position, &cell)? {
+        return pager.write_page(leaf, page);
+    }
+    pager.write_page(leaf, page)?;
+    balance(pager, &path, path.len() - 1, vec![cell])
+}
+
+/// Remove a row from a table b-tree, returning false if there is no row with `row_id`
+pub fn delete_table_row(pager: &mut Pager, root: u64, row_id: u64) -> Result<bool> {
+    let path = find_table_leaf(pager, root, row_id)?;
+    let (leaf, position) = path[path.len() - 1];
+    let page = pager.read_page(leaf)?;
+    let (_header, cell_pointer_array) = parse_page(&page, leaf)?;
+    let cell_pointer = match cell_pointer_array.get(position) {
+        Some(&cell_pointer) if read_table_leaf_cell(&page, cell_pointer)?.1 == row_id => {
+            cell_pointer
+        }
+        _ => return Ok(false),
+    };
+    free_overflow_chain(pager, &page, PageType::LeafTable, cell_pointer)?;
+    remove_from_tree(pager, &path, path.len() - 1)?;
+    Ok(true)
+}
+
+/// Remove the entry with `payload` from an index b-tree, returning false if the index doesn't have it.
+/// `order` sorts two entry payloads.
+pub fn delete_index_entry<O>(
+    pager: &mut Pager,
+    root: u64,
+    payload: &[u8],
+    order: &O,
+) -> Result<bool>
+where
+    O: Fn(&[u8], &[u8]) -> Result<Ordering>,
+{
+    let path = match find_index_entry(pager, root, &|entry| order(entry, payload))? {
+        Some(path) => path,
+        None => return Ok(false),
+    };
+    let depth = path.len() - 1;
+    let (page_num, position) = path[depth];
+    let mut page = pager.read_page(page_num)?.to_vec();
+    let (header, cell_pointer_array) = parse_page(&page, page_num)?;
+    free_overflow_chain(pager, &page, header.page_type, cell_pointer_array[position])?;
+
+    let displaced = if header.page_type == PageType::LeafIndex {
+        remove_from_tree(pager, &path, depth)?
+    } else {
+        // An entry on an interior page is replaced by the largest entry of its left subtree
+        let left_child = read_left_child_pointer(&page, cell_pointer_array[position])?;
+        let mut child = left_child as u64;
+        let predecessor = loop {
+            let child_page = pager.read_page(child)?;
+            let (child_header, child_cells) = parse_page(&child_page, child)?;
+            match (child_header.right_most_pointer, child_cells.last()) {
+                (Some(right_most_pointer), _) => child = right_most_pointer.into(),
+                (None, Some(&cell_pointer)) => {
+                    let size = cell_size(
+                        &child_page,
+                        PageType::LeafIndex,
+                        cell_pointer,
+                        pager.usable_size(),
+                    )?;
+                    break child_page[cell_pointer as usize..cell_pointer as usize + size].to_vec();
+                }
+                (None, None) => bail!("Index leaf page {} is empty", child),
+            }
+        };
+
+        remove_cell(&mut page, page_num, position, pager.usable_size())?;
+        pager.write_page(page_num, page.clone())?;
+        let mut cell = left_child.to_be_bytes().to_vec();
+        cell.extend_from_slice(&predecessor);
+        if insert_cell(&mut page, page_num, position, &cell)? {
+            pager.write_page(page_num, page)?;
+        } else {
+            balance(pager, &path, depth, vec![cell])?;
+        }
+
+        // Both copies of the predecessor share its overflow pages until the one in the leaf is removed.
+        // Treating it as larger than itself leads the search into its left subtree, down to that leaf.
+        let predecessor_payload = read_cell_payload(pager, &predecessor)?;
+        let leaf_path = find_index_leaf(pager, root, &|entry| {
+            Ok(order(entry, &predecessor_payload)?.then(Ordering::Greater))
+        })?;
+        remove_from_tree(pager, &leaf_path, leaf_path.len() - 1)?
+    };
+
+    // Entries taken out of interior pages above an emptied page go back in from the root
+    for cell in displaced {
+        let payload = read_cell_payload(pager, &cell)?;
+        insert_index_cell(pager, root, cell, &|entry| order(entry, &payload))?;
+    }
+    Ok(true)
+}
+
+/// Put every page of a b-tree on the freelist, with the overflow pages of its cells
+pub fn free_tree(pager: &mut Pager, root: u64) -> Result<()> {
+    let page = pager.read_page(root)?;
+    let (header, cell_pointer_array) = parse_page(&page, root)?;
+    for &cell_pointer in &cell_pointer_array {
+        free_overflow_chain(pager, &page, header.page_type, cell_pointer)?;
+        if !header.page_type.is_leaf() {
+            free_tree(pager, read_left_child_pointer(&page, cell_pointer)?.into())?;
+        }
+    }
+    if let Some(right_most_pointer) = header.right_most_pointer {
+        free_tree(pager, right_most_pointer.into())?;
+    }
+    pager.free_page(root)
+}
+
+/// Follow the index pages down to the entry for which `compare` returns `Equal`
+fn find_index_entry<C>(pager: &mut Pager, root: u64, compare: &C) -> Result<Option<Path>>
+where
+    C: Fn(&[u8]) -> Result<Ordering>,
+{
+    let mut path = Vec::new();
+    let mut page_num = root;
+    loop {
+        let page = pager.read_page(page_num)?;
+        let (header, cell_pointer_array) = parse_page(&page, page_num)?;
+        let position = lower_bound(cell_pointer_array.len(), |i| {
+            compare(&read_index_payload(
+                pager,
+                &page,
+                header.page_type,
+                cell_pointer_array[i],
+            )?)
+        })?;
+        path.push((page_num, position));
+        if let Some(&cell_pointer) = cell_pointer_array.get(position) {
+            let payload = read_index_payload(pager, &page, header.page_type, cell_pointer)?;
+            if compare(&payload)? == Ordering::Equal {
+                return Ok(Some(path));
+            }
+        }
+        if header.page_type == PageType::LeafIndex {
+            return Ok(None);
+        }
+        page_num = child_at(&page, &header, &cell_pointer_array, position)?.into();
+    }
+}
+
+/// The full payload of an index leaf cell
+fn read_cell_payload(pager: &mut Pager, cell: &[u8]) -> Result<Vec<u8>> {
+    let (payload_size, cell_content) = read_varint(cell)?;
+    Ok(read_payload(pager, PageType::LeafIndex, payload_size, cell_content)?.into_owned())
+}
+
+/// Free the overflow pages holding the rest of a cell's payload, if it spills
+fn free_overflow_chain(
+    pager: &mut Pager,
+    page: &[u8],
+    page_type: PageType,
+    cell_pointer: u16,
+) -> Result<()> {
+    let usable_size = pager.usable_size();
+    let cell = &page[cell_pointer as usize..];
+    let payload_size = match page_type {
+        PageType::InteriorTable => return Ok(()),
+        PageType::LeafTable | PageType::LeafIndex => read_varint(cell)?.0,
+        PageType::InteriorIndex => read_varint(&cell[4..])?.0,
+    };
+    if local_payload_size(usable_size, page_type, payload_size) as u64 == payload_size {
+        return Ok(());
+    }
+    let end = cell_pointer as usize + cell_size(page, page_type, cell_pointer, usable_size)?;
+    let mut overflow_page = u32::from_be_bytes(page[end - 4..end].try_into()?);
+    while overflow_page != 0 {
+        let next = u32::from_be_bytes(pager.read_page(overflow_page.into())?[..4].try_into()?);
+        pager.free_page(overflow_page.into())?;
+        overflow_page = next;
+    }
+    Ok(())
+}
+
+/// Remove the cell at `path[depth]` from its page. A page left empty is freed and dropped from its parent,
+/// and an interior page left with only its right-most child is replaced by that child.
+/// Returns the index cells that had to be taken out of interior pages, in leaf cell form.
+fn remove_from_tree(
+    pager: &mut Pager,
+    path: &[(u64, usize)],
+    depth: usize,
+) -> Result<Vec<Vec<u8>>> {
+    let (page_num, position) = path[depth];
+    let mut page = pager.read_page(page_num)?.to_vec();
+    remove_cell(&mut page, page_num, position, pager.usable_size())?;
+    let (header, cell_pointer_array) = parse_page(&page, page_num)?;
+    pager.write_page(page_num, page)?;
+    if !cell_pointer_array.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    match (header.right_most_pointer, depth) {
+        // An empty root leaf is an empty table or index
+        (None, 0) => Ok(Vec::new()),
+        (None, _) => {
+            pager.free_page(page_num)?;
+            detach_child(pager, path, depth - 1)
+        }
+        (Some(child), 0) => {
+            collapse_root(pager, page_num, child.into())?;
+            Ok(Vec::new())
+        }
+        (Some(child), _) => {
+            merge_into_sibling(pager, path, depth, child)?;
+            Ok(Vec::new())
+        }
+    }
+}
+
+/// Hand the only child of the interior page at `path[depth]`, which has no cells left, to a sibling together
+/// with the parent's divider between the two, so that all leaves stay at the same depth. The page is freed.
+fn merge_into_sibling(
+    pager: &mut Pager,
+    path: &[(u64, usize)],
+    depth: usize,
+    child: u32,
+) -> Result<()> {
+    let usable_size = pager.usable_size();
+    let page_num = path[depth].0;
+    let (parent, position) = path[depth - 1];
+    let mut parent_page = pager.read_page(parent)?.to_vec();
+    let (parent_header, parent_cells) = parse_page(&parent_page, parent)?;
+    if parent_cells.is_empty() {
+        // The parent is a root without cells that could not take in the page
+        set_child_at(&mut parent_page, parent, position, child)?;
+        pager.write_page(parent, parent_page)?;
+        pager.free_page(page_num)?;
+        return collapse_root(pager, parent, child.into());
+    }
+
+    // Merge into the sibling before the page, or the one after it for the first child
+    let divider_position = position.saturating_sub(1);
+    let divider_pointer = parent_cells[divider_position];
+    let size = cell_size(
+        &parent_page,
+        parent_header.page_type,
+        divider_pointer,
+        usable_size,
+    )?;
+    let divider = parent_page[divider_pointer as usize..divider_pointer as usize + size].to_vec();
+    let sibling = match position {
+        0 => child_at(&parent_page, &parent_header, &parent_cells, 1)?,
+        _ => read_left_child_pointer(&divider, 0)?,
+    };
+    remove_cell(&mut parent_page, parent, divider_position, usable_size)?;
+    set_child_at(&mut parent_page, parent, divider_position, sibling)?;
+    pager.write_page(parent, parent_page)?;
+    pager.free_page(page_num)?;
+
+    let mut sibling_page = pager.read_page(sibling.into())?.to_vec();
+    let (sibling_header, sibling_cells) = parse_page(&sibling_page, sibling.into())?;
+    let (left_child, sibling_position) = match (position, sibling_header.right_most_pointer) {
+        (0, _) => (child, 0),
+        (_, Some(right_most_pointer)) => {
+            // The sibling's right-most child moves into the new last cell
+            set_child_at(
+                &mut sibling_page,
+                sibling.into(),
+                sibling_cells.len(),
+                child,
+            )?;
+            (right_most_pointer, sibling_cells.len())
+        }
+        (_, None) => bail!("Expected page {} to be an interior page", sibling),
+    };
+    let mut cell = left_child.to_be_bytes().to_vec();
+    cell.extend_from_slice(&divider[4..]);
+
+    let mut sibling_path = path[..depth].to_vec();
+    sibling_path[depth - 1].1 = divider_position;
+    sibling_path.push((sibling.into(), sibling_position));
+    if insert_cell(&mut sibling_page, sibling.into(), sibling_position, &cell)? {
+        pager.write_page(sibling.into(), sibling_page)?;
+    } else {
+        pager.write_page(sibling.into(), sibling_page)?;
+        balance(pager, &sibling_path, depth, vec![cell])?;
+    }
+
+    // The parent gave up a cell, and may be left with the sibling as its only child
+    let parent_page = pager.read_page(parent)?;
+    let (parent_header, parent_cells) = parse_page(&parent_page, parent)?;
+    match (
+        parent_cells.is_empty(),
+        parent_header.right_most_pointer,
+        depth - 1,
+    ) {
+        (true, Some(only_child), 0) => collapse_root(pager, parent, only_child.into()),
+        (true, Some(only_child), _) => merge_into_sibling(pager, path, depth - 1, only_child),
+        _ => Ok(()),
+    }
+}
+
+/// Drop the pointer to the freed child at `path[depth]`, along with the cell before it if it was the right-most one
+fn detach_child(pager: &mut Pager, path: &[(u64, usize)], depth: usize) -> Result<Vec<Vec<u8>>> {
+    let (page_num, position) = path[depth];
+    let mut page = pager.read_page(page_num)?.to_vec();
+    let (header, cell_pointer_array) = parse_page(&page, page_num)?;
+    if cell_pointer_array.is_empty() {
+        // The only child of a root without cells is gone, leaving an empty tree
+        let leaf_type = match header.page_type {
+            PageType::InteriorIndex => PageType::LeafIndex,
+            _ => PageType::LeafTable,
+        };
+        let root = build_page(pager, page_num, leaf_type, &[], None)?;
+        pager.write_page(page_num, root)?;
+        return Ok(Vec::new());
+    }
+
+    let removed_position = if position < cell_pointer_array.len() {
+        position
+    } else {
+        // The left child of the last cell becomes the right-most child
+        let last = cell_pointer_array.len() - 1;
+        let left_child = read_left_child_pointer(&page, cell_pointer_array[last])?;
+        let offset = header_offset(page_num);
+        page[offset + 8..offset + 12].copy_from_slice(&left_child.to_be_bytes());
+        pager.write_page(page_num, page.clone())?;
+        last
+    };
+    let cell_pointer = cell_pointer_array[removed_position] as usize;
+    let size = cell_size(
+        &page,
+        header.page_type,
+        cell_pointer as u16,
+        pager.usable_size(),
+    )?;
+    let removed = page[cell_pointer..cell_pointer + size].to_vec();
+
+    let mut path = path[..=depth].to_vec();
+    path[depth].1 = removed_position;
+    let mut displaced = remove_from_tree(pager, &path, depth)?;
+    // Interior index cells hold entries of their own, which have to go somewhere else
+    if header.page_type == PageType::InteriorIndex {
+        displaced.push(removed[4..].to_vec());
+    }
+    Ok(displaced)
+}
+
+/// Point the cell at `position`, or the right-most pointer past the last cell, to `child`
+fn set_child_at(page: &mut [u8], page_num: u64, position: usize, child: u32) -> Result<()> {
+    let (_header, cell_pointer_array) = parse_page(page, page_num)?;
+    let start = match cell_pointer_array.get(position) {
+        Some(&cell_pointer) => cell_pointer as usize,
+        None => header_offset(page_num) + 8,
+    };
+    page[start..start + 4].copy_from_slice(&child.to_be_bytes());
+    Ok(())
+}
+
+/// Move the only child of a root without cells into the root, making the tree one level shallower.
+/// The root is left as it is if the child's cells don't fit, which can happen on page 1.
+fn collapse_root(pager: &mut Pager, root: u64, child: u64) -> Result<()> {
+    let child_page = pager.read_page(child)?;
+    let (child_header, _cell_pointer_array) = parse_page(&child_page, child)?;
+    let cells = read_cells(pager, &child_page, child)?;
+    let size = cells.iter().map(|cell| cell.len() + 2).sum::<usize>();
+    if header_offset(root) + child_header.size() + size > pager.usable_size() as usize {
+        return Ok(());
+    }
+    let page = build_page(
+        pager,
+        root,
+        child_header.page_type,
+        &cells,
+        child_header.right_most_pointer,
+    )?;
+    pager.write_page(root, page)?;
+    pager.free_page(child)
+}
+
+/// Take the cell at `position` off the page, adding the space it used to the freeblock list
+pub fn remove_cell(
+    page: &mut [u8],
+    page_num: u64,
+    position: usize,
+    usable_size: u32,
+) -> Result<()> {
+    let (header, mut cell_pointer_array) = parse_page(page, page_num)?;
+    if position >= cell_pointer_array.len() {
+        bail!("Page {} has no cell {}", page_num, position);
+    }
+    let cell_pointer = cell_pointer_array.remove(position);
+    let size = cell_size(page, header.page_type, cell_pointer, usable_size)?;
+    write_cell_pointer_array(page, page_num, &header, &cell_pointer_array);
+    free_space(page, page_num, &header, cell_pointer as usize, size)
+}
+
+/// Add `size` bytes at `start` to the freeblock list, which is sorted by offset with adjacent blocks merged.
+/// Free space at the start of the cell content area goes back to the unallocated space instead, and gaps
+/// of less than 4 bytes, too small for a freeblock, are counted as fragmented bytes.
+fn free_space(
+    page: &mut [u8],
+    page_num: u64,
+    header: &PageHeader,
+    start: usize,
+    size: usize,
+) -> Result<()> {
+    let read_u16 = |page: &[u8], at: usize| u16::from_be_bytes([page[at], page[at + 1]]) as usize;
+    let mut blocks = Vec::new();
+    let mut next = header.first_freeblock as usize;
+    while next != 0 {
+        if next + 4 > page.len() || blocks.len() > page.len() / 4 {
+            bail!("Corrupt freeblock list on page {}", page_num);
+        }
+        blocks.push((next, read_u16(page, next + 2)));
+        next = read_u16(page, next);
+    }
+    let position = blocks.partition_point(|&(block_start, _)| block_start < start);
+    blocks.insert(position, (start, size));
+
+    let mut fragmented_free_bytes = header.fragmented_free_bytes as usize;
+    let mut merged: Vec<(usize, usize)> = Vec::new();
+    for (block_start, block_size) in blocks {
+        if let Some((last_start, last_size)) = merged.last_mut() {
+            if block_start < *last_start + *last_size {
+                bail!("Overlapping free space on page {}", page_num);
+            }
+            let gap = block_start - (*last_start + *last_size);
+            if gap < 4 && gap <= fragmented_free_bytes {
+                fragmented_free_bytes -= gap;
+                *last_size = block_start + block_size - *last_start;
+                continue;
+            }
+        }
+        merged.push((block_start, block_size));
+    }
+
+    let mut content_start = match header.cell_content_offset {
+        0 => 65536,
+        content_offset => content_offset as usize,
+    };
+    if let Some(&(block_start, block_size)) = merged.first() {
+        if block_start == content_start {
+            content_start += block_size;
+            merged.remove(0);
+        }
+    }
+    merged.retain(|&(_, block_size)| {
+        if block_size < 4 {
+            fragmented_free_bytes += block_size;
+        }
+        block_size >= 4
+    });
+    if fragmented_free_bytes > 255 {
+        bail!("Too many fragmented bytes on page {}", page_num);
+    }
+
+    let offset = header_offset(page_num);
+    let first_freeblock = merged.first().map_or(0, |&(block_start, _)| block_start);
+    page[offset + 1..offset + 3].copy_from_slice(&(first_freeblock as u16).to_be_bytes());
+    for (i, &(block_start, block_size)) in merged.iter().enumerate() {
+        let next = merged.get(i + 1).map_or(0, |&(next_start, _)| next_start);
+        page[block_start..block_start + 2].copy_from_slice(&(next as u16).to_be_bytes());
+        page[block_start + 2..block_start + 4].copy_from_slice(&(block_size as u16).to_be_bytes());
+    }
+    page[offset + 5..offset + 7].copy_from_slice(&(content_start as u16).to_be_bytes());
+    page[offset + 7] = fragmented_free_bytes as u8;
+    Ok(())
+}
+
+/// Lay out a page from scratch, with `cells` packed at the end of the usable space in order
+pub fn build_page(
+    pager: &mut Pager,
+    page_num: u64,
+    page_type: PageType,
+    cells: &[Vec<u8>],
+    right_most_pointer: Option<u32>,
+) -> Result<Vec<u8>> {
+    let mut page = vec![0; pager.page_size() as usize];
+    let offset = header_offset(page_num);
+    if page_num == 1 {
+        page[..offset].copy_from_slice(&pager.read_page(1)?[..offset]);
+    }
+    let header_size = if page_type.is_leaf() { 8 } else { 12 };
+
+    let mut content_start = pager.usable_size() as usize;
+    let mut cell_pointer_array = Vec::new();
+    for cell in cells {
+        if content_start < offset + header_size + (cell_pointer_array.len() + 1) * 2 + cell.len() {
+            bail!("{} cells do not fit in page {}", cells.len(), page_num);
+        }
+        content_start -= cell.len();
+        page[content_start..content_start + cell.len()].copy_from_slice(cell);
+        cell_pointer_array.push(content_start as u16);
+    }
+
+    page[offset] = page_type.to_byte();
+    page[offset + 3..offset + 5].copy_from_slice(&(cells.len() as u16).to_be_bytes());
+    // A cell content offset of 65536 is stored as 0
+    page[offset + 5..offset + 7].copy_from_slice(&(content_start as u16).to_be_bytes());
+    if let Some(right_most_pointer) = right_most_pointer {
+        page[offset + 8..offset + 12].copy_from_slice(&right_most_pointer.to_be_bytes());
+    }
+    let array_start = offset + header_size;
+    for (i, cell_pointer) in cell_pointer_array.iter().enumerate() {
+        page[array_start + i * 2..array_start + i * 2 + 2]
+            .copy_from_slice(&cell_pointer.to_be_bytes());
+    }
+    Ok(page)
+}
+
+/// The cells of a page as byte strings, in cell pointer order
+pub(crate) fn read_cells(pager: &Pager, page: &[u8], page_num: u64) -> Result<Vec<Vec<u8>>> {
+    let (header, cell_pointer_array) = parse_page(page, page_num)?;
+    let mut cells = Vec::new();
+    for cell_pointer in cell_pointer_array {
+        let size = cell_size(page, header.page_type, cell_pointer, pager.usable_size())?;
+        let start = cell_pointer as usize;
+        cells.push(page[start..start + size].to_vec());
+    }
+    Ok(cells)
+}
+
+/// Insert `new_cells` into page `path[depth]` at the recorded position when the page lacks the room for them.
+/// The page is laid out again if that is enough, otherwise its cells are split over several pages and a divider
+/// for each new page goes into the parent, splitting it in turn if needed. A root that splits keeps its page
+/// number and becomes an interior page over the new pages, so the tree grows by one level.
+fn balance(
+    pager: &mut Pager,
+    path: &[(u64, usize)],
+    depth: usize,
+    new_cells: Vec<Vec<u8>>,
+) -> Result<()> {
+    let (page_num, position) = path[depth];
+    let page = pager.read_page(page_num)?;
+    let (header, _cell_pointer_array) = parse_page(&page, page_num)?;
+    let page_type = header.page_type;
+
+    let mut cells = read_cells(pager, &page, page_num)?;
+    let appended = position == cells.len();
+    cells.splice(position..position, new_cells);
+
+    let total_size = cells.iter().map(|cell| cell.len() + 2).sum::<usize>();
+    let usable_size = pager.usable_size() as usize;
+    if header_offset(page_num) + header.size() + total_size <= usable_size {
+        let page = build_page(
+            pager,
+            page_num,
+            page_type,
+            &cells,
+            header.right_most_pointer,
+        )?;
+        return pager.write_page(page_num, page);
+    }
+
+    // Table leaves are divided by rowid keys, other pages by moving the cell between two groups up
+    let separated = page_type != PageType::LeafTable;
+    let (groups, separators) =
+        split_cells(cells, usable_size - header.size(), separated, appended)?;
+
+    let is_root = depth == 0;
+    let mut group_pages = Vec::new();
+    for _ in 0..groups.len() - if is_root { 0 } else { 1 } {
+        group_pages.push(pager.allocate_page()?);
+    }
+    if !is_root {
+        group_pages.push(page_num);
+    }
+
+    let dividers = write_groups(
+        pager,
+        page_type,
+        &groups,
+        &separators,
+        &group_pages,
+        header.right_most_pointer,
+    )?;
+
+    if is_root {
+        let right_most_pointer = group_pages[group_pages.len() - 1] as u32;
+        let root = build_page(
+            pager,
+            page_num,
+            interior_type(page_type),
+            &dividers,
+            Some(right_most_pointer),
+        )?;
+        return pager.write_page(page_num, root);
+    }
+
+    let (parent, parent_position) = path[depth - 1];
+    let mut parent_page = pager.read_page(parent)?.to_vec();
+    let mut fits = true;
+    for (i, divider) in dividers.iter().enumerate() {
+        if !insert_cell(&mut parent_page, parent, parent_position + i, divider)? {
+            fits = false;
+            break;
+        }
+    }
+    if fits {
+        return pager.write_page(parent, parent_page);
+    }
+    balance(pager, path, depth - 1, dividers)
+}
+
+/// Write each group of cells to its page, returning the dividers that point to all but the last page.
+/// Interior groups end with the left child of the separator after them, the last one with `right_most_pointer`.
+fn write_groups(
+    pager: &mut Pager,
+    page_type: PageType,
+    groups: &[Vec<Vec<u8>>],
+    separators: &[Vec<u8>],
+    group_pages: &[u64],
+    right_most_pointer: Option<u32>,
+) -> Result<Vec<Vec<u8>>> {
+    let mut dividers = Vec::new();
+    for (i, group) in groups.iter().enumerate() {
+        let group_page = group_pages[i];
+        let group_right_most_pointer = match separators.get(i) {
+            Some(separator) if !page_type.is_leaf() => Some(read_left_child_pointer(separator, 0)?),
+            _ => right_most_pointer,
+        };
+        let new_page = build_page(
+            pager,
+            group_page,
+            page_type,
+            group,
+            group_right_most_pointer,
+        )?;
+        pager.write_page(group_page, new_page)?;
+
+        if i == groups.len() - 1 {
+            break;
+        }
+        let mut divider = (group_page as u32).to_be_bytes().to_vec();
+        match page_type {
+            PageType::LeafTable => {
+                let last_cell = &group[group.len() - 1];
+                divider.extend(write_varint(read_table_leaf_cell(last_cell, 0)?.1));
+            }
+            PageType::LeafIndex => divider.extend_from_slice(&separators[i]),
+            PageType::InteriorTable | PageType::InteriorIndex => {
+                divider.extend_from_slice(&separators[i][4..])
+            }
+        }
+        dividers.push(divider);
+    }
+    Ok(dividers)
+}
+
+fn interior_type(page_type: PageType) -> PageType {
+    match page_type {
+        PageType::LeafTable | PageType::InteriorTable => PageType::InteriorTable,
+        PageType::LeafIndex | PageType::InteriorIndex => PageType::InteriorIndex,
+    }
+}
+
+/// Build a b-tree bottom-up from leaf cells in key order, filling each page before starting the next,
+/// and return its root page
+pub fn build_tree(pager: &mut Pager, leaf_type: PageType, cells: Vec<Vec<u8>>) -> Result<u64> {
+    let mut page_type = leaf_type;
+    let mut cells = cells;
+    let mut right_most_pointer = None;
+    loop {
+        let header_size = if page_type.is_leaf() { 8 } else { 12 };
+        let capacity = pager.usable_size() as usize - header_size;
+        let separated = page_type != PageType::LeafTable;
+        let (groups, separators) = pack_cells(cells, capacity, separated)?;
+        let mut group_pages = Vec::new();
+        for _ in &groups {
+            group_pages.push(pager.allocate_page()?);
+        }
+        let dividers = write_groups(
+            pager,
+            page_type,
+            &groups,
+            &separators,
+            &group_pages,
+            right_most_pointer,
+        )?;
+
+        let last_page = group_pages[group_pages.len() - 1];
+        if dividers.is_empty() {
+            return Ok(last_page);
+        }
+        cells = dividers;
+        right_most_pointer = Some(last_page as u32);
+        page_type = interior_type(page_type);
+    }
+}
+
+/// Groups of cells for separate pages, and the separator cells between them
+type Split = (Vec<Vec<Vec<u8>>>, Vec<Vec<u8>>);
+
+/// Cut cells into groups that each fit in `capacity` bytes, with the separator cells between the groups
+/// taken out when `separated`. Appended cells leave the left groups full, so that rows added in key order
+/// fill pages completely, other splits spread the cells evenly.
+fn split_cells(
+    cells: Vec<Vec<u8>>,
+    capacity: usize,
+    separated: bool,
+    appended: bool,
+) -> Result<Split> {
+    let (groups, separators) = pack_cells(cells.clone(), capacity, separated)?;
+    if appended || groups.len() < 2 {
+        return Ok((groups, separators));
+    }
+    let total_size = cells.iter().map(|cell| cell.len() + 2).sum::<usize>();
+    let largest_cell = cells.iter().map(|cell| cell.len() + 2).max().unwrap_or(0);
+    let target = (total_size / groups.len() + 1)
+        .max(largest_cell)
+        .min(capacity);
+    pack_cells(cells, target, separated)
+}
+
+fn pack_cells(cells: Vec<Vec<u8>>, capacity: usize, separated: bool) -> Result<Split> {
+    let mut groups: Vec<Vec<Vec<u8>>> = vec![Vec::new()];
+    let mut separators = Vec::new();
+    let mut used = 0;
+    for cell in cells {
+        if cell.len() + 2 > capacity {
+            bail!("Cell of {} bytes does not fit in a page", cell.len());
+        }
+        let group_is_empty = groups[groups.len() - 1].is_empty();
+        if used + cell.len() + 2 > capacity && !group_is_empty {
+            groups.push(Vec::new());
+            used = 0;
+            if separated {
+                separators.push(cell);
+                continue;
+            }
+        }
+        used += cell.len() + 2;
+        let last = groups.len() - 1;
+        groups[last].push(cell);
+    }
+
+    // A separator can't be the last cell, the group after it would be empty
+    if groups.len() > 1 && groups[groups.len() - 1].is_empty() {
+        groups.pop();
+        if let Some(separator) = separators.pop() {
+            let last = groups.len() - 1;
+            match groups[last].pop() {
+                Some(cell) if !groups[last].is_empty() => {
+                    separators.push(cell);
+                    groups.push(vec![separator]);
+                }
+                _ => bail!("Cannot split a page around a single cell"),
+            }
+        }
+    }
+    Ok((groups, separators))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btree::{count_table_cells, visit_table_cells};
+    use crate::util::tests::copy_sample_db;
+
+    #[test]
+    fn test_insert_splits_pages() -> Result<()> {
+        let db_path = copy_sample_db("split-pages");
+        let mut pager = Pager::open(&db_path)?;
+        // Page 2 holds the apples table, insert out of order so both leaves and the root split
+        for i in 0..2000u64 {
+            let row_id = 10 + (i * 7919) % 2000;
+            insert_table_row(&mut pager, 2, row_id, &[b'x'; 100])?;
+        }
+        pager.commit()?;
+
+        let mut pager = Pager::open(&db_path)?;
+        let root = pager.read_page(2)?;
+        assert_eq!(parse_page(&root, 2)?.0.page_type, PageType::InteriorTable);
+        assert_eq!(count_table_cells(&mut pager, 2)?, 2004);
+        let mut row_ids = Vec::new();
+        visit_table_cells(&mut pager, 2, &mut |row_id, _| {
+            row_ids.push(row_id);
+            Ok(())
+        })?;
+        assert!(row_ids.windows(2).all(|pair| pair[0] < pair[1]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_frees_pages() -> Result<()> {
+        let db_path = copy_sample_db("delete-frees-pages");
+        let mut pager = Pager::open(&db_path)?;
+        for row_id in 10..1010 {
+            insert_table_row(&mut pager, 2, row_id, &[b'x'; 200])?;
+        }
+        assert!(!delete_table_row(&mut pager, 2, 5000)?);
+        for row_id in (1..1010).rev() {
+            assert_eq!(
+                delete_table_row(&mut pager, 2, row_id)?,
+                !(5..10).contains(&row_id)
+            );
+        }
+        pager.commit()?;
+
+        let mut pager = Pager::open(&db_path)?;
+        let root = pager.read_page(2)?;
+        let (header, cell_pointer_array) = parse_page(&root, 2)?;
+        assert_eq!(header.page_type, PageType::LeafTable);
+        assert!(cell_pointer_array.is_empty());
+        let freelist_count = pager.header().freelist_count;
+        assert!(freelist_count > 0);
+        // Freed pages are handed out again before the file grows
+        let database_size = pager.header().database_size;
+        pager.allocate_page()?;
+        assert_eq!(pager.header().freelist_count, freelist_count - 1);
+        assert_eq!(pager.header().database_size, database_size);
+        Ok(())
+    }
+}
@@ -0,0 +1,262 @@
+use anyhow::{bail, Result};
+use std::str;
+
+pub const HEADER_SIZE: usize = 100;
+const MAGIC: &[u8; 16] = b"SQLite format 3\0";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextEncoding {
+    Utf8,
+    Utf16le,
+    Utf16be,
+}
+
+impl TextEncoding {
+    pub fn from_u32(value: u32) -> Result<Self> {
+        match value {
+            1 => Ok(Self::Utf8),
+            2 => Ok(Self::Utf16le),
+            3 => Ok(Self::Utf16be),
+            _ => bail!("Invalid text encoding {}", value),
+        }
+    }
+
+    pub fn as_u32(&self) -> u32 {
+        match self {
+            Self::Utf8 => 1,
+            Self::Utf16le => 2,
+            Self::Utf16be => 3,
+        }
+    }
+
+    pub fn encode(&self, text: &str) -> Vec<u8> {
+        match self {
+            Self::Utf8 => text.as_bytes().to_vec(),
+            Self::Utf16le => text.encode_utf16().flat_map(u16::to_le_bytes).collect(),
+            Self::Utf16be => text.encode_utf16().flat_map(u16::to_be_bytes).collect(),
+        }
+    }
+
+    /// Decode the bytes of a TEXT value stored in this encoding
+    pub fn decode(&self, bytes: &[u8]) -> Result<String> {
+        let to_u16 = match self {
+            Self::Utf8 => return Ok(str::from_utf8(bytes)?.to_owned()),
+            Self::Utf16le => u16::from_le_bytes,
+            Self::Utf16be => u16::from_be_bytes,
+        };
+        if bytes.len() & 1 == 1 {
+            bail!("UTF-16 text has an odd number of bytes");
+        }
+        let code_units = bytes
+            .chunks(2)
+            .map(|pair| to_u16([pair[0], pair[1]]))
+            .collect::<Vec<u16>>();
+        Ok(String::from_utf16(&code_units)?)
+    }
+}
+
+/// The 100-byte header at the start of every database file
+#[derive(Debug, Clone)]
+pub struct DatabaseHeader {
+    pub page_size: u32,
+    /// 1 for legacy rollback journal mode, 2 for WAL mode
+    pub write_version: u8,
+    pub read_version: u8,
+    /// Unused space at the end of every page
+    pub reserved_bytes: u8,
+    pub max_embedded_payload_fraction: u8,
+    pub min_embedded_payload_fraction: u8,
+    pub leaf_payload_fraction: u8,
+    pub file_change_counter: u32,
+    /// Size of the database in pages, only trusted when `version_valid_for` matches `file_change_counter`
+    pub database_size: u32,
+    pub freelist_trunk_page: u32,
+    pub freelist_count: u32,
+    pub schema_cookie: u32,
+    pub schema_format: u32,
+    pub default_page_cache_size: u32,
+    /// Largest root b-tree page in auto-vacuum and incremental-vacuum mode, 0 otherwise
+    pub largest_root_page: u32,
+    pub text_encoding: TextEncoding,
+    pub user_version: u32,
+    pub incremental_vacuum: u32,
+    pub application_id: u32,
+    pub version_valid_for: u32,
+    pub sqlite_version_number: u32,
+}
+
+impl DatabaseHeader {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < HEADER_SIZE || &bytes[..16] != MAGIC {
+            bail!("File is not an SQLite database: missing the \"SQLite format 3\" header");
+        }
+        let read_u32 = |offset: usize| {
+            u32::from_be_bytes([
+                bytes[offset],
+                bytes[offset + 1],
+                bytes[offset + 2],
+                bytes[offset + 3],
+            ])
+        };
+
+        // The page size is stored at the 16th byte offset, using 2 bytes in big-endian order.
+        // 65536 does not fit in 2 bytes and is stored as 1.
+        let page_size = match u16::from_be_bytes([bytes[16], bytes[17]]) {
+            1 => 65536,
+            size => size as u32,
+        };
+        if !(512..=65536).contains(&page_size) || !page_size.is_power_of_two() {
+            bail!("Invalid page size {}", page_size);
+        }
+
+        let (write_version, read_version) = (bytes[18], bytes[19]);
+        for version in [write_version, read_version] {
+            if version != 1 && version != 2 {
+                bail!("Invalid file format version {}", version);
+            }
+        }
+
+        let reserved_bytes = bytes[20];
+        if page_size - (reserved_bytes as u32) < 480 {
+            bail!(
+                "{} reserved bytes leave less than 480 usable bytes per page",
+                reserved_bytes
+            );
+        }
+
+        let (max_fraction, min_fraction, leaf_fraction) = (bytes[21], bytes[22], bytes[23]);
+        if (max_fraction, min_fraction, leaf_fraction) != (64, 32, 32) {
+            bail!(
+                "Invalid payload fractions {}/{}/{}, expected 64/32/32",
+                max_fraction,
+                min_fraction,
+                leaf_fraction
+            );
+        }
+
+        let schema_format = read_u32(44);
+        if schema_format > 4 {
+            bail!("Unsupported schema format {}", schema_format);
+        }
+
+        // An empty database has no encoding yet and defaults to UTF-8
+        let text_encoding = match read_u32(56) {
+            0 => TextEncoding::Utf8,
+            value => TextEncoding::from_u32(value)?,
+        };
+
+        if bytes[72..92].iter().any(|&byte| byte != 0) {
+            bail!("Bytes 72 to 91 of the header are reserved and must be zero");
+        }
+
+        Ok(Self {
+            page_size,
+            write_version,
+            read_version,
+            reserved_bytes,
+            max_embedded_payload_fraction: max_fraction,
+            min_embedded_payload_fraction: min_fraction,
+            leaf_payload_fraction: leaf_fraction,
+            file_change_counter: read_u32(24),
+            database_size: read_u32(28),
+            freelist_trunk_page: read_u32(32),
+            freelist_count: read_u32(36),
+            schema_cookie: read_u32(40),
+            schema_format,
+            default_page_cache_size: read_u32(48),
+            largest_root_page: read_u32(52),
+            text_encoding,
+            user_version: read_u32(60),
+            incremental_vacuum: read_u32(64),
+            application_id: read_u32(68),
+            version_valid_for: read_u32(92),
+            sqlite_version_number: read_u32(96),
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0; HEADER_SIZE];
+        let mut write_u32 = |offset: usize, value: u32| {
+            bytes[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+        };
+        write_u32(24, self.file_change_counter);
+        write_u32(28, self.database_size);
+        write_u32(32, self.freelist_trunk_page);
+        write_u32(36, self.freelist_count);
+        write_u32(40, self.schema_cookie);
+        write_u32(44, self.schema_format);
+        write_u32(48, self.default_page_cache_size);
+        write_u32(52, self.largest_root_page);
+        write_u32(56, self.text_encoding.as_u32());
+        write_u32(60, self.user_version);
+        write_u32(64, self.incremental_vacuum);
+        write_u32(68, self.application_id);
+        write_u32(92, self.version_valid_for);
+        write_u32(96, self.sqlite_version_number);
+
+        bytes[..16].copy_from_slice(MAGIC);
+        let page_size = match self.page_size {
+            65536 => 1,
+            size => size as u16,
+        };
+        bytes[16..18].copy_from_slice(&page_size.to_be_bytes());
+        bytes[18] = self.write_version;
+        bytes[19] = self.read_version;
+        bytes[20] = self.reserved_bytes;
+        bytes[21] = self.max_embedded_payload_fraction;
+        bytes[22] = self.min_embedded_payload_fraction;
+        bytes[23] = self.leaf_payload_fraction;
+        bytes
+    }
+
+    /// Page size minus the reserved space at the end of each page
+    pub fn usable_size(&self) -> u32 {
+        self.page_size - self.reserved_bytes as u32
+    }
+
+    pub fn is_wal_mode(&self) -> bool {
+        self.write_version == 2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_from_sample_db() -> Result<()> {
+        let bytes = std::fs::read("sample.db")?;
+        let header = DatabaseHeader::from_bytes(&bytes[..HEADER_SIZE])?;
+
+        assert_eq!(header.page_size, 4096);
+        assert_eq!(header.usable_size(), 4096);
+        assert_eq!(header.database_size, 4);
+        assert_eq!(header.text_encoding, TextEncoding::Utf8);
+        assert_eq!(&header.to_bytes()[..], &bytes[..HEADER_SIZE]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_header_page_size_65536() -> Result<()> {
+        let mut bytes = std::fs::read("sample.db")?[..HEADER_SIZE].to_vec();
+        bytes[16..18].copy_from_slice(&[0, 1]);
+
+        assert_eq!(DatabaseHeader::from_bytes(&bytes)?.page_size, 65536);
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_text() -> Result<()> {
+        assert_eq!(TextEncoding::Utf8.decode("héllo".as_bytes())?, "héllo");
+        assert_eq!(TextEncoding::Utf16le.decode(&[0x68, 0, 0xe9, 0])?, "hé");
+        assert_eq!(TextEncoding::Utf16be.decode(&[0, 0x68, 0, 0xe9])?, "hé");
+        assert!(TextEncoding::Utf16le.decode(&[0x68]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_header_rejects_other_files() {
+        let bytes = [b'x'; HEADER_SIZE];
+        assert!(DatabaseHeader::from_bytes(&bytes).is_err());
+    }
+}
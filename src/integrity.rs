@@ -0,0 +1,648 @@
+use crate::btree::{
+    cell_size, header_offset, local_payload_size, read_index_payload, read_interior_table_key,
+    read_left_child_pointer, read_table_leaf_cell, search_index, visit_table_cells, PageHeader,
+    PageType,
+};
+use crate::pager::Pager;
+use crate::record::parse_records;
+use crate::util::{
+    compare_index_entries, compare_index_key, get_index_columns, get_table_def, get_tables,
+    index_key, read_varint,
+};
+use crate::value::Value;
+use anyhow::{bail, Result};
+use std::cmp::Ordering;
+
+pub const DEFAULT_MAX_ERRORS: usize = 100;
+
+/// Check the structure of the whole database: every b-tree listed in sqlite_schema, the freelist,
+/// and that every page is used exactly once, then that the indexes match the rows of their tables.
+/// Returns the problems found, at most `max_errors`, in the format of sqlite3's report, or "ok".
+pub fn integrity_check(pager: &mut Pager, max_errors: usize) -> Result<Vec<String>> {
+    let database_size = pager.header().database_size as usize;
+    let mut check = IntegrityCheck {
+        pager,
+        referenced: vec![false; database_size + 1],
+        errors: Vec::new(),
+        max_errors,
+    };
+    check.check_database()?;
+
+    if check.errors.is_empty() {
+        return Ok(vec!["ok".to_string()]);
+    }
+    let mut report = vec!["*** in database main ***".to_string()];
+    report.extend(check.errors);
+    Ok(report)
+}
+
+/// The sort key a b-tree orders its cells by
+enum Key {
+    RowId(i64),
+    Entry(Vec<u8>),
+}
+
+struct IntegrityCheck<'a> {
+    pager: &'a mut Pager,
+    /// Whether each page, by page number, has been reached from the freelist or a b-tree
+    referenced: Vec<bool>,
+    errors: Vec<String>,
+    max_errors: usize,
+}
+
+impl IntegrityCheck<'_> {
+    fn is_full(&self) -> bool {
+        self.errors.len() >= self.max_errors
+    }
+
+    fn error(&mut self, message: String) {
+        if !self.is_full() {
+            self.errors.push(message);
+        }
+    }
+
+    fn check_database(&mut self) -> Result<()> {
+        self.check_freelist()?;
+        self.mark_pointer_map_pages();
+
+        // Without a readable schema there is no telling which pages belong to which tree
+        if !self.check_tree(1)? {
+            bail!("malformed database schema");
+        }
+        let schema = get_tables(self.pager)?;
+        let mut intact_trees = vec![true];
+        for object in &schema {
+            // Views and triggers have no b-tree
+            intact_trees.push(object.rootpage == 0 || self.check_tree(object.rootpage)?);
+        }
+
+        for page_num in 1..self.referenced.len() {
+            if !self.referenced[page_num] && page_num as u64 != self.pending_byte_page() {
+                self.error(format!("Page {}: never used", page_num));
+            }
+        }
+
+        // Tables and indexes are only compared once their b-trees can be walked safely
+        for (table, table_intact) in schema.iter().zip(&intact_trees[1..]) {
+            if table.schema_type != "table" || table.rootpage == 0 || !table_intact {
+                continue;
+            }
+            // WITHOUT ROWID tables are index b-trees, their indexes refer to the primary key
+            let root = self.pager.read_page(table.rootpage)?;
+            if !matches!(root[header_offset(table.rootpage)], 0x05 | 0x0d) {
+                continue;
+            }
+            let indexes = schema
+                .iter()
+                .zip(&intact_trees[1..])
+                .filter(|(index, intact)| {
+                    index.schema_type == "index" && index.tbl_name == table.name && **intact
+                })
+                .map(|(index, _)| index)
+                .collect::<Vec<_>>();
+            if indexes.is_empty() || self.is_full() {
+                continue;
+            }
+            self.check_table_indexes(table, &indexes)?;
+        }
+        Ok(())
+    }
+
+    /// Count a reference to a page, reporting page numbers out of range and pages used twice
+    fn reference(&mut self, page_num: u64, prefix: &str) -> bool {
+        if page_num == 0 || page_num as usize >= self.referenced.len() {
+            self.error(format!("{}invalid page number {}", prefix, page_num));
+            return false;
+        }
+        if self.referenced[page_num as usize] {
+            self.error(format!("{}2nd reference to page {}", prefix, page_num));
+            return false;
+        }
+        self.referenced[page_num as usize] = true;
+        true
+    }
+
+    /// The page holding the byte at offset 2^30 is reserved for file locks and never used
+    fn pending_byte_page(&self) -> u64 {
+        (1 << 30) / self.pager.page_size() as u64 + 1
+    }
+
+    /// Auto-vacuum databases keep pointer map pages after page 1, one for every group of pages it maps
+    fn mark_pointer_map_pages(&mut self) {
+        if self.pager.header().largest_root_page == 0 {
+            return;
+        }
+        let pages_per_map = self.pager.usable_size() as usize / 5;
+        let mut page_num = 2;
+        while page_num < self.referenced.len() {
+            self.referenced[page_num] = true;
+            page_num += pages_per_map + 1;
+        }
+    }
+
+    /// Walk the trunk pages of the freelist and compare the number of pages on it with the header
+    fn check_freelist(&mut self) -> Result<()> {
+        let prefix = "Freelist: ";
+        let expected = self.pager.header().freelist_count;
+        let max_leaf_count = self.pager.usable_size() / 4 - 2;
+        let errors_at_start = self.errors.len();
+        let mut remaining = expected as i64;
+        let mut trunk = self.pager.header().freelist_trunk_page as u64;
+        while trunk != 0 && !self.is_full() {
+            if !self.reference(trunk, prefix) {
+                break;
+            }
+            remaining -= 1;
+            let page = self.pager.read_page(trunk)?;
+            let leaf_count = u32::from_be_bytes(page[4..8].try_into()?);
+            if leaf_count > max_leaf_count {
+                self.error(format!(
+                    "{}freelist leaf count too big on page {}",
+                    prefix, trunk
+                ));
+                remaining -= 1;
+            } else {
+                for i in 0..leaf_count as usize {
+                    let leaf = u32::from_be_bytes(page[8 + i * 4..12 + i * 4].try_into()?);
+                    self.reference(leaf as u64, prefix);
+                }
+                remaining -= leaf_count as i64;
+            }
+            trunk = u32::from_be_bytes(page[..4].try_into()?) as u64;
+        }
+        if remaining != 0 && self.errors.len() == errors_at_start {
+            self.error(format!(
+                "{}size is {} but should be {}",
+                prefix,
+                expected as i64 - remaining,
+                expected
+            ));
+        }
+        Ok(())
+    }
+
+    /// Check the b-tree rooted at `root`, returning whether it had no problems
+    fn check_tree(&mut self, root: u64) -> Result<bool> {
+        let errors_at_start = self.errors.len();
+        let mut max_key = None;
+        self.check_page(root, root, None, &mut max_key, "")?;
+        Ok(self.errors.len() == errors_at_start)
+    }
+
+    /// Check a b-tree page and its subtrees, returning the depth of the page above the leaves.
+    /// Cells are visited from the last to the first, each key has to be smaller than the keys
+    /// visited before it, `max_key`, which on return is the smallest key of the subtree.
+    fn check_page(
+        &mut self,
+        root: u64,
+        page_num: u64,
+        table_tree: Option<bool>,
+        max_key: &mut Option<Key>,
+        prefix: &str,
+    ) -> Result<Option<usize>> {
+        if self.is_full() || !self.reference(page_num, prefix) {
+            return Ok(None);
+        }
+        let page_prefix = format!("Tree {} page {}: ", root, page_num);
+        let page = self.pager.read_page(page_num)?;
+        let offset = header_offset(page_num);
+        let header = match PageHeader::from_bytes(&page[offset..]) {
+            Ok(header) => header,
+            Err(_) => {
+                self.error(format!(
+                    "{}invalid page type {:#04x}",
+                    page_prefix, page[offset]
+                ));
+                return Ok(None);
+            }
+        };
+        let is_table = matches!(
+            header.page_type,
+            PageType::LeafTable | PageType::InteriorTable
+        );
+        // Every page of a tree has to be of the same kind as its root
+        if table_tree == Some(!is_table) {
+            let expected = if is_table { "an index" } else { "a table" };
+            self.error(format!("{}expected {} b-tree page", page_prefix, expected));
+            return Ok(None);
+        }
+
+        let cells = match self.check_page_layout(&page, root, page_num, &header)? {
+            Some(cells) => cells,
+            None => return Ok(Some(0)),
+        };
+        let usable_size = self.pager.usable_size();
+        let encoding = self.pager.header().text_encoding;
+        let mut depth = None;
+        // Only the first key of a page may be equal to the bound, which is the key of its parent
+        // cell when the page is a left child: that key repeats the largest rowid of its subtree
+        let mut key_can_be_equal = true;
+        if let Some(right_most) = header.right_most_pointer {
+            let prefix = format!("Tree {} page {} right child: ", root, page_num);
+            self.check_child(
+                root,
+                right_most as u64,
+                is_table,
+                max_key,
+                &prefix,
+                &mut depth,
+            )?;
+            key_can_be_equal = false;
+        }
+        for (i, cell_pointer) in cells.into_iter().rev() {
+            if self.is_full() {
+                break;
+            }
+            let cell_prefix = format!("Tree {} page {} cell {}: ", root, page_num, i);
+
+            let mut payload_intact = true;
+            if header.page_type != PageType::InteriorTable {
+                let cell = match header.page_type {
+                    PageType::InteriorIndex => &page[cell_pointer as usize + 4..],
+                    _ => &page[cell_pointer as usize..],
+                };
+                let (payload_size, mut content) = read_varint(cell)?;
+                if header.page_type == PageType::LeafTable {
+                    content = read_varint(content)?.1;
+                }
+                let local_size = local_payload_size(usable_size, header.page_type, payload_size);
+                if (local_size as u64) < payload_size {
+                    let first_overflow =
+                        u32::from_be_bytes(content[local_size..local_size + 4].try_into()?);
+                    let overflow_size = payload_size - local_size as u64;
+                    let expected =
+                        (overflow_size + usable_size as u64 - 5) / (usable_size as u64 - 4);
+                    payload_intact =
+                        self.check_overflow_chain(first_overflow as u64, expected, &cell_prefix)?;
+                }
+            }
+
+            let key = match header.page_type {
+                PageType::LeafTable => Some(Key::RowId(
+                    read_table_leaf_cell(&page, cell_pointer)?.1 as i64,
+                )),
+                PageType::InteriorTable => Some(Key::RowId(read_interior_table_key(
+                    &page,
+                    cell_pointer,
+                )? as i64)),
+                _ if payload_intact => Some(Key::Entry(
+                    read_index_payload(self.pager, &page, header.page_type, cell_pointer)?
+                        .into_owned(),
+                )),
+                _ => None,
+            };
+            if let Some(key) = key {
+                let ordering = match (&key, &*max_key) {
+                    (Key::RowId(key), Some(Key::RowId(max))) => Some(key.cmp(max)),
+                    (Key::Entry(key), Some(Key::Entry(max))) => {
+                        compare_index_entries(key, max, encoding).ok()
+                    }
+                    _ => None,
+                };
+                let out_of_order = match ordering {
+                    Some(Ordering::Greater) => true,
+                    Some(Ordering::Equal) => !key_can_be_equal,
+                    _ => false,
+                };
+                if out_of_order {
+                    match &key {
+                        Key::RowId(row_id) => {
+                            self.error(format!("{}Rowid {} out of order", cell_prefix, row_id))
+                        }
+                        Key::Entry(_) => {
+                            self.error(format!("{}Index entry out of order", cell_prefix))
+                        }
+                    }
+                }
+                *max_key = Some(key);
+                key_can_be_equal = false;
+            }
+
+            if !header.page_type.is_leaf() {
+                let child = read_left_child_pointer(&page, cell_pointer)?;
+                self.check_child(
+                    root,
+                    child as u64,
+                    is_table,
+                    max_key,
+                    &cell_prefix,
+                    &mut depth,
+                )?;
+                key_can_be_equal = false;
+            }
+        }
+        Ok(Some(depth.map_or(0, |depth| depth + 1)))
+    }
+
+    /// Check a child page, all children of a page need to have the same `depth`
+    fn check_child(
+        &mut self,
+        root: u64,
+        child: u64,
+        table_tree: bool,
+        max_key: &mut Option<Key>,
+        prefix: &str,
+        depth: &mut Option<usize>,
+    ) -> Result<()> {
+        let child_depth = self.check_page(root, child, Some(table_tree), max_key, prefix)?;
+        match (*depth, child_depth) {
+            (Some(current), Some(child_depth)) if current != child_depth => {
+                self.error(format!("{}Child page depth differs", prefix));
+                *depth = Some(child_depth);
+            }
+            (None, _) => *depth = child_depth,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Check that the cells and freeblocks of a page lie within the cell content area without
+    /// overlapping, and that the gaps between them add up to the fragmented byte count of the header.
+    /// Returns the position and pointer of every cell that is safe to read, or `None` when the
+    /// free space of the page can't be accounted for and none of it should be trusted.
+    fn check_page_layout(
+        &mut self,
+        page: &[u8],
+        root: u64,
+        page_num: u64,
+        header: &PageHeader,
+    ) -> Result<Option<Vec<(usize, u16)>>> {
+        let usable_size = self.pager.usable_size() as usize;
+        let cell_array_start = header_offset(page_num) + header.size();
+        let cell_array_end = cell_array_start + header.num_cells as usize * 2;
+        let content_start = match header.cell_content_offset {
+            0 => 65536,
+            content_start => content_start as usize,
+        };
+
+        // The unallocated space, the freeblocks and the fragments have to fit between
+        // the cell pointer array and the end of the page
+        let mut freeblocks = Vec::new();
+        let mut free_size = header.fragmented_free_bytes as usize + content_start;
+        let mut freeblock = header.first_freeblock as usize;
+        let mut corrupt = cell_array_end > usable_size;
+        while freeblock != 0 && !corrupt {
+            if freeblock < content_start || freeblock + 4 > usable_size {
+                corrupt = true;
+                break;
+            }
+            let next = u16::from_be_bytes([page[freeblock], page[freeblock + 1]]) as usize;
+            let size = u16::from_be_bytes([page[freeblock + 2], page[freeblock + 3]]) as usize;
+            // Freeblocks are kept in order of their offset, with at least a fragment between them
+            corrupt = freeblock + size > usable_size || (next != 0 && next <= freeblock + size + 3);
+            freeblocks.push((freeblock, freeblock + size));
+            free_size += size;
+            freeblock = next;
+        }
+        if corrupt || free_size > usable_size || free_size < cell_array_end {
+            self.error(format!(
+                "Tree {} page {}: free space corruption",
+                root, page_num
+            ));
+            return Ok(None);
+        }
+
+        // Cells are checked from last to first, like the rest of the page; once one of them
+        // can't be placed the byte coverage of the page no longer adds up
+        let mut cells = Vec::new();
+        let mut used = freeblocks;
+        let mut check_coverage = true;
+        for i in (0..header.num_cells as usize).rev() {
+            let start = cell_array_start + i * 2;
+            let cell_pointer = u16::from_be_bytes([page[start], page[start + 1]]);
+            let cell_prefix = format!("Tree {} page {} cell {}: ", root, page_num, i);
+            if (cell_pointer as usize) < content_start || cell_pointer as usize > usable_size - 4 {
+                self.error(format!(
+                    "{}Offset {} out of range {}..{}",
+                    cell_prefix,
+                    cell_pointer,
+                    content_start,
+                    usable_size - 4
+                ));
+                check_coverage = false;
+                continue;
+            }
+            let size = cell_size(page, header.page_type, cell_pointer, usable_size as u32);
+            match size {
+                Ok(size) if cell_pointer as usize + size <= usable_size => {
+                    used.push((cell_pointer as usize, cell_pointer as usize + size));
+                    cells.push((i, cell_pointer));
+                }
+                _ => {
+                    self.error(format!("{}Extends off end of page", cell_prefix));
+                    check_coverage = false;
+                }
+            }
+        }
+        cells.reverse();
+        if !check_coverage {
+            return Ok(Some(cells));
+        }
+
+        used.sort_unstable();
+        let mut end = content_start;
+        let mut fragmented = 0;
+        for &(start, stop) in &used {
+            if start < end {
+                self.error(format!(
+                    "Multiple uses for byte {} of page {}",
+                    start, page_num
+                ));
+                return Ok(Some(cells));
+            }
+            fragmented += start - end;
+            end = stop;
+        }
+        fragmented += usable_size.saturating_sub(end);
+        if fragmented != header.fragmented_free_bytes as usize {
+            self.error(format!(
+                "Fragmentation of {} bytes reported as {} on page {}",
+                fragmented, header.fragmented_free_bytes, page_num
+            ));
+        }
+        Ok(Some(cells))
+    }
+
+    /// Follow an overflow chain, expecting `expected` pages. Returns whether it was intact.
+    fn check_overflow_chain(
+        &mut self,
+        first_page: u64,
+        expected: u64,
+        prefix: &str,
+    ) -> Result<bool> {
+        let errors_at_start = self.errors.len();
+        let mut length = 0;
+        let mut page_num = first_page;
+        while page_num != 0 && !self.is_full() {
+            if !self.reference(page_num, prefix) {
+                break;
+            }
+            length += 1;
+            let page = self.pager.read_page(page_num)?;
+            page_num = u32::from_be_bytes(page[..4].try_into()?) as u64;
+        }
+        if length != expected && self.errors.len() == errors_at_start {
+            self.error(format!(
+                "{}overflow list length is {} but should be {}",
+                prefix, length, expected
+            ));
+        }
+        Ok(self.errors.len() == errors_at_start)
+    }
+
+    /// Check that every row of a table has an entry in each of its indexes, and that
+    /// the indexes have no other entries
+    fn check_table_indexes(
+        &mut self,
+        table: &crate::sqlite_schema::SqliteSchema,
+        indexes: &[&crate::sqlite_schema::SqliteSchema],
+    ) -> Result<()> {
+        let encoding = self.pager.header().text_encoding;
+        let table_def = get_table_def(table)?;
+        let (column_names, row_id_alias) = (table_def.column_names(), table_def.row_id_alias());
+        let mut rows = Vec::new();
+        visit_table_cells(self.pager, table.rootpage, &mut |row_id, payload| {
+            let mut record = parse_records(payload, encoding)?;
+            // Columns added after a row was written read as NULL
+            record.resize(column_names.len().max(record.len()), Value::Null);
+            rows.push((row_id, record));
+            Ok(())
+        })?;
+
+        for index in indexes {
+            // Partial indexes only hold some of the rows
+            if index.sql.to_lowercase().contains(" where ") {
+                continue;
+            }
+            let mut entry_count = 0;
+            search_index(
+                self.pager,
+                index.rootpage,
+                &|_| Ok(Ordering::Equal),
+                &mut |_| {
+                    entry_count += 1;
+                    Ok(())
+                },
+            )?;
+
+            // Automatic indexes have no CREATE INDEX statement to tell their columns
+            if !index.sql.is_empty() {
+                let column_indices = get_index_columns(index, &column_names)?;
+                for (row_id, record) in &rows {
+                    let mut key = index_key(record, &column_indices, row_id_alias, *row_id);
+                    key.push(Value::Integer(*row_id as i64));
+                    let mut found = false;
+                    let compare = |payload: &[u8]| {
+                        Ok(compare_index_key(
+                            &parse_records(payload, encoding)?,
+                            &key,
+                            encoding,
+                        ))
+                    };
+                    search_index(self.pager, index.rootpage, &compare, &mut |_| {
+                        found = true;
+                        Ok(())
+                    })?;
+                    if !found {
+                        self.error(format!(
+                            "row {} missing from index {}",
+                            *row_id as i64, index.name
+                        ));
+                    }
+                    if self.is_full() {
+                        return Ok(());
+                    }
+                }
+            }
+            if entry_count != rows.len() {
+                self.error(format!("wrong # of entries in index {}", index.name));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tests::copy_sample_db;
+    use std::io::prelude::*;
+    use std::io::SeekFrom;
+
+    #[test]
+    fn test_integrity_check() -> Result<()> {
+        let db_path = copy_sample_db("integrity-check");
+        let mut pager = Pager::open(&db_path)?;
+        assert_eq!(integrity_check(&mut pager, DEFAULT_MAX_ERRORS)?, vec!["ok"]);
+        drop(pager);
+
+        // Claim a free page the freelist doesn't have
+        let mut file = std::fs::OpenOptions::new().write(true).open(&db_path)?;
+        file.seek(SeekFrom::Start(36))?;
+        file.write_all(&1u32.to_be_bytes())?;
+        drop(file);
+
+        let mut pager = Pager::open(&db_path)?;
+        assert_eq!(
+            integrity_check(&mut pager, DEFAULT_MAX_ERRORS)?,
+            vec![
+                "*** in database main ***",
+                "Freelist: size is 0 but should be 1"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_integrity_check_utf16_index_order() -> Result<()> {
+        use crate::btree_write::{insert_index_entry, insert_table_row};
+        use crate::header::TextEncoding;
+        use crate::record::encode_record;
+        use crate::sql_parser::{parse_create_index_statement, parse_create_table_statement};
+        use crate::util::{create_index, create_table, tests::create_empty_db};
+
+        let db_path = create_empty_db("integrity-utf16-index", TextEncoding::Utf16le);
+        let mut pager = Pager::open(&db_path)?;
+        create_table(
+            parse_create_table_statement("CREATE TABLE t (name TEXT)")?.1,
+            &mut pager,
+        )?;
+        create_index(
+            parse_create_index_statement("CREATE INDEX ts ON t (name)")?.1,
+            &mut pager,
+        )?;
+        let (table_root, index_root) = (2, 3);
+
+        // Index the rows by their UTF-8 order, which isn't the order of UTF-16le text
+        let encoding = TextEncoding::Utf16le;
+        for (row_id, name) in [(1, "a"), (2, "b"), (3, "\u{101}")] {
+            let name = Value::Text(name.to_owned());
+            let record = encode_record(std::slice::from_ref(&name), encoding);
+            insert_table_row(&mut pager, table_root, row_id, &record)?;
+            let key = [name, Value::Integer(row_id as i64)];
+            insert_index_entry(
+                &mut pager,
+                index_root,
+                &encode_record(&key, encoding),
+                &|payload| {
+                    Ok(compare_index_key(
+                        &parse_records(payload, encoding)?,
+                        &key,
+                        TextEncoding::Utf8,
+                    ))
+                },
+            )?;
+        }
+        pager.commit()?;
+
+        // U+0101 is 01 01 in UTF-16le, so its entry belongs before the entry of 'a'
+        assert_eq!(
+            integrity_check(&mut pager, DEFAULT_MAX_ERRORS)?,
+            vec![
+                "*** in database main ***",
+                "Tree 3 page 3 cell 1: Index entry out of order"
+            ]
+        );
+        Ok(())
+    }
+}
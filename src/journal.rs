@@ -0,0 +1,220 @@
+use anyhow::{bail, Result};
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const JOURNAL_HEADER_SIZE: usize = 28;
+const JOURNAL_MAGIC: [u8; 8] = [0xd9, 0xd5, 0x05, 0xf9, 0x20, 0xa1, 0x63, 0xd7];
+/// Headers are padded to a sector, so that a torn header write can't damage the records after it
+const SECTOR_SIZE: u32 = 512;
+/// A record count of all ones asks the reader to compute it from the journal size
+const COMPUTED_RECORD_COUNT: u32 = 0xffffffff;
+
+#[derive(Debug, Clone)]
+pub struct JournalHeader {
+    pub record_count: u32,
+    pub nonce: u32,
+    /// Size of the database in pages before the transaction started
+    pub database_size: u32,
+    pub sector_size: u32,
+    pub page_size: u32,
+}
+
+impl JournalHeader {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < JOURNAL_HEADER_SIZE || bytes[..8] != JOURNAL_MAGIC {
+            bail!("Invalid journal magic number");
+        }
+        let read_u32 =
+            |offset: usize| u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap());
+        let header = Self {
+            record_count: read_u32(8),
+            nonce: read_u32(12),
+            database_size: read_u32(16),
+            sector_size: read_u32(20),
+            page_size: read_u32(24),
+        };
+        if !(32..=65536).contains(&header.sector_size) || !header.sector_size.is_power_of_two() {
+            bail!("Invalid journal sector size {}", header.sector_size);
+        }
+        if !(512..=65536).contains(&header.page_size) || !header.page_size.is_power_of_two() {
+            bail!("Invalid journal page size {}", header.page_size);
+        }
+        Ok(header)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = JOURNAL_MAGIC.to_vec();
+        for field in [
+            self.record_count,
+            self.nonce,
+            self.database_size,
+            self.sector_size,
+            self.page_size,
+        ] {
+            bytes.extend(field.to_be_bytes());
+        }
+        bytes
+    }
+}
+
+pub fn journal_path(db_filepath: &str) -> String {
+    format!("{}-journal", db_filepath)
+}
+
+/// Sum of the nonce and every 200th byte of the page, going backwards from 200 bytes before its end
+fn page_checksum(nonce: u32, page: &[u8]) -> u32 {
+    let mut checksum = nonce;
+    let mut offset = page.len() as i64 - 200;
+    while offset > 0 {
+        checksum = checksum.wrapping_add(page[offset as usize] as u32);
+        offset -= 200;
+    }
+    checksum
+}
+
+/// Save the original content of the pages a transaction is about to overwrite in `<db>-journal`,
+/// together with the database size in pages before the transaction. The journal is synced
+/// before returning, the transaction commits once it is deleted.
+pub fn write_journal(
+    db_filepath: &str,
+    page_size: u32,
+    database_size: u32,
+    pages: &[(u64, Vec<u8>)],
+) -> Result<()> {
+    let clock = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    let header = JournalHeader {
+        record_count: pages.len() as u32,
+        nonce: clock.subsec_nanos() ^ std::process::id(),
+        database_size,
+        sector_size: SECTOR_SIZE,
+        page_size,
+    };
+    let mut journal = header.to_bytes();
+    journal.resize(SECTOR_SIZE as usize, 0);
+    for (page_num, page) in pages {
+        journal.extend((*page_num as u32).to_be_bytes());
+        journal.extend(page);
+        journal.extend(page_checksum(header.nonce, page).to_be_bytes());
+    }
+
+    let mut file = File::create(journal_path(db_filepath))?;
+    file.write_all(&journal)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+pub fn delete_journal(db_filepath: &str) -> Result<()> {
+    fs::remove_file(journal_path(db_filepath))?;
+    Ok(())
+}
+
+/// Roll back the transaction recorded in a hot journal, left behind by a writer that
+/// didn't get to finish its commit: the original pages are written back and the database
+/// file is truncated to its original size. Returns whether there was a journal to roll back.
+pub fn rollback_hot_journal(db_filepath: &str, db_file: &mut File) -> Result<bool> {
+    let journal = match fs::read(journal_path(db_filepath)) {
+        Ok(journal) => journal,
+        Err(_) => return Ok(false),
+    };
+    // Empty journals, or journals with a zeroed header, belong to finished transactions
+    let first_header = match JournalHeader::from_bytes(&journal) {
+        Ok(header) => header,
+        Err(_) => return Ok(false),
+    };
+
+    // The journal may hold several segments, each starting with a header on a sector boundary
+    let mut offset = 0;
+    'segments: while let Ok(header) = JournalHeader::from_bytes(&journal[offset..]) {
+        let record_size = header.page_size as usize + 8;
+        offset += header.sector_size as usize;
+        let record_count = match header.record_count {
+            COMPUTED_RECORD_COUNT => journal.len().saturating_sub(offset) / record_size,
+            count => count as usize,
+        };
+        for _ in 0..record_count {
+            let Some(record) = journal.get(offset..offset + record_size) else {
+                break 'segments;
+            };
+            let page_num = u32::from_be_bytes(record[..4].try_into()?) as u64;
+            let page = &record[4..record_size - 4];
+            let checksum = u32::from_be_bytes(record[record_size - 4..].try_into()?);
+            // A record that doesn't check out is the torn end of the journal
+            if page_num == 0 || checksum != page_checksum(header.nonce, page) {
+                break 'segments;
+            }
+            // Pages past the original size go away with the truncation below
+            if page_num <= first_header.database_size as u64 {
+                db_file.seek(SeekFrom::Start((page_num - 1) * header.page_size as u64))?;
+                db_file.write_all(page)?;
+            }
+            offset += record_size;
+        }
+        let sector_size = header.sector_size as usize;
+        if offset % sector_size != 0 {
+            offset += sector_size - offset % sector_size;
+        }
+        if offset >= journal.len() {
+            break;
+        }
+    }
+
+    db_file.set_len(first_header.database_size as u64 * first_header.page_size as u64)?;
+    db_file.sync_all()?;
+    delete_journal(db_filepath)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pager::Pager;
+    use crate::util::tests::copy_sample_db;
+
+    #[test]
+    fn test_page_checksum_samples_every_200th_byte() {
+        let mut page = vec![0; 1024];
+        page[824] = 3;
+        page[624] = 5;
+        page[24] = 7;
+        page[825] = 100;
+        assert_eq!(page_checksum(1, &page), 16);
+    }
+
+    #[test]
+    fn test_journal_header_roundtrip() {
+        let header = JournalHeader {
+            record_count: 3,
+            nonce: 0xdeadbeef,
+            database_size: 7,
+            sector_size: 512,
+            page_size: 4096,
+        };
+        let parsed = JournalHeader::from_bytes(&header.to_bytes()).unwrap();
+        assert_eq!(parsed.record_count, 3);
+        assert_eq!(parsed.nonce, 0xdeadbeef);
+        assert_eq!(parsed.database_size, 7);
+        assert!(JournalHeader::from_bytes(&[0; JOURNAL_HEADER_SIZE]).is_err());
+    }
+
+    #[test]
+    fn test_hot_journal_restores_database() -> Result<()> {
+        let db_path = copy_sample_db("hot-journal");
+        let original = fs::read(&db_path)?;
+
+        // A commit that died after overwriting page 2 and growing the file
+        write_journal(&db_path, 4096, 4, &[(2, original[4096..8192].to_vec())])?;
+        let mut file = fs::OpenOptions::new().write(true).open(&db_path)?;
+        file.seek(SeekFrom::Start(4096))?;
+        file.write_all(&[0xff; 4096])?;
+        file.set_len(6 * 4096)?;
+        drop(file);
+
+        let mut pager = Pager::open(&db_path)?;
+        assert_eq!(pager.read_page(2)?[0], 0x0d);
+        assert_eq!(fs::read(&db_path)?, original);
+        assert!(fs::metadata(journal_path(&db_path)).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,204 @@
+use anyhow::{bail, Result};
+use std::fs::File;
+
+/// SQLite's locks are byte-range locks on bytes 1 GiB into the file
+const PENDING_BYTE: u64 = 0x40000000;
+const RESERVED_BYTE: u64 = PENDING_BYTE + 1;
+const SHARED_FIRST: u64 = PENDING_BYTE + 2;
+const SHARED_SIZE: u64 = 510;
+
+/// The levels of SQLite's locking protocol, each held on top of the ones below it.
+/// SHARED lets a connection read, RESERVED announces a transaction that is going to write,
+/// and EXCLUSIVE keeps everyone else out while pages are written to the database file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LockLevel {
+    Shared,
+    Reserved,
+    Exclusive,
+}
+
+/// Take `level` on the database file, with the same byte-range locks as stock SQLite, so that
+/// connections of either see each other. Fails with "database is locked" if another connection
+/// holds a conflicting lock.
+pub fn lock(file: &File, level: LockLevel) -> Result<()> {
+    match level {
+        LockLevel::Shared => {
+            // A writer waiting for EXCLUSIVE holds PENDING to keep new readers out
+            set(file, Kind::Read, PENDING_BYTE, 1)?;
+            let shared = set(file, Kind::Read, SHARED_FIRST, SHARED_SIZE);
+            set(file, Kind::Unlock, PENDING_BYTE, 1)?;
+            shared
+        }
+        LockLevel::Reserved => set(file, Kind::Write, RESERVED_BYTE, 1),
+        LockLevel::Exclusive => {
+            set(file, Kind::Write, PENDING_BYTE, 1)?;
+            let exclusive = set(file, Kind::Write, SHARED_FIRST, SHARED_SIZE);
+            if exclusive.is_err() {
+                set(file, Kind::Unlock, PENDING_BYTE, 1)?;
+            }
+            exclusive
+        }
+    }
+}
+
+/// Go back to a SHARED lock after writing
+pub fn unlock_to_shared(file: &File) -> Result<()> {
+    set(file, Kind::Read, SHARED_FIRST, SHARED_SIZE)?;
+    set(file, Kind::Unlock, PENDING_BYTE, 2)
+}
+
+/// Whether another connection holds the RESERVED lock, so that a journal is its transaction
+/// in progress rather than a hot journal
+pub fn is_reserved(file: &File) -> Result<bool> {
+    sys::is_locked(file, RESERVED_BYTE)
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    Read,
+    Write,
+    Unlock,
+}
+
+fn set(file: &File, kind: Kind, start: u64, len: u64) -> Result<()> {
+    match sys::set_lock(file, kind, start, len) {
+        Ok(()) => Ok(()),
+        Err(err)
+            if matches!(
+                err.kind(),
+                std::io::ErrorKind::WouldBlock | std::io::ErrorKind::PermissionDenied
+            ) =>
+        {
+            bail!("database is locked")
+        }
+        Err(err) => bail!("Locking the database failed: {}", err),
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+mod sys {
+    use super::Kind;
+    use std::fs::File;
+    use std::os::raw::{c_int, c_short};
+    use std::os::unix::io::AsRawFd;
+
+    #[cfg(target_os = "linux")]
+    mod consts {
+        use std::os::raw::{c_int, c_short};
+
+        pub const F_GETLK: c_int = 5;
+        pub const F_SETLK: c_int = 6;
+        pub const F_RDLCK: c_short = 0;
+        pub const F_WRLCK: c_short = 1;
+        pub const F_UNLCK: c_short = 2;
+    }
+
+    #[cfg(target_os = "macos")]
+    mod consts {
+        use std::os::raw::{c_int, c_short};
+
+        pub const F_GETLK: c_int = 7;
+        pub const F_SETLK: c_int = 8;
+        pub const F_RDLCK: c_short = 1;
+        pub const F_UNLCK: c_short = 2;
+        pub const F_WRLCK: c_short = 3;
+    }
+
+    use consts::*;
+
+    #[cfg(target_os = "linux")]
+    #[repr(C)]
+    struct Flock {
+        l_type: c_short,
+        l_whence: c_short,
+        l_start: std::os::raw::c_long,
+        l_len: std::os::raw::c_long,
+        l_pid: c_int,
+    }
+
+    #[cfg(target_os = "macos")]
+    #[repr(C)]
+    struct Flock {
+        l_start: i64,
+        l_len: i64,
+        l_pid: c_int,
+        l_type: c_short,
+        l_whence: c_short,
+    }
+
+    extern "C" {
+        fn fcntl(fd: c_int, cmd: c_int, ...) -> c_int;
+    }
+
+    fn flock(l_type: c_short, start: u64, len: u64) -> Flock {
+        Flock {
+            l_type,
+            // SEEK_SET
+            l_whence: 0,
+            l_start: start as _,
+            l_len: len as _,
+            l_pid: 0,
+        }
+    }
+
+    pub fn set_lock(file: &File, kind: Kind, start: u64, len: u64) -> std::io::Result<()> {
+        let l_type = match kind {
+            Kind::Read => F_RDLCK,
+            Kind::Write => F_WRLCK,
+            Kind::Unlock => F_UNLCK,
+        };
+        let mut lock = flock(l_type, start, len);
+        if unsafe { fcntl(file.as_raw_fd(), F_SETLK, &mut lock as *mut Flock) } == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn is_locked(file: &File, byte: u64) -> anyhow::Result<bool> {
+        let mut lock = flock(F_WRLCK, byte, 1);
+        if unsafe { fcntl(file.as_raw_fd(), F_GETLK, &mut lock as *mut Flock) } == -1 {
+            anyhow::bail!(
+                "Checking the database locks failed: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        Ok(lock.l_type != F_UNLCK)
+    }
+}
+
+/// Other platforms take no locks
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+mod sys {
+    use super::Kind;
+    use std::fs::File;
+
+    pub fn set_lock(_file: &File, _kind: Kind, _start: u64, _len: u64) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    pub fn is_locked(_file: &File, _byte: u64) -> anyhow::Result<bool> {
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tests::copy_sample_db;
+
+    #[test]
+    fn test_lock_levels() -> Result<()> {
+        let db_path = copy_sample_db("lock-levels");
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&db_path)?;
+        lock(&file, LockLevel::Shared)?;
+        lock(&file, LockLevel::Reserved)?;
+        lock(&file, LockLevel::Exclusive)?;
+        unlock_to_shared(&file)?;
+        // Locks held by this process never conflict with its own
+        assert!(!is_reserved(&file)?);
+        Ok(())
+    }
+}
@@ -1,66 +1,112 @@
+pub mod btree;
+pub mod btree_write;
+pub mod header;
+pub mod integrity;
+pub mod journal;
+pub mod lock;
+pub mod mmap;
+pub mod pager;
 pub mod record;
 pub mod sql_parser;
 pub mod sqlite_schema;
 pub mod util;
+pub mod value;
+pub mod wal;
 use anyhow::{bail, Result};
-
-use std::fs::File;
-use std::io::prelude::*;
+use pager::{Pager, ReadMode};
+use sql_parser::TransactionStatement;
+use std::io::Write;
+use value::BlobFormat;
 
 fn main() -> Result<()> {
-    // Parse arguments
-    let args = std::env::args().collect::<Vec<_>>();
+    // Parse arguments, `--mmap` may be given anywhere to read the database through a memory map
+    let mut args = std::env::args().collect::<Vec<_>>();
+    let read_mode = if args.iter().any(|arg| arg == "--mmap") {
+        args.retain(|arg| arg != "--mmap");
+        ReadMode::MemoryMapped
+    } else {
+        ReadMode::Buffered
+    };
+    // `--blob-format=raw|hex|literal` picks how query output shows BLOB values
+    let blob_format = match args
+        .iter()
+        .find_map(|arg| arg.strip_prefix("--blob-format="))
+    {
+        Some(name) => BlobFormat::from_name(name)?,
+        None => BlobFormat::Raw,
+    };
+    args.retain(|arg| !arg.starts_with("--blob-format="));
     match args.len() {
         0 | 1 => bail!("Missing <database path> and <command>"),
         2 => bail!("Missing <command>"),
         _ => {}
     }
+    let mut pager = Pager::with_options(&args[1], Pager::DEFAULT_CACHE_SIZE, read_mode)?;
+
+    // Statements run one after the other, changes left uncommitted by a missing COMMIT are dropped
+    for statement in sql_parser::split_statements(&args[2]) {
+        execute(statement, &mut pager, blob_format)?;
+    }
+    Ok(())
+}
 
+fn execute(statement: &str, pager: &mut Pager, blob_format: BlobFormat) -> Result<()> {
     // Parse command and act accordingly
-    let command = &args[2].as_str().split(" ").collect::<Vec<&str>>();
+    let command = &statement.split(' ').collect::<Vec<&str>>();
 
     match command[0].to_lowercase().as_str() {
         ".dbinfo" => {
-            let mut file = File::open(&args[1])?;
-            let mut header = [0; 100];
-            file.read_exact(&mut header)?;
-
-            // The page size is stored at the 16th byte offset, using 2 bytes in big-endian order
-            #[allow(unused_variables)]
-            let page_size = u16::from_be_bytes([header[16], header[17]]);
+            let header = pager.header().clone();
+            let num_tables = util::get_tables(pager)?
+                .iter()
+                .filter(|schema| schema.schema_type == "table")
+                .count();
 
-            // Assume page1 is a b-tree leaf page: 0x0d
-            let mut page1_header = [0; 8];
-            file.read_exact(&mut page1_header)?;
-            #[allow(unused_variables)]
-            let num_tables = u16::from_be_bytes([page1_header[3], page1_header[4]]);
-            // You can use print statements as follows for debugging, they'll be visible when running tests.
-            println!("Logs from your program will appear here!");
-
-            // Uncomment this block to pass the first stage
-            println!("database page size: {}", page_size);
+            println!("database page size: {}", header.page_size);
+            println!("write format: {}", header.write_version);
+            println!("read format: {}", header.read_version);
+            println!("reserved bytes: {}", header.reserved_bytes);
+            println!("file change counter: {}", header.file_change_counter);
+            println!("database page count: {}", header.database_size);
+            println!("freelist page count: {}", header.freelist_count);
+            println!("schema cookie: {}", header.schema_cookie);
+            println!("schema format: {}", header.schema_format);
+            println!("default cache size: {}", header.default_page_cache_size);
+            println!("autovacuum top root: {}", header.largest_root_page);
+            println!("incremental vacuum: {}", header.incremental_vacuum);
+            println!("text encoding: {:?}", header.text_encoding);
+            println!("user version: {}", header.user_version);
+            println!("application id: {}", header.application_id);
+            println!("software version: {}", header.sqlite_version_number);
             println!("number of tables: {}", num_tables);
         }
         ".tables" => {
-            let tables = util::get_tables(&args[1])?;
+            let tables = util::get_tables(pager)?;
 
             let mut table_names: Vec<String> = Vec::new();
             for table in tables {
-                table_names.push(table.tbl_name);
+                if table.schema_type == "table" {
+                    table_names.push(table.tbl_name);
+                }
             }
             println!("{}", table_names.join(" "));
         }
         "select" => match command[1].to_lowercase().as_str() {
             "count(*)" => {
-                assert_eq!(command[2].to_lowercase().as_str(), "from");
-                let table_name = command[3];
-                let num_rows = util::count_table_rows(table_name, &args[1])?;
+                let select_statement = match sql_parser::parse_select_statement(statement) {
+                    Ok((_, statement)) => statement,
+                    Err(err) => bail!("Invalid SELECT statement: {}", err),
+                };
+                let table_name = select_statement.from.as_str();
+                let condition = select_statement.condition;
+                let num_rows = util::count_table_rows(table_name, condition, pager)?;
                 println!("{}", num_rows);
             }
             _ => {
-                let select_statement = sql_parser::parse_select_statement(&args[2].to_lowercase())
-                    .unwrap()
-                    .1; //FIXME: lifetime...
+                let select_statement = match sql_parser::parse_select_statement(statement) {
+                    Ok((_, statement)) => statement,
+                    Err(err) => bail!("Invalid SELECT statement: {}", err),
+                };
                 let fields = select_statement
                     .selector
                     .split(',')
@@ -69,18 +115,87 @@ fn main() -> Result<()> {
 
                 let table_name = select_statement.from.as_str();
                 let condition = select_statement.condition;
-                let records =
-                    util::get_records_from_table(table_name, fields, condition, &args[1])?;
+                let records = util::get_records_from_table(table_name, fields, condition, pager)?;
 
+                // Raw blobs need not be UTF-8, rows are written out as bytes
+                let mut stdout = std::io::stdout().lock();
                 for record in records {
-                    let record_strings = record
+                    let fields = record
                         .iter()
-                        .map(|s| s.to_string())
-                        .collect::<Vec<String>>();
-                    println!("{}", record_strings.join("|"));
+                        .map(|field| field.to_output(blob_format))
+                        .collect::<Vec<Vec<u8>>>();
+                    stdout.write_all(&fields.join(&b'|'))?;
+                    stdout.write_all(b"\n")?;
                 }
             }
         },
+        "insert" => {
+            let insert_statement = match sql_parser::parse_insert_statement(statement) {
+                Ok((_, statement)) => statement,
+                Err(err) => bail!("Invalid INSERT statement: {}", err),
+            };
+            util::insert_rows(insert_statement, pager)?;
+        }
+        "delete" => {
+            let delete_statement = match sql_parser::parse_delete_statement(statement) {
+                Ok((_, statement)) => statement,
+                Err(err) => bail!("Invalid DELETE statement: {}", err),
+            };
+            util::delete_rows(delete_statement, pager)?;
+        }
+        "update" => {
+            let update_statement = match sql_parser::parse_update_statement(statement) {
+                Ok((_, statement)) => statement,
+                Err(err) => bail!("Invalid UPDATE statement: {}", err),
+            };
+            util::update_rows(update_statement, pager)?;
+        }
+        "create" => match command.get(1).map(|word| word.to_lowercase()).as_deref() {
+            Some("index") | Some("unique") => {
+                let create_statement = match sql_parser::parse_create_index_statement(statement) {
+                    Ok((_, statement)) => statement,
+                    Err(err) => bail!("Invalid CREATE INDEX statement: {}", err),
+                };
+                util::create_index(create_statement, pager)?;
+            }
+            _ => {
+                let create_statement = match sql_parser::parse_create_table_statement(statement) {
+                    Ok((_, statement)) => statement,
+                    Err(err) => bail!("Invalid CREATE TABLE statement: {}", err),
+                };
+                util::create_table(create_statement, pager)?;
+            }
+        },
+        "begin" | "commit" | "end" | "rollback" => {
+            match sql_parser::parse_transaction_statement(statement) {
+                Ok((_, TransactionStatement::Begin)) => pager.begin()?,
+                Ok((_, TransactionStatement::Commit)) => pager.commit_transaction()?,
+                Ok((_, TransactionStatement::Rollback)) => pager.rollback_transaction()?,
+                Err(err) => bail!("Invalid transaction statement: {}", err),
+            }
+        }
+        "pragma" => {
+            let pragma_statement = match sql_parser::parse_pragma_statement(statement) {
+                Ok((_, statement)) => statement,
+                Err(err) => bail!("Invalid PRAGMA statement: {}", err),
+            };
+            for line in util::pragma(pragma_statement, pager)? {
+                println!("{}", line);
+            }
+        }
+        "vacuum" => {
+            if let Err(err) = sql_parser::parse_vacuum_statement(statement) {
+                bail!("Invalid VACUUM statement: {}", err);
+            }
+            util::vacuum(pager)?;
+        }
+        "drop" => {
+            let drop_statement = match sql_parser::parse_drop_table_statement(statement) {
+                Ok((_, statement)) => statement,
+                Err(err) => bail!("Invalid DROP TABLE statement: {}", err),
+            };
+            util::drop_table(drop_statement, pager)?;
+        }
         _ => bail!("Missing or invalid command passed: {:?}", command),
     }
 
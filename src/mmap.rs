@@ -0,0 +1,96 @@
+use anyhow::{bail, Result};
+use std::fs::File;
+use std::ops::Range;
+
+/// Read-only shared mapping of a whole file, unmapped on drop.
+/// Writes to the file, by this process or others, change the mapped memory, so it is only
+/// ever copied out and never handed out as a slice. Reading past the end of a file that
+/// another process truncated raises SIGBUS, SQLite's locks keep other connections from doing so.
+pub struct Mmap {
+    ptr: *const u8,
+    len: usize,
+}
+
+impl Mmap {
+    #[cfg(unix)]
+    pub fn map(file: &File) -> Result<Self> {
+        use std::os::unix::io::AsRawFd;
+
+        let len = file.metadata()?.len() as usize;
+        if len == 0 {
+            bail!("Cannot map an empty file");
+        }
+        let ptr = unsafe {
+            sys::mmap(
+                std::ptr::null_mut(),
+                len,
+                sys::PROT_READ,
+                sys::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr as isize == -1 {
+            bail!("mmap failed: {}", std::io::Error::last_os_error());
+        }
+        Ok(Self {
+            ptr: ptr as *const u8,
+            len,
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn map(_file: &File) -> Result<Self> {
+        bail!("Memory-mapping is only supported on unix")
+    }
+}
+
+impl Mmap {
+    /// Size of the mapping in bytes, the size of the file when it was mapped
+    pub fn size(&self) -> usize {
+        self.len
+    }
+
+    /// Copy `range` out of the mapping
+    pub fn copy(&self, range: Range<usize>) -> Vec<u8> {
+        assert!(range.start <= range.end && range.end <= self.len);
+        let mut bytes = vec![0; range.len()];
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                self.ptr.add(range.start),
+                bytes.as_mut_ptr(),
+                range.len(),
+            );
+        }
+        bytes
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        unsafe {
+            sys::munmap(self.ptr as *mut _, self.len);
+        }
+    }
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::os::raw::{c_int, c_long, c_void};
+
+    pub const PROT_READ: c_int = 1;
+    pub const MAP_SHARED: c_int = 1;
+
+    extern "C" {
+        pub fn mmap(
+            addr: *mut c_void,
+            len: usize,
+            prot: c_int,
+            flags: c_int,
+            fd: c_int,
+            offset: c_long,
+        ) -> *mut c_void;
+        pub fn munmap(addr: *mut c_void, len: usize) -> c_int;
+    }
+}
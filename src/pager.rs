@@ -0,0 +1,612 @@
+use crate::header::{DatabaseHeader, HEADER_SIZE};
+use crate::journal;
+use crate::lock::{self, LockLevel};
+use crate::mmap::Mmap;
+use crate::wal::Wal;
+use anyhow::{bail, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::ops::Deref;
+use std::rc::Rc;
+
+/// A page read into its own buffer, shared with the page cache
+#[derive(Clone)]
+pub struct Page(Rc<[u8]>);
+
+impl Deref for Page {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadMode {
+    /// Read pages with seek and read calls, keeping them in the page cache
+    Buffered,
+    /// Map the whole file and copy pages out of the mapping instead of making read calls,
+    /// falling back to buffered reads if the file can't be mapped. Databases in WAL mode
+    /// are never mapped, as checkpoints of other connections may shrink the file under the map.
+    MemoryMapped,
+}
+
+/// Owns the open database file and hands out pages through an LRU cache,
+/// so repeated visits of the same b-tree pages don't go back to the file.
+/// Written pages are kept in memory until `commit` writes them to the file.
+/// Like a SQLite connection in a read transaction, the pager holds a SHARED lock on the file
+/// while it is open, so that nobody commits under its cache.
+pub struct Pager {
+    filepath: String,
+    file: File,
+    header: DatabaseHeader,
+    committed_header: DatabaseHeader,
+    cache: PageCache,
+    dirty: BTreeMap<u64, Rc<[u8]>>,
+    mmap: Option<Mmap>,
+    wal: Option<Wal>,
+    /// Set by `begin`, commits are then held back until `commit_transaction`
+    in_transaction: bool,
+}
+
+impl Pager {
+    pub const DEFAULT_CACHE_SIZE: usize = 2000;
+
+    pub fn open(filepath: &str) -> Result<Self> {
+        Self::with_cache_size(filepath, Self::DEFAULT_CACHE_SIZE)
+    }
+
+    /// Open the database keeping at most `cache_size` pages in memory
+    pub fn with_cache_size(filepath: &str, cache_size: usize) -> Result<Self> {
+        Self::with_options(filepath, cache_size, ReadMode::Buffered)
+    }
+
+    pub fn with_options(filepath: &str, cache_size: usize, read_mode: ReadMode) -> Result<Self> {
+        // Fall back to a read-only handle for files we may not write to
+        let mut file = match OpenOptions::new().read(true).write(true).open(filepath) {
+            Ok(file) => file,
+            Err(_) => File::open(filepath)?,
+        };
+        lock::lock(&file, LockLevel::Shared)?;
+        // A journal left by an interrupted commit means the file may be half-written, unless the
+        // writer holding the RESERVED lock is still in the middle of that commit
+        let journal_path = journal::journal_path(filepath);
+        if std::path::Path::new(&journal_path).exists() && !lock::is_reserved(&file)? {
+            lock::lock(&file, LockLevel::Exclusive)?;
+            journal::rollback_hot_journal(filepath, &mut file)?;
+            lock::unlock_to_shared(&file)?;
+        }
+        let mut db_header = [0; HEADER_SIZE];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut db_header)?;
+        let mut header = DatabaseHeader::from_bytes(&db_header)?;
+
+        // Committed pages in the WAL are newer than the database file, including page 1 with the header
+        let mut wal = Wal::open(filepath)?;
+        if let Some(wal) = &mut wal {
+            if wal.header().page_size != header.page_size {
+                bail!(
+                    "WAL page size {} does not match the database page size {}",
+                    wal.header().page_size,
+                    header.page_size
+                );
+            }
+            if let Some(page1) = wal.read_page(1)? {
+                header = DatabaseHeader::from_bytes(&page1[..HEADER_SIZE])?;
+            }
+        }
+
+        // The size in the header is only trusted if it was written by a version that maintains it
+        if header.version_valid_for != header.file_change_counter || header.database_size == 0 {
+            header.database_size = match wal.as_ref().and_then(Wal::database_size) {
+                Some(database_size) => database_size,
+                None => (file.metadata()?.len() / header.page_size as u64) as u32,
+            };
+        }
+
+        let mmap = match read_mode {
+            ReadMode::MemoryMapped if !header.is_wal_mode() => Mmap::map(&file).ok(),
+            _ => None,
+        };
+
+        Ok(Self {
+            filepath: filepath.to_string(),
+            file,
+            committed_header: header.clone(),
+            header,
+            cache: PageCache::new(cache_size),
+            dirty: BTreeMap::new(),
+            mmap,
+            wal,
+            in_transaction: false,
+        })
+    }
+
+    pub fn filepath(&self) -> &str {
+        &self.filepath
+    }
+
+    pub fn header(&self) -> &DatabaseHeader {
+        &self.header
+    }
+
+    /// Header changes are written to page 1 on the next commit
+    pub fn header_mut(&mut self) -> &mut DatabaseHeader {
+        &mut self.header
+    }
+
+    pub fn page_size(&self) -> u32 {
+        self.header.page_size
+    }
+
+    /// Page size minus the reserved space at the end of each page
+    pub fn usable_size(&self) -> u32 {
+        self.header.usable_size()
+    }
+
+    pub fn read_mode(&self) -> ReadMode {
+        match self.mmap {
+            Some(_) => ReadMode::MemoryMapped,
+            None => ReadMode::Buffered,
+        }
+    }
+
+    pub fn read_page(&mut self, page_num: u64) -> Result<Page> {
+        if page_num == 0 {
+            bail!("Page numbers start at 1");
+        }
+        let page_size = self.page_size() as usize;
+        let start = (page_num - 1) as usize * page_size;
+        let end = start + page_size;
+        if let Some(page) = self.dirty.get(&page_num) {
+            return Ok(Page(page.clone()));
+        }
+        if page_num > self.header.database_size as u64 {
+            bail!(
+                "Page {} is past the end of the database ({} pages)",
+                page_num,
+                self.header.database_size
+            );
+        }
+        if let Some(page) = self.cache.get(page_num) {
+            return Ok(page);
+        }
+
+        let in_wal = matches!(&self.wal, Some(wal) if wal.contains(page_num));
+        let page = match (&mut self.wal, &self.mmap) {
+            (Some(wal), _) if in_wal => wal.read_page(page_num)?.unwrap_or_default(),
+            (_, Some(mmap)) if end <= mmap.size() => mmap.copy(start..end),
+            _ => {
+                let mut page = vec![0; page_size];
+                self.file.seek(SeekFrom::Start(start as u64))?;
+                self.file.read_exact(&mut page)?;
+                page
+            }
+        };
+
+        let page = Page(page.into());
+        self.cache.insert(page_num, page.clone());
+        Ok(page)
+    }
+
+    /// Replace the content of a page, the change stays in memory until `commit`
+    pub fn write_page(&mut self, page_num: u64, page: Vec<u8>) -> Result<()> {
+        if page.len() != self.page_size() as usize {
+            bail!(
+                "Expected a page of {} bytes, got {}",
+                self.page_size(),
+                page.len()
+            );
+        }
+        if page_num == 0 || page_num > self.header.database_size as u64 {
+            bail!("Cannot write page {}, it has not been allocated", page_num);
+        }
+        self.dirty.insert(page_num, page.into());
+        Ok(())
+    }
+
+    /// Hand out a zeroed page, reusing a page from the freelist when there is one
+    /// and appending to the end of the database otherwise
+    pub fn allocate_page(&mut self) -> Result<u64> {
+        // Auto-vacuum databases also keep pointer map pages up to date, which this pager doesn't
+        if self.header.largest_root_page != 0 {
+            bail!("Cannot allocate pages in an auto-vacuum database");
+        }
+        let page_num = match self.header.freelist_trunk_page as u64 {
+            0 => {
+                self.header.database_size += 1;
+                self.header.database_size as u64
+            }
+            trunk => {
+                let mut trunk_page = self.read_page(trunk)?.to_vec();
+                let leaf_count = u32::from_be_bytes(trunk_page[4..8].try_into()?) as usize;
+                let page_num = if leaf_count > 0 {
+                    // Take the last leaf of the trunk page
+                    let leaf = &trunk_page[4 + leaf_count * 4..8 + leaf_count * 4];
+                    let leaf = u32::from_be_bytes(leaf.try_into()?) as u64;
+                    trunk_page[4..8].copy_from_slice(&(leaf_count as u32 - 1).to_be_bytes());
+                    self.write_page(trunk, trunk_page)?;
+                    leaf
+                } else {
+                    // An empty trunk page is reused itself, the next trunk takes its place
+                    self.header.freelist_trunk_page =
+                        u32::from_be_bytes(trunk_page[..4].try_into()?);
+                    trunk
+                };
+                self.header.freelist_count -= 1;
+                page_num
+            }
+        };
+        self.write_page(page_num, vec![0; self.page_size() as usize])?;
+        Ok(page_num)
+    }
+
+    /// Put a page that is no longer used on the freelist, as a leaf of the first trunk page,
+    /// or as the new first trunk page when that one is full
+    pub fn free_page(&mut self, page_num: u64) -> Result<()> {
+        if page_num <= 1 || page_num > self.header.database_size as u64 {
+            bail!("Cannot free page {}", page_num);
+        }
+        let trunk = self.header.freelist_trunk_page as u64;
+        // SQLite leaves room for 6 more leaves for compatibility with older versions
+        let max_leaf_count = self.usable_size() as usize / 4 - 8;
+        if trunk != 0 {
+            let mut trunk_page = self.read_page(trunk)?.to_vec();
+            let leaf_count = u32::from_be_bytes(trunk_page[4..8].try_into()?) as usize;
+            if leaf_count < max_leaf_count {
+                trunk_page[8 + leaf_count * 4..12 + leaf_count * 4]
+                    .copy_from_slice(&(page_num as u32).to_be_bytes());
+                trunk_page[4..8].copy_from_slice(&(leaf_count as u32 + 1).to_be_bytes());
+                self.write_page(trunk, trunk_page)?;
+                self.header.freelist_count += 1;
+                return Ok(());
+            }
+        }
+
+        let mut trunk_page = vec![0; self.page_size() as usize];
+        trunk_page[..4].copy_from_slice(&(trunk as u32).to_be_bytes());
+        self.write_page(page_num, trunk_page)?;
+        self.header.freelist_trunk_page = page_num as u32;
+        self.header.freelist_count += 1;
+        Ok(())
+    }
+
+    /// Open a transaction: the changes of the following statements are only written
+    /// to the file by `commit_transaction`, and can be dropped with `rollback_transaction`
+    pub fn begin(&mut self) -> Result<()> {
+        if self.in_transaction {
+            bail!("Cannot start a transaction within a transaction");
+        }
+        self.in_transaction = true;
+        Ok(())
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.in_transaction
+    }
+
+    pub fn commit_transaction(&mut self) -> Result<()> {
+        if !self.in_transaction {
+            bail!("Cannot commit - no transaction is active");
+        }
+        self.in_transaction = false;
+        self.commit()
+    }
+
+    pub fn rollback_transaction(&mut self) -> Result<()> {
+        if !self.in_transaction {
+            bail!("Cannot rollback - no transaction is active");
+        }
+        self.in_transaction = false;
+        self.rollback();
+        Ok(())
+    }
+
+    /// Write the changed pages and the updated header, unless `begin` opened a transaction
+    /// that is still going on.
+    /// In WAL mode the pages are appended to the WAL as one transaction. Otherwise pages only
+    /// reach the database file here, so this is also where their original content is saved
+    /// to the rollback journal first: a crash before the journal is deleted leaves a hot
+    /// journal that the next open rolls back.
+    pub fn commit(&mut self) -> Result<()> {
+        if self.dirty.is_empty() || self.in_transaction {
+            return Ok(());
+        }
+        // Switching the journal mode is itself committed through the rollback journal
+        let wal_mode = self.header.is_wal_mode() && self.committed_header.is_wal_mode();
+        if !wal_mode && matches!(&self.wal, Some(wal) if wal.database_size().is_some()) {
+            bail!("Cannot write to a database whose WAL file has committed frames");
+        }
+        self.lock_for_writing()?;
+
+        // Readers notice the change through the file change counter
+        self.header.file_change_counter = self.header.file_change_counter.wrapping_add(1);
+        self.header.version_valid_for = self.header.file_change_counter;
+        let mut page1 = self.read_page(1)?.to_vec();
+        page1[..HEADER_SIZE].copy_from_slice(&self.header.to_bytes());
+        self.dirty.insert(1, page1.into());
+
+        let page_size = self.page_size() as u64;
+        if wal_mode {
+            let wal = match &mut self.wal {
+                Some(wal) => wal,
+                None => self
+                    .wal
+                    .insert(Wal::create(&self.filepath, page_size as u32)?),
+            };
+            let pages = self
+                .dirty
+                .iter()
+                .map(|(page_num, page)| (*page_num, &page[..]))
+                .collect::<Vec<_>>();
+            wal.append_transaction(&pages, self.header.database_size)?;
+            for (page_num, page) in std::mem::take(&mut self.dirty) {
+                self.cache.insert(page_num, Page(page));
+            }
+            lock::unlock_to_shared(&self.file)?;
+            self.committed_header = self.header.clone();
+            return Ok(());
+        }
+
+        let original_size = self.committed_header.database_size;
+        let new_size = self.header.database_size;
+        // Pages past the original end of the file are dropped by truncating it, while pages
+        // cut off by a shrinking database must be restored like the overwritten ones
+        let overwritten = self
+            .dirty
+            .keys()
+            .copied()
+            .filter(|&page_num| page_num <= original_size.min(new_size) as u64);
+        let truncated = new_size as u64 + 1..=original_size as u64;
+        let mut originals = Vec::new();
+        for page_num in overwritten.chain(truncated) {
+            let mut page = vec![0; page_size as usize];
+            self.file
+                .seek(SeekFrom::Start((page_num - 1) * page_size))?;
+            self.file.read_exact(&mut page)?;
+            originals.push((page_num, page));
+        }
+        journal::write_journal(&self.filepath, page_size as u32, original_size, &originals)?;
+
+        // Writing changes the memory of the mapping, the file is mapped again afterwards
+        let mapped = self.mmap.take().is_some();
+        for (page_num, page) in std::mem::take(&mut self.dirty) {
+            self.file
+                .seek(SeekFrom::Start((page_num - 1) * page_size))?;
+            self.file.write_all(&page)?;
+            self.cache.insert(page_num, Page(page));
+        }
+        self.file
+            .set_len(self.header.database_size as u64 * page_size)?;
+        self.file.sync_all()?;
+        if mapped && !self.header.is_wal_mode() {
+            self.mmap = Mmap::map(&self.file).ok();
+        }
+        journal::delete_journal(&self.filepath)?;
+        lock::unlock_to_shared(&self.file)?;
+        self.committed_header = self.header.clone();
+        Ok(())
+    }
+
+    /// Copy the committed frames of the WAL into the database file and start the WAL over.
+    /// Returns the number of frames that were copied, or `None` if the database isn't in WAL mode
+    pub fn checkpoint(&mut self) -> Result<Option<u32>> {
+        if !self.committed_header.is_wal_mode() {
+            return Ok(None);
+        }
+        if !self.dirty.is_empty() {
+            bail!("Cannot checkpoint with uncommitted changes");
+        }
+        if self.wal.is_none() {
+            return Ok(Some(0));
+        }
+        self.lock_for_writing()?;
+        let wal = self.wal.as_mut().expect("the WAL was checked above");
+
+        let page_size = self.header.page_size as u64;
+        let database_size = self.header.database_size as u64;
+        for page_num in wal.page_numbers() {
+            if page_num > database_size {
+                continue;
+            }
+            if let Some(page) = wal.read_page(page_num)? {
+                self.file
+                    .seek(SeekFrom::Start((page_num - 1) * page_size))?;
+                self.file.write_all(&page)?;
+            }
+        }
+        self.file.set_len(database_size * page_size)?;
+        self.file.sync_all()?;
+
+        let frame_count = wal.frame_count();
+        wal.reset()?;
+        lock::unlock_to_shared(&self.file)?;
+        Ok(Some(frame_count))
+    }
+
+    /// Take the EXCLUSIVE lock before writing to the database file or the WAL.
+    /// In WAL mode every SQLite connection holds a SHARED lock for as long as it has the database
+    /// open, and this pager doesn't maintain the wal-index in `<db>-shm` that would tell them about
+    /// new frames, so it only writes to the WAL while it is the only connection. The WAL must also
+    /// still be the one this pager read, not one another connection has committed to since.
+    fn lock_for_writing(&self) -> Result<()> {
+        lock::lock(&self.file, LockLevel::Reserved)?;
+        if let Err(err) = lock::lock(&self.file, LockLevel::Exclusive) {
+            lock::unlock_to_shared(&self.file)?;
+            return Err(err);
+        }
+        let wal_current = match &self.wal {
+            Some(wal) => wal.is_current(&self.filepath)?,
+            None => {
+                !matches!(Wal::open(&self.filepath)?, Some(wal) if wal.database_size().is_some())
+            }
+        };
+        if self.committed_header.is_wal_mode() && !wal_current {
+            lock::unlock_to_shared(&self.file)?;
+            bail!("database is locked: another connection committed since the database was opened");
+        }
+        Ok(())
+    }
+
+    /// Switch between WAL mode and the rollback journal. Leaving WAL mode checkpoints
+    /// the WAL and deletes it.
+    pub fn set_wal_mode(&mut self, wal_mode: bool) -> Result<()> {
+        if self.in_transaction {
+            bail!("Cannot change the journal mode within a transaction");
+        }
+        if self.header.is_wal_mode() == wal_mode {
+            return Ok(());
+        }
+        if !wal_mode {
+            self.checkpoint()?;
+        }
+
+        let version = if wal_mode { 2 } else { 1 };
+        self.header.write_version = version;
+        self.header.read_version = version;
+        let page1 = self.read_page(1)?.to_vec();
+        self.write_page(1, page1)?;
+        self.commit()?;
+
+        if !wal_mode && self.wal.take().is_some() {
+            std::fs::remove_file(format!("{}-wal", self.filepath))?;
+        }
+        Ok(())
+    }
+
+    /// Drop the changes made since the last commit
+    pub fn rollback(&mut self) {
+        self.dirty.clear();
+        self.header = self.committed_header.clone();
+    }
+}
+
+/// Least-recently-used page cache: every access stamps the page with a new tick,
+/// and the page with the oldest tick is evicted once the cache is full
+struct PageCache {
+    capacity: usize,
+    tick: u64,
+    pages: HashMap<u64, (Page, u64)>,
+    recency: BTreeMap<u64, u64>,
+}
+
+impl PageCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            tick: 0,
+            pages: HashMap::new(),
+            recency: BTreeMap::new(),
+        }
+    }
+
+    fn get(&mut self, page_num: u64) -> Option<Page> {
+        let (page, last_used) = self.pages.get_mut(&page_num)?;
+        self.recency.remove(last_used);
+        self.tick += 1;
+        *last_used = self.tick;
+        self.recency.insert(self.tick, page_num);
+        Some(page.clone())
+    }
+
+    fn insert(&mut self, page_num: u64, page: Page) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Some((_, last_used)) = self.pages.remove(&page_num) {
+            self.recency.remove(&last_used);
+        }
+        while self.pages.len() >= self.capacity {
+            match self.recency.pop_first() {
+                Some((_, evicted)) => self.pages.remove(&evicted),
+                None => break,
+            };
+        }
+        self.tick += 1;
+        self.pages.insert(page_num, (page, self.tick));
+        self.recency.insert(self.tick, page_num);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_page_cache_evicts_least_recently_used() {
+        let mut cache = PageCache::new(2);
+        cache.insert(1, Page(vec![1].into()));
+        cache.insert(2, Page(vec![2].into()));
+        cache.get(1);
+        cache.insert(3, Page(vec![3].into()));
+
+        assert!(cache.get(1).is_some());
+        assert!(cache.get(2).is_none());
+        assert!(cache.get(3).is_some());
+    }
+
+    #[test]
+    fn test_read_page_sample_db() {
+        let mut pager = Pager::with_cache_size("sample.db", 1).unwrap();
+        assert_eq!(pager.page_size(), 4096);
+        let page2 = pager.read_page(2).unwrap();
+        assert_eq!(page2[0], 0x0d);
+        // Served from the cache the second time
+        assert!(Rc::ptr_eq(&page2.0, &pager.read_page(2).unwrap().0));
+    }
+
+    #[test]
+    fn test_memory_mapped_pages_match_buffered() {
+        let mut buffered = Pager::open("sample.db").unwrap();
+        let mut mapped = Pager::with_options("sample.db", 0, ReadMode::MemoryMapped).unwrap();
+        assert_eq!(mapped.read_mode(), ReadMode::MemoryMapped);
+        for page_num in 1..=4 {
+            let page = mapped.read_page(page_num).unwrap();
+            assert_eq!(&page[..], &buffered.read_page(page_num).unwrap()[..]);
+        }
+    }
+
+    #[test]
+    fn test_memory_mapped_pages_after_commit() -> Result<()> {
+        let db_path = crate::util::tests::copy_sample_db("mmap-commit");
+        // Without a cache every read goes to the mapping
+        let mut pager = Pager::with_options(&db_path, 0, ReadMode::MemoryMapped)?;
+        let mut page = pager.read_page(2)?.to_vec();
+        page[4000] = 0xaa;
+        pager.write_page(2, page)?;
+        let new_page = pager.allocate_page()?;
+        let mut page = vec![0; pager.page_size() as usize];
+        page[0] = 0xbb;
+        pager.write_page(new_page, page)?;
+        pager.commit()?;
+
+        assert_eq!(pager.read_mode(), ReadMode::MemoryMapped);
+        assert_eq!(pager.read_page(2)?[4000], 0xaa);
+        assert_eq!(pager.read_page(new_page)?[0], 0xbb);
+        Ok(())
+    }
+
+    #[test]
+    fn test_transaction_holds_back_commits() -> Result<()> {
+        let db_path = crate::util::tests::copy_sample_db("transaction");
+        let mut pager = Pager::open(&db_path)?;
+        pager.begin()?;
+        assert!(pager.begin().is_err());
+        let page = pager.allocate_page()?;
+        pager.commit()?;
+        assert_eq!(Pager::open(&db_path)?.header().database_size, 4);
+
+        pager.rollback_transaction()?;
+        assert_eq!(pager.header().database_size, 4);
+        assert!(pager.commit_transaction().is_err());
+
+        pager.begin()?;
+        assert_eq!(pager.allocate_page()?, page);
+        pager.commit_transaction()?;
+        assert_eq!(Pager::open(&db_path)?.header().database_size, 5);
+        assert!(std::fs::metadata(journal::journal_path(&db_path)).is_err());
+        Ok(())
+    }
+}
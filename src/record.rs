@@ -1,113 +1,251 @@
-use crate::util::read_varint;
+use crate::header::TextEncoding;
+use crate::util::{read_varint, write_varint};
+use crate::value::{Value, ValueRef};
 use anyhow::{bail, Result};
-use std::{fmt, str};
+use std::borrow::Cow;
+use std::str;
 
+/// A record whose header is parsed up front and whose values are only decoded when asked for,
+/// borrowing from the payload
 #[derive(Debug, Clone)]
-pub enum RecordField {
-    Null,
-    Int8(i8),
-    Int16(i16),
-    Int24(i32),
-    Int32(i32),
-    Int48(i64),
-    Int64(i64),
-    Float64(f64),
-    Zero,
-    One,
-    Internal,
-    Blob(String),
-    Text(String),
+pub struct RecordView<'a> {
+    payload: &'a [u8],
+    encoding: TextEncoding,
+    /// Serial type and offset in the payload of each value
+    columns: Vec<(u64, usize)>,
 }
 
-impl RecordField {
-    pub fn parse_from_bytes(variant_indicator: u64, content: &[u8]) -> Result<(Self, &[u8])> {
-        match variant_indicator {
-            0 => Ok((Self::Null, content)),
-            1 => {
-                let num = i8::from_be_bytes([content[0]]);
-                Ok((Self::Int8(num.into()), &content[1..]))
-            }
-            2 => {
-                let num = i16::from_be_bytes(content[..2].try_into()?);
-                Ok((Self::Int16(num.into()), &content[2..]))
-            }
-            3 => {
-                let num = i32::from_be_bytes([0, content[0], content[1], content[2]]);
-                Ok((Self::Int24(num.into()), &content[3..]))
-            }
-            4 => {
-                let num = i32::from_be_bytes(content[..4].try_into()?);
-                Ok((Self::Int32(num.into()), &content[4..]))
-            }
-            5 => {
-                let bytes = [&[0, 0], &content[..6]].concat();
-                let num = i64::from_be_bytes(bytes.try_into().unwrap()); // TODO: figure out why ? cannot be used here
-                Ok((Self::Int48(num.into()), &content[6..]))
-            }
-            6 => {
-                let num = i64::from_be_bytes(content[..8].try_into()?);
-                Ok((Self::Int64(num.into()), &content[8..]))
-            }
-            7 => {
-                let num = f64::from_be_bytes(content[..8].try_into()?);
-                Ok((Self::Float64(num.into()), &content[8..]))
-            }
-            8 => Ok((Self::Zero, content)),
-            9 => Ok((Self::One, content)),
-            10 | 11 => Ok((Self::Internal, content)), // TODO: Should raise warning
-            v @ 12.. if variant_indicator % 2 == 0 => {
-                let blob_size = ((v - 12) / 2) as usize;
-                let text = str::from_utf8(&content[..blob_size])?.to_owned();
-                Ok((Self::Blob(text), &content[blob_size..]))
-            }
-            v @ 12.. if variant_indicator % 2 == 1 => {
-                let text_size = ((v - 13) / 2) as usize;
-                let text = str::from_utf8(&content[..text_size])?.to_owned();
-                Ok((Self::Text(text), &content[text_size..]))
-            }
-            _ => {
-                bail!("Not reachable")
+impl<'a> RecordView<'a> {
+    pub fn parse(payload: &'a [u8], encoding: TextEncoding) -> Result<Self> {
+        let (header_size, rest) = read_varint(payload)?;
+        let header_start = payload.len() - rest.len();
+        if header_size as usize > payload.len() {
+            bail!(
+                "Record header of {} bytes runs past the end of the record",
+                header_size
+            );
+        }
+        if (header_size as usize) < header_start {
+            bail!("Record header of {} bytes is too small", header_size);
+        }
+
+        let mut header = &payload[header_start..header_size as usize];
+        let mut offset = header_size as usize;
+        let mut columns = Vec::new();
+        while !header.is_empty() {
+            let (serial_type, header_left) = read_varint(header)?;
+            header = header_left;
+            match offset
+                .checked_add(value_size(serial_type))
+                .filter(|&end| end <= payload.len())
+            {
+                Some(end) => {
+                    columns.push((serial_type, offset));
+                    offset = end;
+                }
+                None => bail!(
+                    "Field of serial type {} runs past the end of the record",
+                    serial_type
+                ),
             }
         }
+        Ok(Self {
+            payload,
+            encoding,
+            columns,
+        })
+    }
+
+    /// Number of values in the record
+    pub fn len(&self) -> usize {
+        self.columns.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.columns.is_empty()
+    }
+
+    /// The value of column `i`. Records written before columns were added to their table
+    /// end early, their missing values are NULL.
+    pub fn get(&self, i: usize) -> Result<ValueRef<'a>> {
+        let Some(&(serial_type, offset)) = self.columns.get(i) else {
+            return Ok(ValueRef::Null);
+        };
+        let bytes = &self.payload[offset..offset + value_size(serial_type)];
+        Ok(match serial_type {
+            // Serial types 10 and 11 are reserved for internal use and never stored
+            0 | 10 | 11 => ValueRef::Null,
+            1..=6 => {
+                // Big-endian two's complement, sign extended from the first byte
+                let fill = if bytes[0] & 0x80 == 0 { 0 } else { 0xff };
+                let mut num = [fill; 8];
+                num[8 - bytes.len()..].copy_from_slice(bytes);
+                ValueRef::Integer(i64::from_be_bytes(num))
+            }
+            7 => ValueRef::Real(f64::from_be_bytes(bytes.try_into()?)),
+            8 => ValueRef::Integer(0),
+            9 => ValueRef::Integer(1),
+            v if v & 1 == 0 => ValueRef::Blob(bytes),
+            _ => match self.encoding {
+                TextEncoding::Utf8 => ValueRef::Text(Cow::Borrowed(str::from_utf8(bytes)?)),
+                encoding => ValueRef::Text(Cow::Owned(encoding.decode(bytes)?)),
+            },
+        })
+    }
+
+    /// Rearrange the columns, so that column `i` of the view is column `order[i]` of the record
+    pub fn reorder(&mut self, order: &[usize]) {
+        self.columns = order
+            .iter()
+            .map(|&i| self.columns.get(i).copied().unwrap_or((0, 0)))
+            .collect();
+    }
+
+    /// Every value of the record, decoded into owned values
+    pub fn to_values(&self) -> Result<Vec<Value>> {
+        (0..self.len())
+            .map(|i| Ok(self.get(i)?.to_value()))
+            .collect()
+    }
+}
+
+/// Size in bytes of a value of serial type `serial_type`
+fn value_size(serial_type: u64) -> usize {
+    match serial_type {
+        0 | 8..=11 => 0,
+        1..=4 => serial_type as usize,
+        5 => 6,
+        6 | 7 => 8,
+        v => ((v - 12) / 2) as usize,
     }
 }
 
-impl fmt::Display for RecordField {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            Self::Null => write!(f, "NULL"),
-            Self::Int8(num) => write!(f, "{}", num),
-            Self::Int16(num) => write!(f, "{}", num),
-            Self::Int24(num) => write!(f, "{}", num),
-            Self::Int32(num) => write!(f, "{}", num),
-            Self::Int48(num) => write!(f, "{}", num),
-            Self::Int64(num) => write!(f, "{}", num),
-            Self::Float64(num) => write!(f, "{}", num),
-            Self::Zero => write!(f, "ZERO"),
-            Self::One => write!(f, "ONE"),
-            Self::Internal => write!(f, "INTERNAL"),
-            Self::Blob(text) => write!(f, "{}", text),
-            Self::Text(text) => write!(f, "{}", text),
+/// Serial type and content bytes of a value inside a record
+fn serialize_value(value: &Value, encoding: TextEncoding) -> (u64, Vec<u8>) {
+    match value {
+        Value::Null => (0, Vec::new()),
+        Value::Real(num) => (7, num.to_be_bytes().to_vec()),
+        Value::Text(text) => {
+            let bytes = encoding.encode(text);
+            (bytes.len() as u64 * 2 + 13, bytes)
         }
+        Value::Blob(blob) => (blob.len() as u64 * 2 + 12, blob.clone()),
+        Value::Integer(num) => {
+            // Use the smallest integer serial type that holds the value
+            let (serial_type, size) = match num {
+                -0x80..=0x7f => (1, 1),
+                -0x8000..=0x7fff => (2, 2),
+                -0x80_0000..=0x7f_ffff => (3, 3),
+                -0x8000_0000..=0x7fff_ffff => (4, 4),
+                -0x8000_0000_0000..=0x7fff_ffff_ffff => (5, 6),
+                _ => (6, 8),
+            };
+            (serial_type, num.to_be_bytes()[8 - size..].to_vec())
+        }
+    }
+}
+
+/// Decode every value of a record
+pub fn parse_records(payload: &[u8], encoding: TextEncoding) -> Result<Vec<Value>> {
+    RecordView::parse(payload, encoding)?.to_values()
+}
+
+/// Serialize values into a record: a header of serial types followed by their contents
+pub fn encode_record(fields: &[Value], encoding: TextEncoding) -> Vec<u8> {
+    let mut serial_types = Vec::new();
+    let mut content = Vec::new();
+    for field in fields {
+        let (serial_type, bytes) = serialize_value(field, encoding);
+        serial_types.extend(write_varint(serial_type));
+        content.extend(bytes);
+    }
+
+    // The header size varint counts itself
+    let mut header_size = serial_types.len() + 1;
+    while write_varint(header_size as u64).len() + serial_types.len() != header_size {
+        header_size += 1;
     }
+
+    let mut record = write_varint(header_size as u64);
+    record.extend(serial_types);
+    record.extend(content);
+    record
 }
 
-pub fn parse_records(payload: &[u8]) -> Result<Vec<RecordField>> {
-    let (header_size, _rest) = read_varint(payload)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::BlobFormat;
+
+    #[test]
+    fn test_encode_record_round_trip() -> Result<()> {
+        let fields = vec![
+            Value::Null,
+            Value::Integer(7),
+            Value::Integer(-300),
+            Value::Integer(1 << 40),
+            Value::Real(9.5),
+            Value::Text("Granny Smith".to_owned()),
+        ];
+        let record = encode_record(&fields, TextEncoding::Utf8);
+        // Header: size, NULL, int8, int16, int48, float, 12-byte text
+        assert_eq!(&record[..7], &[7, 0, 1, 2, 5, 7, 37]);
+        assert_eq!(parse_records(&record, TextEncoding::Utf8)?, fields);
+        Ok(())
+    }
+
+    #[test]
+    fn test_integer_serial_types() -> Result<()> {
+        // 24-bit -2, 48-bit -1, and the constants 0 and 1 of serial types 8 and 9
+        let record = [
+            5, 3, 5, 8, 9, 0xff, 0xff, 0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        ];
+        let parsed = parse_records(&record, TextEncoding::Utf8)?;
+        assert_eq!(
+            parsed,
+            [
+                Value::Integer(-2),
+                Value::Integer(-1),
+                Value::Integer(0),
+                Value::Integer(1)
+            ]
+        );
+        Ok(())
+    }
 
-    let header = &payload[..header_size as usize];
-    let (header_size, mut header) = read_varint(header)?; // consume and skip the first varint again TODO: understand mut header, &mut header
+    #[test]
+    fn test_record_view_decodes_columns_on_demand() -> Result<()> {
+        let fields = [
+            Value::Text("Fuji".to_owned()),
+            Value::Integer(300),
+            Value::Text("Red".to_owned()),
+        ];
+        let record = encode_record(&fields, TextEncoding::Utf8);
+        let mut view = RecordView::parse(&record, TextEncoding::Utf8)?;
+        assert_eq!(view.len(), 3);
+        assert!(matches!(view.get(2)?, ValueRef::Text(Cow::Borrowed("Red"))));
+        assert_eq!(view.get(1)?, ValueRef::Integer(300));
+        assert_eq!(view.get(7)?, ValueRef::Null);
 
-    let mut content = &payload[header_size as usize..];
+        view.reorder(&[2, 0]);
+        assert_eq!(view.to_values()?, [fields[2].clone(), fields[0].clone()]);
+
+        let record = encode_record(&fields, TextEncoding::Utf16le);
+        let view = RecordView::parse(&record, TextEncoding::Utf16le)?;
+        assert_eq!(view.get(0)?.to_value(), fields[0]);
+        assert!(RecordView::parse(&record[..record.len() - 1], TextEncoding::Utf16le).is_err());
+        Ok(())
+    }
 
-    let mut parsed_records = Vec::new();
-    while header.len() > 0 {
-        let (variant_indicator, header_left) = read_varint(header)?;
-        header = header_left;
-        let (serial_type, content_left) =
-            RecordField::parse_from_bytes(variant_indicator, content)?;
-        content = content_left;
-        parsed_records.push(serial_type);
+    #[test]
+    fn test_blobs_keep_their_bytes() -> Result<()> {
+        let blob = vec![0x89, b'P', b'N', b'G', 0x00, 0xff];
+        let record = encode_record(&[Value::Blob(blob.clone())], TextEncoding::Utf8);
+        let parsed = parse_records(&record, TextEncoding::Utf8)?;
+        assert_eq!(parsed[0], Value::Blob(blob.clone()));
+        assert_eq!(parsed[0].to_output(BlobFormat::Raw), blob);
+        assert_eq!(parsed[0].to_output(BlobFormat::Hex), b"89504E4700FF");
+        assert_eq!(parsed[0].to_output(BlobFormat::Literal), b"X'89504E4700FF'");
+        Ok(())
     }
-    Ok(parsed_records)
 }
@@ -1,24 +1,809 @@
+use crate::value::{Affinity, Value};
+use nom::branch::alt;
 use nom::bytes::complete::take_until;
-use nom::bytes::complete::{is_not, tag};
-use nom::character::complete::multispace0;
-use nom::combinator::{map, opt};
-use nom::multi::separated_list0;
-use nom::sequence::{delimited, preceded, tuple};
+use nom::bytes::complete::{is_not, tag, tag_no_case, take_while, take_while1};
+use nom::character::complete::{char, digit0, digit1, multispace0, multispace1, one_of, satisfy};
+use nom::combinator::{all_consuming, eof, map, map_res, not, opt, peek, recognize, value, verify};
+use nom::multi::{fold_many0, many0, separated_list0, separated_list1};
+use nom::sequence::{delimited, pair, preceded, separated_pair, terminated, tuple};
 use nom::IResult;
+use std::cmp::Ordering;
 
 #[derive(Debug)]
 pub struct SelectStatement {
     pub selector: String, // TODO: is using &str better here?
     pub from: String,
-    pub condition: Option<(String, String)>, // Simplified condition with no AND
+    pub condition: Option<SelectCondition>, // Simplified condition with no AND
+}
+
+/// The WHERE clause of a SELECT statement
+#[derive(Debug, Clone, PartialEq)]
+pub enum SelectCondition {
+    /// `column <operator> value`
+    Comparison(String, ComparisonOperator, Value),
+    /// `column BETWEEN low AND high`
+    Between(String, Value, Value),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComparisonOperator {
+    Equals,
+    NotEquals,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl ComparisonOperator {
+    /// Whether `left <operator> right` holds for operands ordered `left` to `right`
+    pub fn holds(&self, ordering: Ordering) -> bool {
+        match self {
+            Self::Equals => ordering == Ordering::Equal,
+            Self::NotEquals => ordering != Ordering::Equal,
+            Self::Less => ordering == Ordering::Less,
+            Self::LessOrEqual => ordering != Ordering::Greater,
+            Self::Greater => ordering == Ordering::Greater,
+            Self::GreaterOrEqual => ordering != Ordering::Less,
+        }
+    }
+}
+
+/// An expression UPDATE assigns to a column
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expression {
+    Literal(Value),
+    Column(String),
+    Negate(Box<Expression>),
+    /// `left || right`
+    Concatenate(Box<Expression>, Box<Expression>),
+    Arithmetic(Box<Expression>, ArithmeticOperator, Box<Expression>),
+}
+
+impl Expression {
+    /// The names of the columns the expression reads
+    pub fn columns(&self) -> Vec<&str> {
+        match self {
+            Self::Literal(_) => Vec::new(),
+            Self::Column(name) => vec![name.as_str()],
+            Self::Negate(operand) => operand.columns(),
+            Self::Concatenate(left, right) | Self::Arithmetic(left, _, right) => {
+                let mut columns = left.columns();
+                columns.extend(right.columns());
+                columns
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArithmeticOperator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+}
+
+impl ArithmeticOperator {
+    /// `left <operator> right` the way SQLite computes it: NULL if either operand is NULL,
+    /// text and blobs taken as the number they start with, integers that overflow giving reals,
+    /// and division by zero giving NULL
+    pub fn apply(&self, left: &Value, right: &Value) -> Value {
+        let (left, right) = (left.to_numeric(), right.to_numeric());
+        if let (Value::Integer(left), Value::Integer(right)) = (&left, &right) {
+            let (left, right) = (*left, *right);
+            let result = match self {
+                Self::Divide | Self::Remainder if right == 0 => return Value::Null,
+                Self::Add => left.checked_add(right),
+                Self::Subtract => left.checked_sub(right),
+                Self::Multiply => left.checked_mul(right),
+                Self::Divide => left.checked_div(right),
+                // Only i64::MIN % -1 overflows, its remainder is 0
+                Self::Remainder => Some(left.checked_rem(right).unwrap_or(0)),
+            };
+            if let Some(result) = result {
+                return Value::Integer(result);
+            }
+        }
+        let (left, right) = match (left, right) {
+            (Value::Null, _) | (_, Value::Null) => return Value::Null,
+            (left, right) => (left.as_f64(), right.as_f64()),
+        };
+        let result = match self {
+            Self::Add => left + right,
+            Self::Subtract => left - right,
+            Self::Multiply => left * right,
+            Self::Divide if right == 0.0 => return Value::Null,
+            Self::Divide => left / right,
+            // The remainder of reals is that of their integer parts
+            Self::Remainder => match (left as i64, right as i64) {
+                (_, 0) => return Value::Null,
+                (left, right) => left.checked_rem(right).unwrap_or(0) as f64,
+            },
+        };
+        if result.is_nan() {
+            Value::Null
+        } else {
+            Value::Real(result)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct InsertStatement {
+    pub table: String,
+    pub columns: Option<Vec<String>>,
+    pub values: Vec<Vec<Value>>,
+}
+
+#[derive(Debug)]
+pub struct DeleteStatement {
+    pub table: String,
+    pub condition: Option<SelectCondition>,
+}
+
+#[derive(Debug)]
+pub struct UpdateStatement {
+    pub table: String,
+    pub assignments: Vec<(String, Expression)>,
+    pub condition: Option<SelectCondition>,
+}
+
+#[derive(Debug)]
+pub struct CreateTableStatement {
+    pub name: String,
+    pub if_not_exists: bool,
+    /// The statement as SQLite stores it in sqlite_schema, without IF NOT EXISTS
+    pub sql: String,
+}
+
+/// A table as declared by its CREATE TABLE statement
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableDef {
+    pub name: String,
+    pub columns: Vec<ColumnDef>,
+    pub constraints: Vec<TableConstraint>,
+    pub without_rowid: bool,
+    pub strict: bool,
+}
+
+/// A column definition, with the expressions of its constraints as written
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnDef {
+    pub name: String,
+    /// The type name as written, `DECIMAL(10,2)`, or empty for no type
+    pub declared_type: String,
+    pub affinity: Affinity,
+    pub not_null: bool,
+    pub default: Option<String>,
+    pub collation: Option<String>,
+    pub primary_key: Option<ColumnPrimaryKey>,
+    pub unique: bool,
+    pub checks: Vec<String>,
+    pub references: Option<ForeignKey>,
+    pub generated: Option<GeneratedColumn>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ColumnPrimaryKey {
+    pub descending: bool,
+    pub autoincrement: bool,
+}
+
+/// The parent table and columns of a REFERENCES clause, no columns meaning its primary key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForeignKey {
+    pub table: String,
+    pub columns: Vec<String>,
+}
+
+/// `GENERATED ALWAYS AS (expression) [STORED | VIRTUAL]`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GeneratedColumn {
+    pub expression: String,
+    pub stored: bool,
+}
+
+/// A table constraint, after the column definitions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TableConstraint {
+    PrimaryKey(Vec<String>),
+    Unique(Vec<String>),
+    Check(String),
+    ForeignKey(Vec<String>, ForeignKey),
+}
+
+impl TableDef {
+    pub fn column_names(&self) -> Vec<String> {
+        self.columns
+            .iter()
+            .map(|column| column.name.clone())
+            .collect()
+    }
+
+    /// Position of a column, looked up ignoring case as SQL does
+    pub fn column_position(&self, name: &str) -> Option<usize> {
+        self.columns
+            .iter()
+            .position(|column| column.name.eq_ignore_ascii_case(name))
+    }
+
+    /// Whether the rowid is declared AUTOINCREMENT, so that the rowids of deleted rows are never reused
+    pub fn autoincrement(&self) -> bool {
+        matches!(
+            self.row_id_alias().map(|i| self.columns[i].primary_key),
+            Some(Some(ColumnPrimaryKey {
+                autoincrement: true,
+                ..
+            }))
+        )
+    }
+
+    /// Where each column is in the table's records, `None` for VIRTUAL generated columns, which
+    /// are computed when read rather than stored
+    pub fn record_slots(&self) -> Vec<Option<usize>> {
+        let mut slot = 0;
+        self.columns
+            .iter()
+            .map(|column| match column.generated {
+                Some(GeneratedColumn { stored: false, .. }) => None,
+                _ => {
+                    slot += 1;
+                    Some(slot - 1)
+                }
+            })
+            .collect()
+    }
+
+    /// Positions of the PRIMARY KEY columns, declared on a column or as a table constraint
+    pub fn primary_key(&self) -> Vec<usize> {
+        if let Some(position) = self
+            .columns
+            .iter()
+            .position(|column| column.primary_key.is_some())
+        {
+            return vec![position];
+        }
+        self.constraints
+            .iter()
+            .find_map(|constraint| match constraint {
+                TableConstraint::PrimaryKey(columns) => Some(
+                    columns
+                        .iter()
+                        .filter_map(|name| self.column_position(name))
+                        .collect(),
+                ),
+                _ => None,
+            })
+            .unwrap_or_default()
+    }
+
+    /// Position of the INTEGER PRIMARY KEY column, which aliases the rowid. A column declared
+    /// `INTEGER PRIMARY KEY DESC` doesn't, and WITHOUT ROWID tables have no rowid to alias.
+    pub fn row_id_alias(&self) -> Option<usize> {
+        let primary_key = self.primary_key();
+        if self.without_rowid || primary_key.len() != 1 {
+            return None;
+        }
+        let column = &self.columns[primary_key[0]];
+        let descending = matches!(
+            column.primary_key,
+            Some(ColumnPrimaryKey {
+                descending: true,
+                ..
+            })
+        );
+        (column.declared_type.eq_ignore_ascii_case("integer") && !descending)
+            .then_some(primary_key[0])
+    }
+
+    /// Whether SQLite backs UNIQUE or PRIMARY KEY constraints of the table with automatic indexes.
+    /// The rowid and the primary key of a WITHOUT ROWID table need none.
+    pub fn has_automatic_index(&self) -> bool {
+        let has_unique = self.columns.iter().any(|column| column.unique)
+            || self
+                .constraints
+                .iter()
+                .any(|constraint| matches!(constraint, TableConstraint::Unique(_)));
+        let indexed_primary_key =
+            !self.primary_key().is_empty() && self.row_id_alias().is_none() && !self.without_rowid;
+        has_unique || indexed_primary_key
+    }
+}
+
+#[derive(Debug)]
+pub struct CreateIndexStatement {
+    pub name: String,
+    pub table: String,
+    pub columns: Vec<String>,
+    pub unique: bool,
+    pub if_not_exists: bool,
+    /// The statement as SQLite stores it in sqlite_schema, without IF NOT EXISTS
+    pub sql: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionStatement {
+    Begin,
+    Commit,
+    Rollback,
+}
+
+#[derive(Debug)]
+pub struct PragmaStatement {
+    pub name: String,
+    pub value: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct DropTableStatement {
+    pub name: String,
+    pub if_exists: bool,
 }
 
 fn parse_list(input: &str) -> IResult<&str, Vec<&str>> {
-    let single_field_parser = preceded(multispace0, is_not(",)"));
-    separated_list0(tag(","), single_field_parser)(input)
+    separated_list0(tag(","), preceded(multispace0, parse_list_item))(input)
+}
+
+/// A list item, up to the next comma or closing parenthesis that isn't nested in parentheses,
+/// so that `DECIMAL(10,2)` or `PRIMARY KEY (a, b)` stay in one piece
+fn parse_list_item(input: &str) -> IResult<&str, &str> {
+    let mut depth = 0;
+    let mut end = input.len();
+    for (i, c) in input.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth > 0 => depth -= 1,
+            ',' | ')' if depth == 0 => {
+                end = i;
+                break;
+            }
+            _ => {}
+        }
+    }
+    if end == 0 {
+        return Err(nom::Err::Error(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::IsNot,
+        )));
+    }
+    Ok((&input[end..], &input[..end]))
+}
+
+/// Whitespace and comments
+fn blank(input: &str) -> IResult<&str, ()> {
+    let line_comment = recognize(pair(tag("--"), opt(is_not("\n"))));
+    let block_comment = recognize(tuple((tag("/*"), take_until("*/"), tag("*/"))));
+    value((), many0(alt((multispace1, line_comment, block_comment))))(input)
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '$'
+}
+
+/// A keyword in any case, after optional blanks, that isn't the start of a longer word
+fn keyword<'a>(word: &'static str) -> impl FnMut(&'a str) -> IResult<&'a str, &'a str> {
+    preceded(
+        blank,
+        terminated(tag_no_case(word), not(satisfy(is_word_char))),
+    )
+}
+
+/// A name after optional blanks, which SQLite also accepts in single quotes
+fn parse_name(input: &str) -> IResult<&str, String> {
+    preceded(blank, alt((parse_identifier, parse_string_literal)))(input)
+}
+
+/// `(name, ...)`
+fn parse_name_list(input: &str) -> IResult<&str, Vec<String>> {
+    delimited(
+        preceded(blank, char('(')),
+        separated_list1(preceded(blank, char(',')), parse_name),
+        preceded(blank, char(')')),
+    )(input)
+}
+
+/// The text inside a pair of parentheses, which may nest and hide parentheses in quotes
+fn parse_parenthesized(input: &str) -> IResult<&str, &str> {
+    let (input, _) = preceded(blank, char('('))(input)?;
+    let mut depth = 0;
+    let mut quote = None;
+    for (i, c) in input.char_indices() {
+        match (quote, c) {
+            (Some(end), _) if c == end => quote = None,
+            (Some(_), _) => {}
+            (None, '\'' | '"' | '`') => quote = Some(c),
+            (None, '[') => quote = Some(']'),
+            (None, '(') => depth += 1,
+            (None, ')') if depth == 0 => return Ok((&input[i + 1..], input[..i].trim())),
+            (None, ')') => depth -= 1,
+            _ => {}
+        }
+    }
+    Err(nom::Err::Error(nom::error::Error::new(
+        input,
+        nom::error::ErrorKind::Char,
+    )))
+}
+
+/// Words that end the type name of a column and start its constraints
+const COLUMN_CONSTRAINT_KEYWORDS: [&str; 11] = [
+    "constraint",
+    "primary",
+    "not",
+    "null",
+    "unique",
+    "check",
+    "default",
+    "collate",
+    "references",
+    "generated",
+    "as",
+];
+
+/// The type name of a column as written, words like `DOUBLE PRECISION` optionally followed
+/// by one or two sizes, `DECIMAL(10,2)`. Words may be quoted, and a type that is a single
+/// quoted word, `"text"`, is that word.
+fn parse_type_name(input: &str) -> IResult<&str, String> {
+    let word = || {
+        verify(take_while1(is_word_char), |word: &str| {
+            !COLUMN_CONSTRAINT_KEYWORDS
+                .iter()
+                .any(|keyword| keyword.eq_ignore_ascii_case(word))
+        })
+    };
+    let quoted = || {
+        preceded(
+            peek(one_of("'\"`[")),
+            alt((parse_string_literal, parse_identifier)),
+        )
+    };
+    let token = || alt((word(), recognize(quoted())));
+    let (input, type_name) = preceded(
+        blank,
+        recognize(tuple((
+            token(),
+            many0(preceded(blank, token())),
+            opt(parse_parenthesized),
+        ))),
+    )(input)?;
+    let type_name = match all_consuming(quoted())(type_name) {
+        Ok((_, word)) => word,
+        Err(_) => type_name.to_owned(),
+    };
+    Ok((input, type_name))
+}
+
+/// `ON CONFLICT <resolution>`, which doesn't change how tables are read
+fn parse_conflict_clause(input: &str) -> IResult<&str, ()> {
+    let resolution = alt((
+        keyword("rollback"),
+        keyword("abort"),
+        keyword("fail"),
+        keyword("ignore"),
+        keyword("replace"),
+    ));
+    value(
+        (),
+        opt(tuple((keyword("on"), keyword("conflict"), resolution))),
+    )(input)
+}
+
+/// `table [(column, ...)]` with the actions and deferral of a REFERENCES clause, which are skipped
+fn parse_foreign_key_clause(input: &str) -> IResult<&str, ForeignKey> {
+    let action = alt((
+        recognize(pair(
+            keyword("set"),
+            alt((keyword("null"), keyword("default"))),
+        )),
+        keyword("cascade"),
+        keyword("restrict"),
+        recognize(pair(keyword("no"), keyword("action"))),
+    ));
+    let on = value(
+        (),
+        tuple((
+            keyword("on"),
+            alt((keyword("delete"), keyword("update"))),
+            action,
+        )),
+    );
+    let match_clause = value((), pair(keyword("match"), parse_name));
+    let deferrable = tuple((
+        opt(keyword("not")),
+        keyword("deferrable"),
+        opt(pair(
+            keyword("initially"),
+            alt((keyword("deferred"), keyword("immediate"))),
+        )),
+    ));
+    map(
+        terminated(
+            pair(parse_name, opt(parse_name_list)),
+            pair(many0(alt((on, match_clause))), opt(deferrable)),
+        ),
+        |(table, columns)| ForeignKey {
+            table,
+            columns: columns.unwrap_or_default(),
+        },
+    )(input)
+}
+
+/// `DEFAULT` value as written: a literal, a parenthesized expression or a keyword like CURRENT_TIME
+fn parse_default_value(input: &str) -> IResult<&str, String> {
+    map(
+        preceded(
+            blank,
+            alt((
+                recognize(parse_literal),
+                recognize(parse_parenthesized),
+                take_while1(is_word_char),
+            )),
+        ),
+        str::to_owned,
+    )(input)
+}
+
+#[derive(Clone)]
+enum ColumnConstraint {
+    PrimaryKey(ColumnPrimaryKey),
+    NotNull,
+    Null,
+    Unique,
+    Check(String),
+    Default(String),
+    Collate(String),
+    References(ForeignKey),
+    Generated(GeneratedColumn),
+}
+
+fn parse_column_constraint(input: &str) -> IResult<&str, ColumnConstraint> {
+    let primary_key = map(
+        tuple((
+            pair(keyword("primary"), keyword("key")),
+            opt(alt((
+                value(false, keyword("asc")),
+                value(true, keyword("desc")),
+            ))),
+            parse_conflict_clause,
+            opt(keyword("autoincrement")),
+        )),
+        |(_, descending, _, autoincrement)| {
+            ColumnConstraint::PrimaryKey(ColumnPrimaryKey {
+                descending: descending.unwrap_or(false),
+                autoincrement: autoincrement.is_some(),
+            })
+        },
+    );
+    let generated = map(
+        tuple((
+            opt(pair(keyword("generated"), keyword("always"))),
+            keyword("as"),
+            parse_parenthesized,
+            opt(alt((
+                value(true, keyword("stored")),
+                value(false, keyword("virtual")),
+            ))),
+        )),
+        |(_, _, expression, stored)| {
+            ColumnConstraint::Generated(GeneratedColumn {
+                expression: expression.to_owned(),
+                stored: stored.unwrap_or(false),
+            })
+        },
+    );
+    preceded(
+        opt(pair(keyword("constraint"), parse_name)),
+        alt((
+            primary_key,
+            value(
+                ColumnConstraint::NotNull,
+                tuple((keyword("not"), keyword("null"), parse_conflict_clause)),
+            ),
+            value(ColumnConstraint::Null, keyword("null")),
+            value(
+                ColumnConstraint::Unique,
+                pair(keyword("unique"), parse_conflict_clause),
+            ),
+            map(preceded(keyword("check"), parse_parenthesized), |check| {
+                ColumnConstraint::Check(check.to_owned())
+            }),
+            map(
+                preceded(keyword("default"), parse_default_value),
+                ColumnConstraint::Default,
+            ),
+            map(
+                preceded(keyword("collate"), parse_name),
+                ColumnConstraint::Collate,
+            ),
+            map(
+                preceded(keyword("references"), parse_foreign_key_clause),
+                ColumnConstraint::References,
+            ),
+            generated,
+        )),
+    )(input)
+}
+
+/// `name [type] [constraint ...]`
+fn parse_column_def(input: &str) -> IResult<&str, ColumnDef> {
+    let (input, (name, declared_type, constraints)) = tuple((
+        parse_name,
+        opt(parse_type_name),
+        many0(parse_column_constraint),
+    ))(input)?;
+    let declared_type = declared_type.unwrap_or_default();
+    let mut column = ColumnDef {
+        name,
+        affinity: Affinity::from_declared_type(&declared_type),
+        declared_type,
+        not_null: false,
+        default: None,
+        collation: None,
+        primary_key: None,
+        unique: false,
+        checks: Vec::new(),
+        references: None,
+        generated: None,
+    };
+    for constraint in constraints {
+        match constraint {
+            ColumnConstraint::PrimaryKey(primary_key) => column.primary_key = Some(primary_key),
+            ColumnConstraint::NotNull => column.not_null = true,
+            ColumnConstraint::Null => column.not_null = false,
+            ColumnConstraint::Unique => column.unique = true,
+            ColumnConstraint::Check(check) => column.checks.push(check),
+            ColumnConstraint::Default(default) => column.default = Some(default),
+            ColumnConstraint::Collate(collation) => column.collation = Some(collation),
+            ColumnConstraint::References(references) => column.references = Some(references),
+            ColumnConstraint::Generated(generated) => column.generated = Some(generated),
+        }
+    }
+    Ok((input, column))
+}
+
+/// `PRIMARY KEY (...)`, `UNIQUE (...)`, `CHECK (...)` or `FOREIGN KEY (...) REFERENCES ...`
+fn parse_table_constraint(input: &str) -> IResult<&str, TableConstraint> {
+    // Indexed columns may have a collation and a sort order, which don't matter for reading
+    let indexed_columns = || {
+        delimited(
+            preceded(blank, char('(')),
+            separated_list1(
+                preceded(blank, char(',')),
+                terminated(
+                    parse_name,
+                    pair(
+                        opt(pair(keyword("collate"), parse_name)),
+                        opt(alt((keyword("asc"), keyword("desc")))),
+                    ),
+                ),
+            ),
+            preceded(blank, char(')')),
+        )
+    };
+    preceded(
+        opt(pair(keyword("constraint"), parse_name)),
+        alt((
+            map(
+                delimited(
+                    pair(keyword("primary"), keyword("key")),
+                    indexed_columns(),
+                    parse_conflict_clause,
+                ),
+                TableConstraint::PrimaryKey,
+            ),
+            map(
+                delimited(keyword("unique"), indexed_columns(), parse_conflict_clause),
+                TableConstraint::Unique,
+            ),
+            map(preceded(keyword("check"), parse_parenthesized), |check| {
+                TableConstraint::Check(check.to_owned())
+            }),
+            map(
+                preceded(
+                    pair(keyword("foreign"), keyword("key")),
+                    pair(
+                        parse_name_list,
+                        preceded(keyword("references"), parse_foreign_key_clause),
+                    ),
+                ),
+                |(columns, references)| TableConstraint::ForeignKey(columns, references),
+            ),
+        )),
+    )(input)
+}
+
+/// The parenthesized column definitions and table constraints of CREATE TABLE. Table constraints
+/// come after the columns, and SQLite doesn't require commas between them.
+fn parse_table_elements(input: &str) -> IResult<&str, (Vec<ColumnDef>, Vec<TableConstraint>)> {
+    let (mut input, _) = preceded(blank, char('('))(input)?;
+    let mut columns = Vec::new();
+    let mut constraints = Vec::new();
+    loop {
+        if let Ok((rest, constraint)) = parse_table_constraint(input) {
+            constraints.push(constraint);
+            input = rest;
+        } else if constraints.is_empty() {
+            let (rest, column) = parse_column_def(input)?;
+            columns.push(column);
+            input = rest;
+        } else {
+            let (rest, constraint) = parse_table_constraint(input)?;
+            constraints.push(constraint);
+            input = rest;
+        }
+
+        if let Ok((rest, _)) = preceded(blank, char::<&str, nom::error::Error<&str>>(','))(input) {
+            input = rest;
+        } else if let Ok((rest, _)) =
+            preceded(blank, char::<&str, nom::error::Error<&str>>(')'))(input)
+        {
+            return Ok((rest, (columns, constraints)));
+        } else if constraints.is_empty() {
+            return Err(nom::Err::Error(nom::error::Error::new(
+                input,
+                nom::error::ErrorKind::Char,
+            )));
+        }
+    }
+}
+
+/// A CREATE TABLE statement, as typed or as stored in sqlite_schema:
+/// `CREATE [TEMP] TABLE [IF NOT EXISTS] [schema.]name (column, ..., constraint, ...) [options]`
+pub fn parse_table_def(input: &str) -> IResult<&str, TableDef> {
+    let (input, _) = tuple((
+        keyword("create"),
+        opt(alt((keyword("temporary"), keyword("temp")))),
+        keyword("table"),
+        opt(tuple((keyword("if"), keyword("not"), keyword("exists")))),
+    ))(input)?;
+    let (input, (schema_or_name, name)) = pair(
+        parse_name,
+        opt(preceded(preceded(blank, char('.')), parse_name)),
+    )(input)?;
+    let (input, (columns, constraints)) = parse_table_elements(input)?;
+    let table_option = alt((
+        value("without rowid", pair(keyword("without"), keyword("rowid"))),
+        value("strict", keyword("strict")),
+    ));
+    let (input, options) = terminated(
+        separated_list0(preceded(blank, char(',')), table_option),
+        tuple((blank, opt(char(';')), blank, eof)),
+    )(input)?;
+
+    let table = TableDef {
+        name: name.unwrap_or(schema_or_name),
+        columns,
+        constraints,
+        without_rowid: options.contains(&"without rowid"),
+        strict: options.contains(&"strict"),
+    };
+    // Constraints may only name columns of the table
+    let constraint_columns = table
+        .constraints
+        .iter()
+        .flat_map(|constraint| match constraint {
+            TableConstraint::PrimaryKey(columns)
+            | TableConstraint::Unique(columns)
+            | TableConstraint::ForeignKey(columns, _) => columns.as_slice(),
+            TableConstraint::Check(_) => &[],
+        })
+        .collect::<Vec<&String>>();
+    if constraint_columns
+        .iter()
+        .any(|name| table.column_position(name).is_none())
+    {
+        return Err(nom::Err::Failure(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::Verify,
+        )));
+    }
+    Ok((input, table))
 }
 
-pub fn parse_create_table(input: &str) -> IResult<&str, Vec<&str>> {
+/// The indexed columns of a CREATE INDEX statement, each possibly followed by COLLATE or ASC/DESC
+pub fn parse_create_index(input: &str) -> IResult<&str, Vec<&str>> {
     preceded(take_until("("), delimited(tag("("), parse_list, tag(")")))(input)
 }
 
@@ -27,53 +812,775 @@ pub fn parse_first_word(input: &str) -> IResult<&str, &str> {
 }
 
 pub fn parse_selector(input: &str) -> IResult<&str, &str> {
-    delimited(tag("select"), take_until("from"), multispace0)(input)
+    delimited(
+        tag_no_case("select"),
+        take_until_keyword("from"),
+        multispace0,
+    )(input)
+}
+
+/// Everything up to the first occurrence of `keyword` as a whole word, ignoring case
+fn take_until_keyword<'a>(keyword: &'static str) -> impl Fn(&'a str) -> IResult<&'a str, &'a str> {
+    move |input: &'a str| {
+        let lowercase = input.to_ascii_lowercase();
+        let is_word = |c: Option<char>| matches!(c, Some(c) if c.is_alphanumeric() || c == '_');
+        let position = lowercase.match_indices(keyword).find_map(|(i, _)| {
+            let before = lowercase[..i].chars().next_back();
+            let after = lowercase[i + keyword.len()..].chars().next();
+            (!is_word(before) && !is_word(after)).then_some(i)
+        });
+        match position {
+            Some(i) => Ok((&input[i..], &input[..i])),
+            None => Err(nom::Err::Error(nom::error::Error::new(
+                input,
+                nom::error::ErrorKind::TakeUntil,
+            ))),
+        }
+    }
+}
+
+fn parse_comparison_operator(input: &str) -> IResult<&str, ComparisonOperator> {
+    alt((
+        value(ComparisonOperator::Equals, alt((tag("=="), tag("=")))),
+        value(ComparisonOperator::NotEquals, alt((tag("!="), tag("<>")))),
+        value(ComparisonOperator::LessOrEqual, tag("<=")),
+        value(ComparisonOperator::GreaterOrEqual, tag(">=")),
+        value(ComparisonOperator::Less, tag("<")),
+        value(ComparisonOperator::Greater, tag(">")),
+    ))(input)
 }
 
-pub fn parse_condition(input: &str) -> IResult<&str, Option<(&str, &str)>> {
-    let parser0 = delimited(tag("where"), take_until("="), tag("="));
-    let parser1 = preceded(multispace0, is_not("\n\r"));
-    opt(tuple((parser0, parser1)))(input)
+/// `WHERE column <operator> value` or `WHERE column BETWEEN low AND high`
+pub fn parse_condition(input: &str) -> IResult<&str, Option<SelectCondition>> {
+    let between = map(
+        tuple((
+            parse_identifier,
+            preceded(ws(tag_no_case("between")), parse_literal),
+            preceded(ws(tag_no_case("and")), parse_literal),
+        )),
+        |(column, low, high)| SelectCondition::Between(column, low, high),
+    );
+    let comparison = map(
+        tuple((
+            parse_identifier,
+            ws(parse_comparison_operator),
+            parse_literal,
+        )),
+        |(column, operator, value)| SelectCondition::Comparison(column, operator, value),
+    );
+    opt(preceded(
+        ws(tag_no_case("where")),
+        alt((between, comparison)),
+    ))(input)
 }
 
 pub fn parse_from(input: &str) -> IResult<&str, &str> {
     preceded(
-        tag("from"),
-        delimited(multispace0, is_not("\t\n\r "), multispace0),
+        tag_no_case("from"),
+        delimited(multispace0, is_not("\t\n\r ;"), multispace0),
     )(input)
 }
 
+/// `SELECT columns FROM table [WHERE condition]`, in any case
 pub fn parse_select_statement(input: &str) -> IResult<&str, SelectStatement> {
     let select_from_parser = preceded(multispace0, tuple((parse_selector, parse_from)));
-    let parser = tuple((select_from_parser, parse_condition));
-    // TODO: maybe map_res here? whats the difference?
+    let parser = terminated(
+        tuple((select_from_parser, parse_condition)),
+        pair(multispace0, eof),
+    );
     map(parser, |((selector, from), condition)| SelectStatement {
         selector: selector.trim().to_owned(),
         from: from.to_owned(),
-        condition: match condition {
-            Some((on, value)) => Some((
-                on.trim().to_owned(),
-                value.replace("\"", "").replace("\'", "").trim().to_owned(),
-            )),
-            None => None,
-        },
+        condition,
+    })(input)
+}
+
+/// Split a script into its statements at the semicolons that are not inside quotes
+pub fn split_statements(input: &str) -> Vec<&str> {
+    let mut statements = Vec::new();
+    let mut quote = None;
+    let mut start = 0;
+    for (i, c) in input.char_indices() {
+        match (quote, c) {
+            (None, '\'' | '"' | '`') => quote = Some(c),
+            (None, '[') => quote = Some(']'),
+            (None, ';') => {
+                statements.push(&input[start..i]);
+                start = i + 1;
+            }
+            (Some(end), _) if c == end => quote = None,
+            _ => {}
+        }
+    }
+    statements.push(&input[start..]);
+    statements
+        .into_iter()
+        .map(str::trim)
+        .filter(|statement| !statement.is_empty())
+        .collect()
+}
+
+/// Surround a parser with optional whitespace
+fn ws<'a, O, P>(parser: P) -> impl FnMut(&'a str) -> IResult<&'a str, O>
+where
+    P: FnMut(&'a str) -> IResult<&'a str, O>,
+{
+    delimited(multispace0, parser, multispace0)
+}
+
+/// A bare identifier, or one quoted with double quotes, backticks or square brackets
+pub fn parse_identifier(input: &str) -> IResult<&str, String> {
+    alt((
+        map(
+            take_while1(|c: char| c.is_alphanumeric() || c == '_' || c == '$'),
+            str::to_owned,
+        ),
+        map(
+            delimited(
+                char('"'),
+                many0(alt((is_not("\""), value("\"", tag("\"\""))))),
+                char('"'),
+            ),
+            |parts| parts.concat(),
+        ),
+        map(delimited(char('`'), is_not("`"), char('`')), str::to_owned),
+        map(delimited(char('['), is_not("]"), char(']')), str::to_owned),
+    ))(input)
+}
+
+/// A single-quoted string with '' standing for a quote
+fn parse_string_literal(input: &str) -> IResult<&str, String> {
+    map(
+        delimited(
+            char('\''),
+            many0(alt((is_not("'"), value("'", tag("''"))))),
+            char('\''),
+        ),
+        |parts| parts.concat(),
+    )(input)
+}
+
+fn parse_number_literal(input: &str) -> IResult<&str, Value> {
+    let exponent = tuple((one_of("eE"), opt(one_of("+-")), digit1));
+    let number = recognize(tuple((
+        opt(one_of("+-")),
+        alt((
+            recognize(pair(digit1, opt(pair(char('.'), digit0)))),
+            recognize(pair(char('.'), digit1)),
+        )),
+        opt(exponent),
+    )));
+    map(number, |number: &str| match number.parse::<i64>() {
+        Ok(integer) => Value::Integer(integer),
+        // Integers too large for 64 bits become reals, as in SQLite
+        Err(_) => Value::Real(number.parse().unwrap_or_default()),
+    })(input)
+}
+
+/// A blob written as an even number of hex digits, `X'..'`
+fn parse_blob_literal(input: &str) -> IResult<&str, Vec<u8>> {
+    let hex_digits = delimited(
+        char('\''),
+        take_while(|c: char| c.is_ascii_hexdigit()),
+        char('\''),
+    );
+    map_res(preceded(one_of("xX"), hex_digits), |digits: &str| {
+        if digits.len() & 1 == 1 {
+            return Err("odd number of hex digits");
+        }
+        Ok((0..digits.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).unwrap_or_default())
+            .collect())
     })(input)
 }
 
+pub fn parse_literal(input: &str) -> IResult<&str, Value> {
+    alt((
+        value(Value::Null, tag_no_case("null")),
+        map(parse_blob_literal, Value::Blob),
+        map(parse_string_literal, Value::Text),
+        parse_number_literal,
+    ))(input)
+}
+
+/// `INSERT INTO table [(column, ...)] VALUES (value, ...)[, (value, ...)]`
+pub fn parse_insert_statement(input: &str) -> IResult<&str, InsertStatement> {
+    let columns = delimited(
+        ws(char('(')),
+        separated_list1(ws(char(',')), parse_identifier),
+        ws(char(')')),
+    );
+    let row = delimited(
+        ws(char('(')),
+        separated_list1(ws(char(',')), parse_literal),
+        ws(char(')')),
+    );
+    let parser = tuple((
+        preceded(
+            pair(ws(tag_no_case("insert")), ws(tag_no_case("into"))),
+            parse_identifier,
+        ),
+        opt(columns),
+        preceded(
+            ws(tag_no_case("values")),
+            separated_list1(ws(char(',')), row),
+        ),
+    ));
+    map(
+        terminated(parser, pair(opt(ws(char(';'))), eof)),
+        |(table, columns, values)| InsertStatement {
+            table,
+            columns,
+            values,
+        },
+    )(input)
+}
+
+/// A literal, a column, or a negated or parenthesized expression
+fn parse_operand(input: &str) -> IResult<&str, Expression> {
+    ws(alt((
+        map(
+            terminated(parse_literal, not(satisfy(is_word_char))),
+            Expression::Literal,
+        ),
+        map(parse_identifier, Expression::Column),
+        delimited(char('('), parse_expression, char(')')),
+        map(preceded(char('-'), parse_operand), |operand| {
+            Expression::Negate(Box::new(operand))
+        }),
+        preceded(char('+'), parse_operand),
+    )))(input)
+}
+
+/// Operands joined by `||`, which binds tighter than arithmetic
+fn parse_concatenation(input: &str) -> IResult<&str, Expression> {
+    let (input, first) = parse_operand(input)?;
+    fold_many0(
+        preceded(tag("||"), parse_operand),
+        move || first.clone(),
+        |left, right| Expression::Concatenate(Box::new(left), Box::new(right)),
+    )(input)
+}
+
+/// Concatenations joined by `*`, `/` and `%`
+fn parse_product(input: &str) -> IResult<&str, Expression> {
+    let operator = alt((
+        value(ArithmeticOperator::Multiply, char('*')),
+        value(ArithmeticOperator::Divide, char('/')),
+        value(ArithmeticOperator::Remainder, char('%')),
+    ));
+    let (input, first) = parse_concatenation(input)?;
+    fold_many0(
+        pair(operator, parse_concatenation),
+        move || first.clone(),
+        |left, (operator, right)| Expression::Arithmetic(Box::new(left), operator, Box::new(right)),
+    )(input)
+}
+
+/// An expression of literals and columns with `+`, `-`, `*`, `/`, `%` and `||`,
+/// in SQLite's order of precedence
+pub fn parse_expression(input: &str) -> IResult<&str, Expression> {
+    let operator = alt((
+        value(ArithmeticOperator::Add, char('+')),
+        value(ArithmeticOperator::Subtract, char('-')),
+    ));
+    let (input, first) = parse_product(input)?;
+    fold_many0(
+        pair(operator, parse_product),
+        move || first.clone(),
+        |left, (operator, right)| Expression::Arithmetic(Box::new(left), operator, Box::new(right)),
+    )(input)
+}
+
+/// `column = expression`
+fn parse_assignment(input: &str) -> IResult<&str, (String, Expression)> {
+    separated_pair(parse_identifier, ws(char('=')), parse_expression)(input)
+}
+
+/// `DELETE FROM table [WHERE condition]`, with the conditions of SELECT
+pub fn parse_delete_statement(input: &str) -> IResult<&str, DeleteStatement> {
+    let parser = tuple((
+        preceded(
+            pair(ws(tag_no_case("delete")), ws(tag_no_case("from"))),
+            parse_identifier,
+        ),
+        parse_condition,
+    ));
+    map(
+        terminated(parser, pair(opt(ws(char(';'))), eof)),
+        |(table, condition)| DeleteStatement { table, condition },
+    )(input)
+}
+
+/// `UPDATE table SET column = expression[, ...] [WHERE condition]`, with the conditions of SELECT
+pub fn parse_update_statement(input: &str) -> IResult<&str, UpdateStatement> {
+    let parser = tuple((
+        preceded(ws(tag_no_case("update")), parse_identifier),
+        preceded(
+            ws(tag_no_case("set")),
+            separated_list1(ws(char(',')), parse_assignment),
+        ),
+        parse_condition,
+    ));
+    map(
+        terminated(parser, pair(opt(ws(char(';'))), eof)),
+        |(table, assignments, condition)| UpdateStatement {
+            table,
+            assignments,
+            condition,
+        },
+    )(input)
+}
+
+/// `CREATE TABLE [IF NOT EXISTS] table (column definitions)`
+pub fn parse_create_table_statement(input: &str) -> IResult<&str, CreateTableStatement> {
+    let if_not_exists = tuple((
+        tag_no_case("if"),
+        ws(tag_no_case("not")),
+        ws(tag_no_case("exists")),
+    ));
+    let (definition, if_not_exists) = preceded(
+        pair(ws(tag_no_case("create")), ws(tag_no_case("table"))),
+        opt(if_not_exists),
+    )(input)?;
+    let (_, name) = terminated(parse_identifier, pair(multispace0, peek(char('('))))(definition)?;
+
+    let definition = definition.trim_end().trim_end_matches(';').trim_end();
+    Ok((
+        "",
+        CreateTableStatement {
+            name,
+            if_not_exists: if_not_exists.is_some(),
+            sql: format!("CREATE TABLE {}", definition),
+        },
+    ))
+}
+
+/// `CREATE [UNIQUE] INDEX [IF NOT EXISTS] index ON table (column [ASC], ...)`
+pub fn parse_create_index_statement(input: &str) -> IResult<&str, CreateIndexStatement> {
+    let if_not_exists = tuple((
+        tag_no_case("if"),
+        ws(tag_no_case("not")),
+        ws(tag_no_case("exists")),
+    ));
+    let (definition, (unique, if_not_exists)) = preceded(
+        ws(tag_no_case("create")),
+        pair(
+            opt(ws(tag_no_case("unique"))),
+            preceded(ws(tag_no_case("index")), opt(if_not_exists)),
+        ),
+    )(input)?;
+    let indexed_column = terminated(parse_identifier, opt(ws(tag_no_case("asc"))));
+    let (_, (name, table, columns)) = terminated(
+        tuple((
+            ws(parse_identifier),
+            preceded(ws(tag_no_case("on")), parse_identifier),
+            delimited(
+                ws(char('(')),
+                separated_list1(ws(char(',')), indexed_column),
+                ws(char(')')),
+            ),
+        )),
+        pair(opt(ws(char(';'))), eof),
+    )(definition)?;
+
+    let definition = definition.trim().trim_end_matches(';').trim_end();
+    let unique = unique.is_some();
+    Ok((
+        "",
+        CreateIndexStatement {
+            name,
+            table,
+            columns,
+            unique,
+            if_not_exists: if_not_exists.is_some(),
+            sql: format!(
+                "CREATE {}INDEX {}",
+                if unique { "UNIQUE " } else { "" },
+                definition
+            ),
+        },
+    ))
+}
+
+/// `DROP TABLE [IF EXISTS] table`
+pub fn parse_drop_table_statement(input: &str) -> IResult<&str, DropTableStatement> {
+    let parser = tuple((
+        preceded(
+            pair(ws(tag_no_case("drop")), ws(tag_no_case("table"))),
+            opt(pair(tag_no_case("if"), ws(tag_no_case("exists")))),
+        ),
+        parse_identifier,
+    ));
+    map(
+        terminated(parser, pair(opt(ws(char(';'))), eof)),
+        |(if_exists, name)| DropTableStatement {
+            name,
+            if_exists: if_exists.is_some(),
+        },
+    )(input)
+}
+
+/// `BEGIN [DEFERRED | IMMEDIATE | EXCLUSIVE] [TRANSACTION]`, `COMMIT | END [TRANSACTION]`
+/// or `ROLLBACK [TRANSACTION]`
+pub fn parse_transaction_statement(input: &str) -> IResult<&str, TransactionStatement> {
+    let begin = value(
+        TransactionStatement::Begin,
+        pair(
+            ws(tag_no_case("begin")),
+            opt(ws(alt((
+                tag_no_case("deferred"),
+                tag_no_case("immediate"),
+                tag_no_case("exclusive"),
+            )))),
+        ),
+    );
+    let commit = value(
+        TransactionStatement::Commit,
+        ws(alt((tag_no_case("commit"), tag_no_case("end")))),
+    );
+    let rollback = value(TransactionStatement::Rollback, ws(tag_no_case("rollback")));
+    terminated(
+        alt((begin, commit, rollback)),
+        tuple((opt(ws(tag_no_case("transaction"))), opt(ws(char(';'))), eof)),
+    )(input)
+}
+
+/// `PRAGMA name [= value]` or `PRAGMA name(value)`
+pub fn parse_pragma_statement(input: &str) -> IResult<&str, PragmaStatement> {
+    let pragma_value = || alt((parse_string_literal, parse_identifier));
+    let parser = pair(
+        preceded(ws(tag_no_case("pragma")), parse_identifier),
+        opt(alt((
+            preceded(ws(char('=')), pragma_value()),
+            delimited(ws(char('(')), pragma_value(), ws(char(')'))),
+        ))),
+    );
+    map(
+        terminated(parser, pair(opt(ws(char(';'))), eof)),
+        |(name, value)| PragmaStatement {
+            name: name.to_lowercase(),
+            value,
+        },
+    )(input)
+}
+
+/// `VACUUM`
+pub fn parse_vacuum_statement(input: &str) -> IResult<&str, ()> {
+    value(
+        (),
+        terminated(ws(tag_no_case("vacuum")), pair(opt(ws(char(';'))), eof)),
+    )(input)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use anyhow::Result;
     #[test]
-    fn test_parse_field_from_create_table() -> Result<()> {
-        let statement = "CREATE TABLE student\n(\n\tid integer primary key autoincrement,\n\tname text,\n\tclass text\n)";
+    fn test_parse_table_def() -> Result<()> {
+        let statement = "CREATE TABLE student\n(\n\tid integer primary key autoincrement,\n\tname text not null,\n\tclass text\n)";
+        let (_, table) = parse_table_def(statement)?;
+        assert_eq!(table.name, "student");
+        assert_eq!(table.column_names(), vec!["id", "name", "class"]);
+        assert_eq!(
+            table.columns[0].primary_key,
+            Some(ColumnPrimaryKey {
+                descending: false,
+                autoincrement: true
+            })
+        );
+        assert!(table.columns[1].not_null);
+        assert_eq!(table.columns[2].affinity, Affinity::Text);
+        assert_eq!(table.row_id_alias(), Some(0));
+        assert!(!table.has_automatic_index());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_table_def_constraints() -> Result<()> {
+        let statement = "CREATE TABLE IF NOT EXISTS main.t (\n  \"a b\" DECIMAL(10, 2) NOT NULL DEFAULT (1 + 1), -- the price\n  c text COLLATE nocase CHECK (c != ')') REFERENCES p(x) ON DELETE CASCADE,\n  d AS (c || 'x') STORED, e,\n  CONSTRAINT pk PRIMARY KEY (c, \"a b\" DESC) UNIQUE (e) CHECK (e > 0)\n) WITHOUT ROWID, STRICT;";
+        let (_, table) = parse_table_def(statement)?;
+        assert_eq!(table.name, "t");
+        assert_eq!(table.column_names(), vec!["a b", "c", "d", "e"]);
+        assert_eq!(table.columns[0].declared_type, "DECIMAL(10, 2)");
+        assert_eq!(table.columns[0].affinity, Affinity::Numeric);
+        assert_eq!(table.columns[0].default.as_deref(), Some("(1 + 1)"));
+        assert_eq!(table.columns[1].collation.as_deref(), Some("nocase"));
+        assert_eq!(table.columns[1].checks, vec!["c != ')'"]);
+        assert_eq!(
+            table.columns[1].references,
+            Some(ForeignKey {
+                table: "p".to_owned(),
+                columns: vec!["x".to_owned()]
+            })
+        );
+        assert_eq!(
+            table.columns[2].generated,
+            Some(GeneratedColumn {
+                expression: "c || 'x'".to_owned(),
+                stored: true
+            })
+        );
+        assert_eq!(table.columns[3].affinity, Affinity::Blob);
+        assert_eq!(
+            table.constraints,
+            vec![
+                TableConstraint::PrimaryKey(vec!["c".to_owned(), "a b".to_owned()]),
+                TableConstraint::Unique(vec!["e".to_owned()]),
+                TableConstraint::Check("e > 0".to_owned()),
+            ]
+        );
+        assert_eq!(table.primary_key(), vec![1, 0]);
+        assert!(table.without_rowid && table.strict);
+        assert_eq!(table.row_id_alias(), None);
+
+        // Constraints can't name columns the table doesn't have
+        assert!(parse_table_def("CREATE TABLE t(a, PRIMARY KEY(b))").is_err());
+        assert!(parse_table_def("CREATE TABLE t(a,)").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_table_def_options() -> Result<()> {
+        let table = |sql| parse_table_def(sql).map(|(_, table)| table);
+        assert!(table("CREATE TABLE t(a PRIMARY KEY, b) WITHOUT ROWID")?.without_rowid);
+        assert!(
+            table("CREATE TABLE t(a, b, PRIMARY KEY(a)) strict, without\nrowid")?.without_rowid
+        );
+        assert!(!table("CREATE TABLE t(a, b)")?.without_rowid);
+        assert!(!table("CREATE TABLE t(without_rowid text, rowid_copy int)")?.without_rowid);
+
+        // The rowid is aliased by an INTEGER PRIMARY KEY declared either way, but not a DESC one
+        assert_eq!(
+            table("CREATE TABLE t(a, id INTEGER, PRIMARY KEY(id))")?.row_id_alias(),
+            Some(1)
+        );
+        assert_eq!(
+            table("CREATE TABLE t(id INTEGER PRIMARY KEY DESC)")?.row_id_alias(),
+            None
+        );
+        assert_eq!(
+            table("CREATE TABLE t(id INT PRIMARY KEY)")?.row_id_alias(),
+            None
+        );
+        assert!(table("CREATE TABLE t(id INT PRIMARY KEY)")?.has_automatic_index());
+        assert!(table("CREATE TABLE t(id INTEGER PRIMARY KEY, b UNIQUE)")?.has_automatic_index());
+
+        // Type names may be quoted
+        let quoted = table("CREATE TABLE t5 (a \"integer\" primary key, b \"text\", c 'int' not null, d `big` [real])")?;
+        assert_eq!(quoted.column_names(), vec!["a", "b", "c", "d"]);
+        assert_eq!(quoted.columns[1].declared_type, "text");
+        assert_eq!(quoted.columns[1].affinity, Affinity::Text);
+        assert_eq!(quoted.columns[2].affinity, Affinity::Integer);
+        assert!(quoted.columns[2].not_null);
+        assert_eq!(quoted.columns[3].declared_type, "`big` [real]");
+        assert_eq!(quoted.columns[3].affinity, Affinity::Real);
+        assert_eq!(quoted.row_id_alias(), Some(0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_create_index() -> Result<()> {
+        let statement = "CREATE INDEX idx_companies_country\n\ton companies (country)";
 
-        let parsed = parse_create_table(statement)?
+        let parsed = parse_create_index(statement)?.1;
+        assert_eq!(parsed, vec!["country"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_insert_statement() -> Result<()> {
+        let statement =
+            "INSERT INTO apples (name, \"color\") VALUES ('Pink Lady', 'it''s pink'), (NULL, -1.5e2)";
+
+        let parsed = parse_insert_statement(statement)?.1;
+        assert_eq!(parsed.table, "apples");
+        assert_eq!(
+            parsed.columns,
+            Some(vec!["name".to_owned(), "color".to_owned()])
+        );
+        assert_eq!(parsed.values.len(), 2);
+        assert!(matches!(&parsed.values[0][1], Value::Text(text) if text == "it's pink"));
+        assert!(matches!(parsed.values[1][0], Value::Null));
+        assert!(matches!(parsed.values[1][1], Value::Real(num) if num == -150.0));
+
+        let parsed = parse_insert_statement("INSERT INTO t VALUES (x'00fF10', X'')")?.1;
+        assert!(matches!(&parsed.values[0][0], Value::Blob(blob) if *blob == [0, 0xff, 0x10]));
+        assert!(matches!(&parsed.values[0][1], Value::Blob(blob) if blob.is_empty()));
+        assert!(parse_insert_statement("INSERT INTO t VALUES (x'abc')").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_delete_statement() -> Result<()> {
+        let parsed = parse_delete_statement("DELETE FROM apples WHERE color = 'Red';")?.1;
+        assert_eq!(parsed.table, "apples");
+        assert_eq!(
+            parsed.condition,
+            Some(SelectCondition::Comparison(
+                "color".to_owned(),
+                ComparisonOperator::Equals,
+                Value::Text("Red".to_owned())
+            ))
+        );
+        let parsed = parse_delete_statement("DELETE FROM t WHERE v BETWEEN -1 AND 100")?.1;
+        assert_eq!(
+            parsed.condition,
+            Some(SelectCondition::Between(
+                "v".to_owned(),
+                Value::Integer(-1),
+                Value::Integer(100)
+            ))
+        );
+        assert!(parse_delete_statement("delete from apples")?
             .1
-            .iter()
-            .map(|s| parse_first_word(s).unwrap().1)
-            .collect::<Vec<&str>>();
-        println!("{:?}", parsed);
+            .condition
+            .is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_update_statement() -> Result<()> {
+        let parsed =
+            parse_update_statement("UPDATE apples SET color = 'Green', name = NULL WHERE id = 3")?
+                .1;
+        assert_eq!(parsed.table, "apples");
+        assert_eq!(parsed.assignments.len(), 2);
+        assert_eq!(
+            parsed.assignments[1],
+            ("name".to_owned(), Expression::Literal(Value::Null))
+        );
+        assert_eq!(
+            parsed.condition,
+            Some(SelectCondition::Comparison(
+                "id".to_owned(),
+                ComparisonOperator::Equals,
+                Value::Integer(3)
+            ))
+        );
+
+        let parsed =
+            parse_update_statement("UPDATE t SET v = -v + 2 * (w - 1) || 'x' WHERE v >= 1")?.1;
+        let column = |name: &str| Box::new(Expression::Column(name.to_owned()));
+        let literal = |value| Box::new(Expression::Literal(value));
+        // || binds tighter than *, which binds tighter than +
+        let product = Expression::Arithmetic(
+            literal(Value::Integer(2)),
+            ArithmeticOperator::Multiply,
+            Box::new(Expression::Concatenate(
+                Box::new(Expression::Arithmetic(
+                    column("w"),
+                    ArithmeticOperator::Subtract,
+                    literal(Value::Integer(1)),
+                )),
+                literal(Value::Text("x".to_owned())),
+            )),
+        );
+        assert_eq!(
+            parsed.assignments,
+            [(
+                "v".to_owned(),
+                Expression::Arithmetic(
+                    Box::new(Expression::Negate(column("v"))),
+                    ArithmeticOperator::Add,
+                    Box::new(product)
+                )
+            )]
+        );
+        assert!(parse_update_statement("UPDATE t SET v = nullable WHERE v = 1").is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let apply = |left, operator: ArithmeticOperator, right| operator.apply(&left, &right);
+        use ArithmeticOperator::*;
+        use Value::*;
+        assert_eq!(apply(Integer(7), Divide, Integer(2)), Integer(3));
+        assert_eq!(apply(Integer(7), Divide, Integer(0)), Null);
+        assert_eq!(apply(Real(5.5), Remainder, Integer(2)), Real(1.0));
+        assert_eq!(apply(Text("3abc".to_owned()), Add, Integer(1)), Integer(4));
+        assert_eq!(
+            apply(Text("abc".to_owned()), Multiply, Integer(5)),
+            Integer(0)
+        );
+        assert_eq!(apply(Null, Add, Integer(1)), Null);
+        assert_eq!(
+            apply(Integer(i64::MAX), Add, Integer(1)),
+            Real(i64::MAX as f64 + 1.0)
+        );
+        assert_eq!(apply(Integer(i64::MIN), Remainder, Integer(-1)), Integer(0));
+    }
+
+    #[test]
+    fn test_parse_create_and_drop_table_statements() -> Result<()> {
+        let parsed = parse_create_table_statement(
+            "create table if not exists \"fruit basket\" (id integer primary key, name text);",
+        )?
+        .1;
+        assert_eq!(parsed.name, "fruit basket");
+        assert!(parsed.if_not_exists);
+        assert_eq!(
+            parsed.sql,
+            "CREATE TABLE \"fruit basket\" (id integer primary key, name text)"
+        );
+        assert!(parse_create_table_statement("CREATE TABLE t").is_err());
+
+        let parsed = parse_drop_table_statement("DROP TABLE IF EXISTS apples")?.1;
+        assert_eq!(parsed.name, "apples");
+        assert!(parsed.if_exists);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_transaction_statements() -> Result<()> {
+        assert_eq!(
+            split_statements("BEGIN; INSERT INTO t VALUES ('a;b'); COMMIT;"),
+            ["BEGIN", "INSERT INTO t VALUES ('a;b')", "COMMIT"]
+        );
+        assert_eq!(
+            parse_transaction_statement("begin immediate transaction")?.1,
+            TransactionStatement::Begin
+        );
+        assert_eq!(
+            parse_transaction_statement("END")?.1,
+            TransactionStatement::Commit
+        );
+        assert_eq!(
+            parse_transaction_statement("ROLLBACK;")?.1,
+            TransactionStatement::Rollback
+        );
+        assert!(parse_transaction_statement("BEGIN NOW").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_pragma_statement() -> Result<()> {
+        let parsed = parse_pragma_statement("PRAGMA WAL_CHECKPOINT;")?.1;
+        assert_eq!(parsed.name, "wal_checkpoint");
+        assert_eq!(parsed.value, None);
+        let parsed = parse_pragma_statement("pragma journal_mode = wal")?.1;
+        assert_eq!(parsed.value.as_deref(), Some("wal"));
+        let parsed = parse_pragma_statement("PRAGMA wal_checkpoint(TRUNCATE)")?.1;
+        assert_eq!(parsed.value.as_deref(), Some("TRUNCATE"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_create_index_statement() -> Result<()> {
+        let parsed =
+            parse_create_index_statement("CREATE UNIQUE INDEX idx ON apples (name, color ASC);")?.1;
+        assert_eq!(parsed.name, "idx");
+        assert_eq!(parsed.table, "apples");
+        assert_eq!(parsed.columns, ["name", "color"]);
+        assert!(parsed.unique && !parsed.if_not_exists);
+        assert_eq!(
+            parsed.sql,
+            "CREATE UNIQUE INDEX idx ON apples (name, color ASC)"
+        );
+        assert!(parse_create_index_statement("CREATE INDEX idx ON apples (name DESC)").is_err());
         Ok(())
     }
 
@@ -83,6 +1590,42 @@ mod tests {
 
         let parsed = parse_select_statement(statement)?;
         println!("{:?}", parsed);
+
+        let statement = "select name from apples where rowid between 1000 and '2000'";
+        let (_, parsed) = parse_select_statement(statement)?;
+        assert_eq!(
+            parsed.condition,
+            Some(SelectCondition::Between(
+                "rowid".to_owned(),
+                Value::Integer(1000),
+                Value::Text("2000".to_owned())
+            ))
+        );
+        let (_, parsed) = parse_select_statement("SELECT Name FROM Apples WHERE price >= 9.5")?;
+        assert_eq!(
+            (parsed.selector.as_str(), parsed.from.as_str()),
+            ("Name", "Apples")
+        );
+        assert_eq!(
+            parsed.condition,
+            Some(SelectCondition::Comparison(
+                "price".to_owned(),
+                ComparisonOperator::GreaterOrEqual,
+                Value::Real(9.5)
+            ))
+        );
+        let (_, parsed) = parse_select_statement("select fromage from t where x <> 'From'")?;
+        assert_eq!(parsed.selector, "fromage");
+        assert_eq!(
+            parsed.condition,
+            Some(SelectCondition::Comparison(
+                "x".to_owned(),
+                ComparisonOperator::NotEquals,
+                Value::Text("From".to_owned())
+            ))
+        );
+        // Conditions this parser doesn't understand are errors, not ignored
+        assert!(parse_select_statement("select name from apples where id = 1 and x = 2").is_err());
         Ok(())
     }
 }
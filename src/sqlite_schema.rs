@@ -1,6 +1,8 @@
+use crate::header::TextEncoding;
+use crate::record::encode_record;
 use crate::util::read_varint;
+use crate::value::Value;
 use anyhow::{bail, Result};
-use std::str;
 
 #[derive(Debug)]
 pub struct SqliteSchema {
@@ -11,32 +13,46 @@ pub struct SqliteSchema {
     pub sql: String,
 }
 
+/// Split the text field of serial type `serial_type` off the front of `content`
+fn take_text<'a>(content: &mut &'a [u8], serial_type: u64) -> Result<&'a [u8]> {
+    if serial_type < 13 || serial_type & 1 == 0 {
+        bail!(
+            "Expected text type for schema field, observed {}",
+            serial_type
+        );
+    }
+    let size = ((serial_type - 13) / 2) as usize;
+    let Some(text) = content.get(..size) else {
+        bail!("Schema field runs past the end of the record");
+    };
+    *content = &content[size..];
+    Ok(text)
+}
+
 impl SqliteSchema {
-    pub fn from_bytes(payload: &[u8]) -> Result<Self> {
+    pub fn from_bytes(payload: &[u8], encoding: TextEncoding) -> Result<Self> {
         let (header_size, _rest) = read_varint(payload)?;
 
-        let header = &payload[..header_size as usize];
+        let Some(header) = payload.get(..header_size as usize) else {
+            bail!("Schema record header runs past the end of the record");
+        };
         let (header_size, header) = read_varint(header)?; // consume and skip the first varint again
 
         let mut content = &payload[header_size as usize..];
 
         let (schema_type_type, header) = read_varint(header)?;
-        let schema_type_size = (schema_type_type - 13) / 2;
-        let schema_type = str::from_utf8(&content[..schema_type_size as usize])?.to_owned();
-        content = &content[schema_type_size as usize..];
+        let schema_type = encoding.decode(take_text(&mut content, schema_type_type)?)?;
 
         let (name_type, header) = read_varint(header)?;
-        let name_size = (name_type - 13) / 2;
-        let name = str::from_utf8(&content[..name_size as usize])?.to_owned();
-        content = &content[name_size as usize..];
+        let name = encoding.decode(take_text(&mut content, name_type)?)?;
 
         let (tbl_name_type, header) = read_varint(header)?;
-        let tbl_name_size = (tbl_name_type - 13) / 2;
-        let tbl_name = str::from_utf8(&content[..tbl_name_size as usize])?.to_owned();
-        content = &content[tbl_name_size as usize..];
+        let tbl_name = encoding.decode(take_text(&mut content, tbl_name_type)?)?;
 
         let (rootpage_type, header) = read_varint(header)?;
         let rootpage_size = match rootpage_type {
+            // Views and triggers have no b-tree, their rootpage is stored as the integer constant 0
+            8 => 0,
             1 => 1,
             2 => 2,
             3 => 3,
@@ -48,6 +64,9 @@ impl SqliteSchema {
                 rootpage_type
             ),
         };
+        if content.len() < rootpage_size as usize {
+            bail!("Schema record rootpage runs past the end of the record");
+        }
         let mut rootpage_bytes: [u8; 8] = [0; 8];
         for (i, byte) in &mut content[..rootpage_size as usize].iter().rev().enumerate() {
             rootpage_bytes[rootpage_bytes.len() - i - 1] = byte.to_owned();
@@ -55,9 +74,13 @@ impl SqliteSchema {
         let rootpage = u64::from_be_bytes(rootpage_bytes);
         content = &content[rootpage_size as usize..];
 
+        // Automatic indexes created for UNIQUE and PRIMARY KEY constraints have NULL sql
         let (sql_type, _header) = read_varint(header)?;
-        let sql_size = (sql_type - 13) / 2;
-        let sql = str::from_utf8(&content[..sql_size as usize])?.to_owned();
+        let sql = if sql_type == 0 {
+            String::new()
+        } else {
+            encoding.decode(take_text(&mut content, sql_type)?)?
+        };
 
         Ok(Self {
             schema_type,
@@ -67,4 +90,22 @@ impl SqliteSchema {
             sql,
         })
     }
+
+    /// Serialize the schema row into the record stored in the sqlite_schema table
+    pub fn to_bytes(&self, encoding: TextEncoding) -> Vec<u8> {
+        let sql = match self.sql.as_str() {
+            "" => Value::Null,
+            sql => Value::Text(sql.to_owned()),
+        };
+        encode_record(
+            &[
+                Value::Text(self.schema_type.clone()),
+                Value::Text(self.name.clone()),
+                Value::Text(self.tbl_name.clone()),
+                Value::Integer(self.rootpage as i64),
+                sql,
+            ],
+            encoding,
+        )
+    }
 }
@@ -1,201 +1,1403 @@
-use crate::record::{parse_records, RecordField};
-use crate::sql_parser::{parse_create_table, parse_first_word};
+use crate::btree::PageType;
+use crate::btree::{
+    count_table_cells, parse_page, search_index, visit_table_cells, visit_table_row, TableCursor,
+};
+use crate::btree_write::{
+    build_index_cell, build_page, build_table_leaf_cell, build_tree, delete_index_entry,
+    delete_table_row, free_tree, insert_index_entry, insert_table_row, max_row_id,
+    update_table_row,
+};
+use crate::header::{TextEncoding, HEADER_SIZE};
+use crate::integrity;
+use crate::journal::journal_path;
+use crate::pager::Pager;
+use crate::record::{encode_record, parse_records, RecordView};
+use crate::sql_parser::{
+    parse_create_index, parse_first_word, parse_identifier, parse_table_def, ArithmeticOperator,
+    ComparisonOperator, CreateIndexStatement, CreateTableStatement, DeleteStatement,
+    DropTableStatement, Expression, InsertStatement, PragmaStatement, SelectCondition, TableDef,
+    UpdateStatement,
+};
 use crate::sqlite_schema::SqliteSchema;
+use crate::value::{Affinity, Value, ValueRef};
 use anyhow::{self, bail};
+use std::cmp::Ordering;
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::prelude::*;
-use std::io::SeekFrom;
-
-pub fn get_page_size(filepath: &str) -> anyhow::Result<u16> {
-    let mut file = File::open(filepath)?;
-    let mut db_header = [0; 100];
-    file.read_exact(&mut db_header)?;
-    let page_size = u16::from_be_bytes([db_header[16], db_header[17]]);
-
-    Ok(page_size)
-}
-
-pub fn read_page(filepath: &str, page_size: u16, page_num: u64) -> anyhow::Result<Vec<u8>> {
-    let mut file = File::open(filepath)?;
-    let mut page = Vec::new();
-    file.seek(SeekFrom::Start((page_num - 1) * page_size as u64))?;
-    file.take(page_size.into()).read_to_end(&mut page)?;
-    Ok(page)
-}
 
 pub fn read_varint(input: &[u8]) -> anyhow::Result<(u64, &[u8])> {
-    let mut bytes = input.iter();
     let mut varint: u64 = 0;
-    let mut msb = 1;
-
-    let mut bytes_consumed = 0;
-    while msb == 1 {
-        let byte = bytes.next().unwrap();
-        varint = varint << 7;
-        varint += (byte & 0x7F) as u64;
-        msb = byte >> 7;
-        bytes_consumed += 1;
-    }
-    Ok((varint, &input[bytes_consumed..]))
-}
-
-pub fn get_tables(filepath: &str) -> anyhow::Result<Vec<SqliteSchema>> {
-    // Assume no overflow
-    let page_size = get_page_size(filepath)?;
-    let page1 = read_page(filepath, page_size, 1)?;
-    let page1_header = &page1[100..108];
-    let num_tables = u16::from_be_bytes([page1_header[3], page1_header[4]]);
-    let _cell_content_offset = u16::from_be_bytes([page1_header[5], page1_header[6]]);
-    let cell_pointer_array: Vec<u16> = page1[108..(108 + num_tables * 2) as usize]
-        .chunks(2)
-        .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
-        .collect();
-
-    let mut tables: Vec<SqliteSchema> = Vec::new();
+    for (i, byte) in input.iter().enumerate() {
+        // The 9th byte contributes all of its 8 bits
+        if i == 8 {
+            varint = (varint << 8) | *byte as u64;
+            return Ok((varint, &input[9..]));
+        }
+        varint = (varint << 7) | (byte & 0x7F) as u64;
+        if byte >> 7 == 0 {
+            return Ok((varint, &input[i + 1..]));
+        }
+    }
+    bail!("Varint runs past the end of the input")
+}
 
-    for i in 0..num_tables {
-        let cell_content = &page1[cell_pointer_array[i as usize] as usize..];
-        let (payload_size, cell_content) = read_varint(cell_content)?;
-        let (_row_id, cell_content) = read_varint(cell_content)?;
-        let (payload, _rest) = cell_content.split_at(payload_size as usize);
+pub fn write_varint(value: u64) -> Vec<u8> {
+    if value >> 56 != 0 {
+        // Values needing more than 56 bits use 8 bytes of 7 bits and a last byte of 8 bits
+        let mut bytes = (0..8)
+            .map(|i| ((value >> (8 + 7 * (7 - i))) & 0x7F) as u8 | 0x80)
+            .collect::<Vec<u8>>();
+        bytes.push(value as u8);
+        return bytes;
+    }
 
-        let schema = SqliteSchema::from_bytes(payload)?;
-        tables.push(schema);
+    let mut bytes = Vec::new();
+    let mut rest = value;
+    loop {
+        bytes.push((rest & 0x7F) as u8 | 0x80);
+        rest >>= 7;
+        if rest == 0 {
+            break;
+        }
     }
+    bytes[0] &= 0x7F;
+    bytes.reverse();
+    bytes
+}
+
+pub fn get_tables(pager: &mut Pager) -> anyhow::Result<Vec<SqliteSchema>> {
+    let encoding = pager.header().text_encoding;
+    let mut tables: Vec<SqliteSchema> = Vec::new();
+    visit_table_cells(pager, 1, &mut |_row_id, payload| {
+        tables.push(SqliteSchema::from_bytes(payload, encoding)?);
+        Ok(())
+    })?;
     Ok(tables)
 }
 
 pub fn get_table_name_to_schema_map(
-    filepath: &str,
+    pager: &mut Pager,
 ) -> anyhow::Result<HashMap<String, SqliteSchema>> {
-    let page_size = get_page_size(filepath)?;
-    let page1 = read_page(filepath, page_size, 1)?;
-
-    let page1_header = &page1[100..108];
-    let num_tables = u16::from_be_bytes([page1_header[3], page1_header[4]]);
-    let _cell_content_offset = u16::from_be_bytes([page1_header[5], page1_header[6]]);
-    let cell_pointer_array: Vec<u16> = page1[108..(108 + num_tables * 2) as usize]
-        .chunks(2)
-        .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
-        .collect();
-
     let mut tables: HashMap<String, SqliteSchema> = HashMap::new();
-
-    for i in 0..num_tables {
-        let cell_content = &page1[cell_pointer_array[i as usize] as usize..];
-        let (payload_size, cell_content) = read_varint(cell_content)?;
-        let (_row_id, cell_content) = read_varint(cell_content)?;
-        let (payload, _rest) = cell_content.split_at(payload_size as usize);
-
-        let schema = SqliteSchema::from_bytes(payload)?;
-        tables.insert(schema.tbl_name.to_owned(), schema);
+    for schema in get_tables(pager)? {
+        // Indexes share the tbl_name of the table they belong to
+        if schema.schema_type == "table" {
+            tables.insert(schema.tbl_name.to_owned(), schema);
+        }
     }
     Ok(tables)
 }
 
-pub fn count_table_rows(table_name: &str, filepath: &str) -> anyhow::Result<u64> {
-    let tables = get_tables(filepath)?;
-    let mut table_map: HashMap<String, SqliteSchema> = HashMap::new();
-    for table in tables {
-        table_map.insert(table.tbl_name.to_owned(), table);
+/// Find an index of `table_name` whose first indexed column is `column`
+pub fn find_index_on_column(
+    table_name: &str,
+    column: &str,
+    pager: &mut Pager,
+) -> anyhow::Result<Option<SqliteSchema>> {
+    for schema in get_tables(pager)? {
+        if schema.schema_type != "index" || schema.tbl_name != table_name {
+            continue;
+        }
+        let first_column = parse_create_index(schema.sql.as_str())
+            .ok()
+            .and_then(|(_, columns)| columns.first().copied())
+            .and_then(|column| parse_first_word(column).ok())
+            .map(|(_, column)| column);
+        if first_column.map(|name| name.eq_ignore_ascii_case(column)) == Some(true) {
+            return Ok(Some(schema));
+        }
     }
-    if let Some(table_schema) = table_map.get(table_name) {
-        let page_size = get_page_size(filepath)?;
-        let page = read_page(filepath, page_size, table_schema.rootpage)?;
+    Ok(None)
+}
 
-        let page_header = &page[..8];
-        let num_page_cells = u16::from_be_bytes([page_header[3], page_header[4]]);
+/// The schema of a table, looked up ignoring case as SQL does
+pub fn lookup_table_schema(table_name: &str, pager: &mut Pager) -> anyhow::Result<SqliteSchema> {
+    match get_table_name_to_schema_map(pager)?
+        .into_values()
+        .find(|schema| schema.tbl_name.eq_ignore_ascii_case(table_name))
+    {
+        Some(table_schema) => Ok(table_schema),
+        None => bail!("Table {} not found.", table_name),
+    }
+}
 
-        Ok(num_page_cells.into())
-    } else {
-        Err(anyhow::anyhow!("Table {} not found.", table_name))
+/// The number of rows of a table that match `condition`, all of them without one
+pub fn count_table_rows(
+    table_name: &str,
+    condition: Option<SelectCondition>,
+    pager: &mut Pager,
+) -> anyhow::Result<u64> {
+    let table_schema = lookup_table_schema(table_name, pager)?;
+    if condition.is_none() {
+        return count_table_cells(pager, table_schema.rootpage);
     }
+    let table = get_table_def(&table_schema)?;
+    let (row_condition, index) = resolve_condition(&table_schema, &table, condition, pager)?;
+    let mut count = 0;
+    visit_rows(
+        &table_schema,
+        &table,
+        row_condition,
+        index,
+        pager,
+        &mut |_, _| {
+            count += 1;
+            Ok(())
+        },
+    )?;
+    Ok(count)
 }
 
 pub fn get_records_from_table(
     table_name: &str,
     fields: Vec<&str>,
-    condition: Option<(String, String)>,
-    filepath: &str,
-) -> anyhow::Result<Vec<Vec<RecordField>>> {
-    let table_map = get_table_name_to_schema_map(filepath)?;
-
-    if let Some(table_schema) = table_map.get(table_name) {
-        let page_size = get_page_size(filepath)?;
-
-        let create_statement = table_schema.sql.clone();
-        let field_names = parse_create_table(create_statement.as_str())
-            // FIXME: the map_err with a closure that never fails is here so that the IResult, which contains the create statement won't propagate
-            // It never fails so I can just unwrap
-            .map_err(|_| parse_create_table(" "))
-            .unwrap()
-            .1
+    condition: Option<SelectCondition>,
+    pager: &mut Pager,
+) -> anyhow::Result<Vec<Vec<Value>>> {
+    let table_schema = lookup_table_schema(table_name, pager)?;
+    let table = get_table_def(&table_schema)?;
+    let mut field_positions = Vec::new();
+    for field in fields {
+        field_positions.push(resolve_column(&table, field)?);
+    }
+
+    let affinity = |position| column_affinity(&table, position);
+    let (row_condition, index) = resolve_condition(&table_schema, &table, condition, pager)?;
+
+    // Only the selected columns are decoded
+    let mut rows = Vec::new();
+    visit_rows(
+        &table_schema,
+        &table,
+        row_condition,
+        index,
+        pager,
+        &mut |row_id, record| {
+            let mut row = Vec::new();
+            for position in &field_positions {
+                row.push(match position {
+                    // REAL columns may store integral values as integers, they read back as reals
+                    Some(i) => match (record.get(*i)?, affinity(Some(*i))) {
+                        (ValueRef::Integer(num), Affinity::Real) => Value::Real(num as f64),
+                        (value, _) => value.to_value(),
+                    },
+                    None => Value::Integer(row_id as i64),
+                });
+            }
+            rows.push(row);
+            Ok(())
+        },
+    )?;
+    Ok(rows)
+}
+
+/// Resolve a WHERE condition to the position of its column and its values in the column's affinity,
+/// with an index on the column to look the rows up with if the condition is an equality
+fn resolve_condition(
+    table_schema: &SqliteSchema,
+    table: &TableDef,
+    condition: Option<SelectCondition>,
+    pager: &mut Pager,
+) -> anyhow::Result<(Option<Condition>, Option<SqliteSchema>)> {
+    let affinity = |position| column_affinity(table, position);
+    match condition {
+        Some(SelectCondition::Comparison(column, operator, value)) => {
+            let position = resolve_column(table, &column)?;
+            let index = match position {
+                Some(_) if operator == ComparisonOperator::Equals => {
+                    find_index_on_column(&table_schema.tbl_name, column.as_str(), pager)?
+                }
+                _ => None,
+            };
+            let value = value.apply_affinity(affinity(position));
+            Ok((
+                Some(Condition::Comparison(position, operator, value)),
+                index,
+            ))
+        }
+        Some(SelectCondition::Between(column, low, high)) => {
+            let position = resolve_column(table, &column)?;
+            let (low, high) = (
+                low.apply_affinity(affinity(position)),
+                high.apply_affinity(affinity(position)),
+            );
+            Ok((Some(Condition::Between(position, low, high)), None))
+        }
+        None => Ok((None, None)),
+    }
+}
+
+/// Names of the rowid, unless the table has a column of that name
+const ROW_ID_NAMES: [&str; 3] = ["rowid", "oid", "_rowid_"];
+
+/// Position of a column in the records of a table, or `None` for the rowid, which is what the
+/// INTEGER PRIMARY KEY column and the `rowid`, `oid` and `_rowid_` pseudo-columns refer to.
+/// The name may be quoted.
+fn resolve_column(table: &TableDef, name: &str) -> anyhow::Result<Option<usize>> {
+    let name = match parse_identifier(name) {
+        Ok(("", unquoted)) => unquoted,
+        _ => name.to_owned(),
+    };
+    let name = name.as_str();
+    match table.column_position(name) {
+        // The record only holds NULL for the INTEGER PRIMARY KEY
+        Some(position) if Some(position) == table.row_id_alias() => Ok(None),
+        Some(position) if table.record_slots()[position].is_none() => bail!(
+            "Reading {} is not supported: VIRTUAL generated columns aren't stored",
+            name
+        ),
+        Some(position) => Ok(Some(position)),
+        None if ROW_ID_NAMES
             .iter()
-            .map(|s| parse_first_word(s).unwrap().1)
-            .collect::<Vec<&str>>();
+            .any(|row_id| row_id.eq_ignore_ascii_case(name))
+            && !table.without_rowid =>
+        {
+            Ok(None)
+        }
+        None => bail!("Field {} not found in table", name),
+    }
+}
+
+/// The affinity values compared with a column take, the rowid's being INTEGER
+fn column_affinity(table: &TableDef, position: Option<usize>) -> Affinity {
+    match position {
+        Some(i) => table.columns[i].affinity,
+        None => Affinity::Integer,
+    }
+}
 
-        let mut field_name_map = HashMap::new();
-        for (i, field_name) in field_names.iter().enumerate() {
-            field_name_map.insert(*field_name, i);
+/// A WHERE condition on the column at a position, `None` for the rowid, with its values
+/// converted to the affinity of the column
+enum Condition {
+    Comparison(Option<usize>, ComparisonOperator, Value),
+    Between(Option<usize>, Value, Value),
+}
+
+impl Condition {
+    fn position(&self) -> Option<usize> {
+        match self {
+            Self::Comparison(position, _, _) | Self::Between(position, _, _) => *position,
+        }
+    }
+
+    /// Whether a row with `value` in the column of the condition satisfies it, text comparing
+    /// in the database `encoding`. Comparisons with NULL are never true.
+    fn matches(&self, value: &ValueRef, encoding: TextEncoding) -> bool {
+        match self {
+            _ if value.is_null() => false,
+            Self::Comparison(_, operator, expected) => {
+                !expected.is_null()
+                    && operator.holds(value.compare(&expected.as_value_ref(), encoding))
+            }
+            Self::Between(_, low, high) => {
+                !low.is_null()
+                    && !high.is_null()
+                    && value.compare(&low.as_value_ref(), encoding) != Ordering::Less
+                    && value.compare(&high.as_value_ref(), encoding) != Ordering::Greater
+            }
         }
+    }
 
-        let page = read_page(filepath, page_size, table_schema.rootpage)?;
+    /// The rowids a condition on the rowid allows, `None` if it allows none. Rowids are integers,
+    /// so bounds are rounded towards the inside of the range; text sorts after every number.
+    fn row_id_range(&self) -> Option<(i64, i64)> {
+        let (low, high) = match self {
+            Self::Comparison(_, operator, value) => match operator {
+                _ if value.is_null() => return None,
+                ComparisonOperator::Equals => {
+                    (row_id_above(value, true)?, row_id_below(value, true)?)
+                }
+                ComparisonOperator::NotEquals => (i64::MIN, i64::MAX),
+                ComparisonOperator::Less => (i64::MIN, row_id_below(value, false)?),
+                ComparisonOperator::LessOrEqual => (i64::MIN, row_id_below(value, true)?),
+                ComparisonOperator::Greater => (row_id_above(value, false)?, i64::MAX),
+                ComparisonOperator::GreaterOrEqual => (row_id_above(value, true)?, i64::MAX),
+            },
+            Self::Between(_, low, high) => (row_id_above(low, true)?, row_id_below(high, true)?),
+        };
+        (low <= high).then_some((low, high))
+    }
+}
 
-        let page_header = &page[..8];
-        let num_page_cells = u16::from_be_bytes([page_header[3], page_header[4]]);
-        let cell_pointer_array: Vec<u16> = page[8..(100 + num_page_cells * 2) as usize]
-            .chunks(2)
-            .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
-            .collect();
+/// The smallest rowid above `value`, or equal to it if `inclusive`
+fn row_id_above(value: &Value, inclusive: bool) -> Option<i64> {
+    match value {
+        Value::Integer(num) if inclusive => Some(*num),
+        Value::Integer(num) => num.checked_add(1),
+        Value::Real(num) if inclusive => Some(num.ceil() as i64),
+        Value::Real(num) => (num.floor() as i64).checked_add(1),
+        _ => None,
+    }
+}
 
-        let mut field_indices = Vec::new();
-        for field in fields {
-            if let Some(field_index) = field_name_map.get(field) {
-                field_indices.push(*field_index);
-            } else {
-                bail!("Field {} not found in table", field)
+/// The largest rowid below `value`, or equal to it if `inclusive`
+fn row_id_below(value: &Value, inclusive: bool) -> Option<i64> {
+    match value {
+        Value::Null => None,
+        Value::Integer(num) if inclusive => Some(*num),
+        Value::Integer(num) => num.checked_sub(1),
+        Value::Real(num) if inclusive => Some(num.floor() as i64),
+        Value::Real(num) => (num.ceil() as i64).checked_sub(1),
+        Value::Text(_) | Value::Blob(_) => Some(i64::MAX),
+    }
+}
+
+/// The rowid and every value of the rows `visit_rows` visits
+fn find_rows(
+    table_schema: &SqliteSchema,
+    table: &TableDef,
+    condition: Option<Condition>,
+    index: Option<SqliteSchema>,
+    pager: &mut Pager,
+) -> anyhow::Result<Vec<(u64, Vec<Value>)>> {
+    let mut rows = Vec::new();
+    visit_rows(
+        table_schema,
+        table,
+        condition,
+        index,
+        pager,
+        &mut |row_id, record| {
+            // Rows written before ALTER TABLE ADD COLUMN lack the added columns, which read as NULL
+            let mut values = record.to_values()?;
+            values.resize(table.columns.len(), Value::Null);
+            rows.push((row_id, values));
+            Ok(())
+        },
+    )?;
+    Ok(rows)
+}
+
+/// Visit the rowid and record of the rows of a table whose field at the condition's position, or rowid,
+/// satisfies the condition. Rows are looked up through `index` when there is one on that column, and
+/// conditions on the rowid only visit the rows in their range.
+fn visit_rows<F>(
+    table_schema: &SqliteSchema,
+    table: &TableDef,
+    condition: Option<Condition>,
+    index: Option<SqliteSchema>,
+    pager: &mut Pager,
+    visit: &mut F,
+) -> anyhow::Result<()>
+where
+    F: FnMut(u64, &RecordView) -> anyhow::Result<()>,
+{
+    if table.without_rowid {
+        return visit_without_rowid_rows(table_schema, table, condition, index, pager, visit);
+    }
+    let encoding = pager.header().text_encoding;
+    // Records skip VIRTUAL generated columns, the view puts the stored ones back at their positions
+    let record_slots = table.record_slots();
+    let table_order = record_slots.contains(&None).then(|| {
+        record_slots
+            .iter()
+            .map(|slot| slot.unwrap_or(usize::MAX))
+            .collect::<Vec<usize>>()
+    });
+    let mut handle_row = |row_id: u64, payload: &[u8]| -> anyhow::Result<()> {
+        let mut record = RecordView::parse(payload, encoding)?;
+        if let Some(table_order) = &table_order {
+            record.reorder(table_order);
+        }
+        if let Some(condition) = &condition {
+            let matches = match condition.position() {
+                Some(i) => condition.matches(&record.get(i)?, encoding),
+                None => condition.matches(&ValueRef::Integer(row_id as i64), encoding),
+            };
+            if !matches {
+                return Ok(());
             }
         }
+        visit(row_id, &record)
+    };
 
-        let mut condition_pair: Option<(usize, String)> = None;
-        if let Some((condition_on, condition_value)) = condition {
-            if let Some(index) = field_name_map.get(condition_on.as_str()) {
-                condition_pair = Some((*index, condition_value));
-            } else {
-                bail!("Field {} not found in table", condition_on)
+    match (&condition, index) {
+        (
+            Some(Condition::Comparison(_, ComparisonOperator::Equals, condition_value)),
+            Some(index_schema),
+        ) => {
+            // Index entries are the indexed columns followed by the rowid of the table row
+            let mut row_ids = Vec::new();
+            search_index(
+                pager,
+                index_schema.rootpage,
+                &|payload| compare_first_column(payload, encoding, condition_value),
+                &mut |payload| {
+                    let entry = RecordView::parse(payload, encoding)?;
+                    let last = match entry.len() {
+                        0 => ValueRef::Null,
+                        len => entry.get(len - 1)?,
+                    };
+                    match last {
+                        ValueRef::Integer(row_id) => row_ids.push(row_id as u64),
+                        _ => bail!("Index {} entry has no rowid", index_schema.name),
+                    }
+                    Ok(())
+                },
+            )?;
+            for row_id in row_ids {
+                visit_table_row(pager, table_schema.rootpage, row_id, &mut handle_row)?;
             }
         }
+        (Some(condition), _) if condition.position().is_none() => {
+            // Seek to the first rowid in range and stop after the last one
+            if let Some((low, high)) = condition.row_id_range() {
+                let mut cursor = TableCursor::new(table_schema.rootpage);
+                let mut has_row = cursor.seek(pager, low)?;
+                while has_row {
+                    let row_id = cursor.row_id()?;
+                    if row_id > high {
+                        break;
+                    }
+                    handle_row(row_id as u64, &cursor.payload(pager)?)?;
+                    has_row = cursor.next(pager)?;
+                }
+            }
+        }
+        _ => visit_table_cells(pager, table_schema.rootpage, &mut handle_row)?,
+    }
+    Ok(())
+}
 
-        let mut records = Vec::new();
-        for i in 0..num_page_cells {
-            let cell_content = &page[cell_pointer_array[i as usize] as usize..];
-            let (payload_size, cell_content) = read_varint(cell_content)?;
-            let (_row_id, cell_content) = read_varint(cell_content)?;
-            let (payload, _rest) = cell_content.split_at(payload_size as usize);
-            let record = parse_records(payload)?;
+/// Order the first column of an index entry or WITHOUT ROWID record against a value
+fn compare_first_column(
+    payload: &[u8],
+    encoding: TextEncoding,
+    value: &Value,
+) -> anyhow::Result<Ordering> {
+    Ok(RecordView::parse(payload, encoding)?
+        .get(0)?
+        .compare(&value.as_value_ref(), encoding))
+}
+
+/// Visit the rows of a WITHOUT ROWID table matching the condition, from the index b-tree the table is
+/// stored in. Rows are looked up by key when the condition is on the first primary key column, or through
+/// `index`, whose entries end with the primary key. Such rows have no rowid, they are visited with 0.
+fn visit_without_rowid_rows<F>(
+    table_schema: &SqliteSchema,
+    table: &TableDef,
+    condition: Option<Condition>,
+    index: Option<SqliteSchema>,
+    pager: &mut Pager,
+    visit: &mut F,
+) -> anyhow::Result<()>
+where
+    F: FnMut(u64, &RecordView) -> anyhow::Result<()>,
+{
+    let encoding = pager.header().text_encoding;
+    let column_names = table.column_names();
+    let primary_key = table.primary_key();
+    if primary_key.is_empty() {
+        bail!(
+            "WITHOUT ROWID table {} has no PRIMARY KEY",
+            table_schema.name
+        );
+    }
+    // Records hold the primary key columns first, then the other stored columns in table order
+    let record_slots = table.record_slots();
+    let mut stored_columns = primary_key.clone();
+    stored_columns.extend(
+        (0..column_names.len()).filter(|&i| !primary_key.contains(&i) && record_slots[i].is_some()),
+    );
+    let mut table_order = vec![usize::MAX; column_names.len()];
+    for (stored, &position) in stored_columns.iter().enumerate() {
+        table_order[position] = stored;
+    }
 
-            let record_slice = field_indices
+    let mut handle_entry = |payload: &[u8]| -> anyhow::Result<()> {
+        let mut record = RecordView::parse(payload, encoding)?;
+        record.reorder(&table_order);
+        if let Some(condition) = &condition {
+            let Some(i) = condition.position() else {
+                bail!("WITHOUT ROWID table {} has no rowid", table_schema.name);
+            };
+            if !condition.matches(&record.get(i)?, encoding) {
+                return Ok(());
+            }
+        }
+        visit(0, &record)
+    };
+
+    match (&condition, index) {
+        (Some(Condition::Comparison(position, ComparisonOperator::Equals, condition_value)), _)
+            if *position == Some(primary_key[0]) =>
+        {
+            search_index(
+                pager,
+                table_schema.rootpage,
+                &|payload| compare_first_column(payload, encoding, condition_value),
+                &mut handle_entry,
+            )?;
+        }
+        (
+            Some(Condition::Comparison(_, ComparisonOperator::Equals, condition_value)),
+            Some(index_schema),
+        ) => {
+            // Index entries are the indexed columns followed by the primary key columns they don't include
+            let mut entry_columns = get_index_columns(&index_schema, &column_names)?;
+            for &column in &primary_key {
+                if !entry_columns.contains(&column) {
+                    entry_columns.push(column);
+                }
+            }
+            let key_positions = primary_key
                 .iter()
-                .map(|i| record[*i].clone())
-                .collect::<Vec<RecordField>>();
+                .filter_map(|column| entry_columns.iter().position(|i| i == column))
+                .collect::<Vec<usize>>();
+            let mut keys = Vec::new();
+            search_index(
+                pager,
+                index_schema.rootpage,
+                &|payload| compare_first_column(payload, encoding, condition_value),
+                &mut |payload| {
+                    let entry = RecordView::parse(payload, encoding)?;
+                    let mut key = Vec::new();
+                    for &position in &key_positions {
+                        if position >= entry.len() {
+                            bail!("Index {} entry has no primary key", index_schema.name);
+                        }
+                        key.push(entry.get(position)?.to_value());
+                    }
+                    keys.push(key);
+                    Ok(())
+                },
+            )?;
+            for key in keys {
+                search_index(
+                    pager,
+                    table_schema.rootpage,
+                    &|payload| {
+                        Ok(compare_index_key(
+                            &parse_records(payload, encoding)?,
+                            &key,
+                            encoding,
+                        ))
+                    },
+                    &mut handle_entry,
+                )?;
+            }
+        }
+        _ => {
+            let every_entry = |_: &[u8]| Ok(Ordering::Equal);
+            search_index(
+                pager,
+                table_schema.rootpage,
+                &every_entry,
+                &mut handle_entry,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// The definition of a table, parsed from the CREATE TABLE statement in its schema
+pub fn get_table_def(table_schema: &SqliteSchema) -> anyhow::Result<TableDef> {
+    match parse_table_def(&table_schema.sql) {
+        Ok((_, table)) => Ok(table),
+        Err(err) => bail!("Invalid schema of table {}: {}", table_schema.name, err),
+    }
+}
+
+/// The indexes of a table with the positions of their columns in the table
+fn get_table_indexes(
+    table_schema: &SqliteSchema,
+    column_names: &[String],
+    pager: &mut Pager,
+) -> anyhow::Result<Vec<(SqliteSchema, Vec<usize>)>> {
+    let mut indexes = Vec::new();
+    for schema in get_tables(pager)? {
+        if schema.schema_type != "index" || schema.tbl_name != table_schema.tbl_name {
+            continue;
+        }
+        if schema.sql.is_empty() {
+            bail!(
+                "Writing to {} is not supported: automatic index {} cannot be maintained",
+                table_schema.tbl_name,
+                schema.name
+            );
+        }
+        let column_indices = get_index_columns(&schema, column_names)?;
+        indexes.push((schema, column_indices));
+    }
+    Ok(indexes)
+}
+
+/// Positions in the table of the columns an index was created on
+pub(crate) fn get_index_columns(
+    index_schema: &SqliteSchema,
+    column_names: &[String],
+) -> anyhow::Result<Vec<usize>> {
+    let mut column_indices = Vec::new();
+    for column in parse_create_index(index_schema.sql.as_str())
+        .map(|(_, columns)| columns)
+        .unwrap_or_default()
+    {
+        let name = parse_first_word(column)
+            .map(|(_, name)| name)
+            .unwrap_or(column);
+        match column_names
+            .iter()
+            .position(|column_name| column_name.eq_ignore_ascii_case(name))
+        {
+            Some(i) => column_indices.push(i),
+            None => bail!("Column {} of index {} not found", name, index_schema.name),
+        }
+    }
+    Ok(column_indices)
+}
+
+/// Order an index entry against a key, column by column, text comparing in the database `encoding`
+pub(crate) fn compare_index_key(
+    entry: &[Value],
+    key: &[Value],
+    encoding: TextEncoding,
+) -> Ordering {
+    for (entry_field, key_field) in entry.iter().zip(key) {
+        match entry_field.compare(key_field, encoding) {
+            Ordering::Equal => continue,
+            ordering => return ordering,
+        }
+    }
+    Ordering::Equal
+}
+
+/// The indexed fields of a row, with the rowid standing in for the INTEGER PRIMARY KEY column
+pub(crate) fn index_key(
+    record: &[Value],
+    column_indices: &[usize],
+    row_id_alias: Option<usize>,
+    row_id: u64,
+) -> Vec<Value> {
+    column_indices
+        .iter()
+        .map(|&i| match row_id_alias {
+            Some(alias) if alias == i => Value::Integer(row_id as i64),
+            _ => record[i].clone(),
+        })
+        .collect()
+}
+
+/// Order two index entry payloads
+pub(crate) fn compare_index_entries(
+    left: &[u8],
+    right: &[u8],
+    encoding: TextEncoding,
+) -> anyhow::Result<Ordering> {
+    Ok(compare_index_key(
+        &parse_records(left, encoding)?,
+        &parse_records(right, encoding)?,
+        encoding,
+    ))
+}
+
+/// The rowid of a new row: one past the largest rowid of the table, which may be negative,
+/// or past `largest_used` when an AUTOINCREMENT table had larger rowids that were deleted since
+fn next_row_id(pager: &mut Pager, root: u64, largest_used: Option<i64>) -> anyhow::Result<u64> {
+    let largest = max_row_id(pager, root)?;
+    let largest = largest_used.map_or(largest, |used| used.max(largest));
+    match largest.checked_add(1) {
+        Some(row_id) => Ok(row_id as u64),
+        None => bail!("database or disk is full: the table already has the largest rowid"),
+    }
+}
+
+/// The largest rowid an AUTOINCREMENT table ever had, kept in its row of sqlite_sequence
+struct Sequence {
+    root: u64,
+    /// The table's row in sqlite_sequence, which its first insert adds
+    row_id: Option<u64>,
+    value: i64,
+}
+
+fn find_sequence(table_name: &str, pager: &mut Pager) -> anyhow::Result<Sequence> {
+    let encoding = pager.header().text_encoding;
+    let root = lookup_table_schema("sqlite_sequence", pager)?.rootpage;
+    let mut sequence = Sequence {
+        root,
+        row_id: None,
+        value: 0,
+    };
+    visit_table_cells(pager, root, &mut |row_id, payload| {
+        let record = parse_records(payload, encoding)?;
+        if record.first().map(Value::to_string).as_deref() == Some(table_name) {
+            sequence.row_id = Some(row_id);
+            sequence.value = record.get(1).and_then(Value::as_i64).unwrap_or(0);
+        }
+        Ok(())
+    })?;
+    Ok(sequence)
+}
 
-            if let Some((condition_index, ref condition_value)) = condition_pair {
-                if record[condition_index].to_string().to_lowercase() == *condition_value {
-                    records.push(record_slice)
+/// Add a row to a table and to each of its indexes, returning its rowid
+pub fn insert_row(
+    table_schema: &SqliteSchema,
+    mut record: Vec<Value>,
+    row_id: Option<u64>,
+    pager: &mut Pager,
+) -> anyhow::Result<u64> {
+    let encoding = pager.header().text_encoding;
+    let table = get_table_def(table_schema)?;
+    let column_names = table.column_names();
+    let row_id_alias = table.row_id_alias();
+
+    let sequence = if table.autoincrement() {
+        Some(find_sequence(&table_schema.name, pager)?)
+    } else {
+        None
+    };
+
+    // The INTEGER PRIMARY KEY value becomes the rowid and is stored as NULL
+    let alias_value = match row_id_alias {
+        Some(i) if !record[i].is_null() => match record[i].as_i64() {
+            Some(value) => Some(value),
+            None => bail!(
+                "datatype mismatch: INTEGER PRIMARY KEY {} can't be set to '{}'",
+                column_names[i],
+                record[i]
+            ),
+        },
+        _ => None,
+    };
+    let row_id = match (row_id, alias_value) {
+        (Some(row_id), _) => row_id,
+        (None, Some(value)) => value as u64,
+        (None, None) => next_row_id(
+            pager,
+            table_schema.rootpage,
+            sequence.as_ref().map(|sequence| sequence.value),
+        )?,
+    };
+    if let Some(i) = row_id_alias {
+        record[i] = Value::Null;
+    }
+
+    insert_table_row(
+        pager,
+        table_schema.rootpage,
+        row_id,
+        &encode_record(&record, encoding),
+    )?;
+
+    for (index_schema, column_indices) in get_table_indexes(table_schema, &column_names, pager)? {
+        let key = index_key(&record, &column_indices, row_id_alias, row_id);
+        add_index_entry(&index_schema, key, row_id, pager)?;
+    }
+
+    // An AUTOINCREMENT table's sequence only ever grows, starting from 0
+    if let Some(sequence) = sequence {
+        if sequence.row_id.is_none() || row_id as i64 > sequence.value {
+            let record = [
+                Value::Text(table_schema.name.clone()),
+                Value::Integer(sequence.value.max(row_id as i64)),
+            ];
+            let payload = encode_record(&record, encoding);
+            match sequence.row_id {
+                Some(sequence_row) => {
+                    update_table_row(pager, sequence.root, sequence_row, &payload)?
+                }
+                None => {
+                    let sequence_row = next_row_id(pager, sequence.root, None)?;
+                    insert_table_row(pager, sequence.root, sequence_row, &payload)?
                 }
+            }
+        }
+    }
+    Ok(row_id)
+}
+
+/// Add the entry of a row to an index, failing if a UNIQUE index already has the key
+fn add_index_entry(
+    index_schema: &SqliteSchema,
+    mut key: Vec<Value>,
+    row_id: u64,
+    pager: &mut Pager,
+) -> anyhow::Result<()> {
+    let encoding = pager.header().text_encoding;
+    // NULLs are distinct from each other, even in a UNIQUE index
+    let has_null = key.iter().any(|field| matches!(field, Value::Null));
+    if index_schema.sql.to_lowercase().split_whitespace().nth(1) == Some("unique") && !has_null {
+        let mut duplicate = false;
+        search_index(
+            pager,
+            index_schema.rootpage,
+            &|payload| {
+                Ok(compare_index_key(
+                    &parse_records(payload, encoding)?,
+                    &key,
+                    encoding,
+                ))
+            },
+            &mut |_payload| {
+                duplicate = true;
+                Ok(())
+            },
+        )?;
+        if duplicate {
+            bail!("UNIQUE constraint failed: index {}", index_schema.name);
+        }
+    }
+
+    key.push(Value::Integer(row_id as i64));
+    insert_index_entry(
+        pager,
+        index_schema.rootpage,
+        &encode_record(&key, encoding),
+        &|payload| {
+            Ok(compare_index_key(
+                &parse_records(payload, encoding)?,
+                &key,
+                encoding,
+            ))
+        },
+    )
+}
+
+/// Remove the entry of a row from an index
+fn remove_index_entry(
+    index_schema: &SqliteSchema,
+    mut key: Vec<Value>,
+    row_id: u64,
+    pager: &mut Pager,
+) -> anyhow::Result<()> {
+    let encoding = pager.header().text_encoding;
+    key.push(Value::Integer(row_id as i64));
+    let deleted = delete_index_entry(
+        pager,
+        index_schema.rootpage,
+        &encode_record(&key, encoding),
+        &|left, right| compare_index_entries(left, right, encoding),
+    )?;
+    if !deleted {
+        bail!(
+            "Index {} has no entry for rowid {}",
+            index_schema.name,
+            row_id
+        );
+    }
+    Ok(())
+}
+
+/// The schema of a table to write to, with its parsed definition
+fn find_table_schema(
+    table_name: &str,
+    pager: &mut Pager,
+) -> anyhow::Result<(SqliteSchema, TableDef)> {
+    let table_schema = lookup_table_schema(table_name, pager)?;
+    let table = get_table_def(&table_schema)?;
+    if table.without_rowid {
+        bail!(
+            "Writing to {} is not supported: WITHOUT ROWID tables can't be written",
+            table_schema.name
+        );
+    }
+    if table.record_slots().contains(&None) {
+        bail!(
+            "Writing to {} is not supported: VIRTUAL generated columns can't be computed",
+            table_schema.name
+        );
+    }
+    Ok((table_schema, table))
+}
+
+/// Run an INSERT statement, returning the number of inserted rows
+pub fn insert_rows(statement: InsertStatement, pager: &mut Pager) -> anyhow::Result<usize> {
+    let (table_schema, table) = find_table_schema(&statement.table, pager)?;
+    let column_names = table.column_names();
+
+    // Map each given column to its position in the record, missing columns are NULL
+    let positions = match &statement.columns {
+        Some(columns) => {
+            let mut positions = Vec::new();
+            for column in columns {
+                match table.column_position(column) {
+                    Some(position) => positions.push(position),
+                    None => bail!("Field {} not found in table", column),
+                }
+            }
+            positions
+        }
+        None => (0..column_names.len()).collect(),
+    };
+
+    for values in &statement.values {
+        if values.len() != positions.len() {
+            bail!("{} values for {} columns", values.len(), positions.len());
+        }
+        let mut record = vec![Value::Null; column_names.len()];
+        for (&position, value) in positions.iter().zip(values) {
+            record[position] = value
+                .clone()
+                .apply_affinity(table.columns[position].affinity);
+        }
+        insert_row(&table_schema, record, None, pager)?;
+    }
+    pager.commit()?;
+    Ok(statement.values.len())
+}
+
+/// Run a DELETE statement, removing the matching rows and their index entries.
+/// Returns the number of deleted rows.
+pub fn delete_rows(statement: DeleteStatement, pager: &mut Pager) -> anyhow::Result<usize> {
+    let (table_schema, table) = find_table_schema(&statement.table, pager)?;
+    let column_names = table.column_names();
+    let row_id_alias = table.row_id_alias();
+    let (condition, index) = resolve_condition(&table_schema, &table, statement.condition, pager)?;
+    let rows = find_rows(&table_schema, &table, condition, index, pager)?;
+
+    let indexes = get_table_indexes(&table_schema, &column_names, pager)?;
+    for (row_id, record) in &rows {
+        for (index_schema, column_indices) in &indexes {
+            let key = index_key(record, column_indices, row_id_alias, *row_id);
+            remove_index_entry(index_schema, key, *row_id, pager)?;
+        }
+        delete_table_row(pager, table_schema.rootpage, *row_id)?;
+    }
+    pager.commit()?;
+    Ok(rows.len())
+}
+
+/// Describe a new b-tree in sqlite_schema under the next free rowid
+fn add_schema_row(schema: &SqliteSchema, pager: &mut Pager) -> anyhow::Result<()> {
+    let encoding = pager.header().text_encoding;
+    let row_id = next_row_id(pager, 1, None)?;
+    insert_table_row(pager, 1, row_id, &schema.to_bytes(encoding))
+}
+
+/// Allocate the empty root page of a new table and add it to sqlite_schema
+fn add_table(name: &str, sql: &str, pager: &mut Pager) -> anyhow::Result<()> {
+    let table_schema = SqliteSchema {
+        schema_type: "table".to_owned(),
+        name: name.to_owned(),
+        tbl_name: name.to_owned(),
+        rootpage: 0,
+        sql: sql.to_owned(),
+    };
+    let table = get_table_def(&table_schema)?;
+    if table.without_rowid {
+        bail!(
+            "Creating {} is not supported: WITHOUT ROWID tables can't be written",
+            name
+        );
+    }
+    if table.has_automatic_index() {
+        bail!("Creating {} is not supported: UNIQUE and PRIMARY KEY constraints need automatic indexes", name);
+    }
+
+    let rootpage = pager.allocate_page()?;
+    let root = build_page(pager, rootpage, PageType::LeafTable, &[], None)?;
+    pager.write_page(rootpage, root)?;
+    add_schema_row(
+        &SqliteSchema {
+            rootpage,
+            ..table_schema
+        },
+        pager,
+    )
+}
+
+/// Run a CREATE TABLE statement, bumping the schema cookie so other connections reload the schema
+pub fn create_table(statement: CreateTableStatement, pager: &mut Pager) -> anyhow::Result<()> {
+    let schemas = get_tables(pager)?;
+    if let Some(existing) = schemas
+        .iter()
+        .find(|schema| schema.name.eq_ignore_ascii_case(&statement.name))
+    {
+        if statement.if_not_exists && existing.schema_type == "table" {
+            return Ok(());
+        }
+        bail!("{} {} already exists", existing.schema_type, existing.name);
+    }
+    if statement.name.to_lowercase().starts_with("sqlite_") {
+        bail!("Object name reserved for internal use: {}", statement.name);
+    }
+
+    add_table(&statement.name, &statement.sql, pager)?;
+    // AUTOINCREMENT tables keep their largest rowid in sqlite_sequence, created along with the first one
+    let autoincrement = statement.sql.to_lowercase().contains("autoincrement");
+    if autoincrement
+        && !schemas
+            .iter()
+            .any(|schema| schema.name == "sqlite_sequence")
+    {
+        add_table(
+            "sqlite_sequence",
+            "CREATE TABLE sqlite_sequence(name,seq)",
+            pager,
+        )?;
+    }
+
+    let header = pager.header_mut();
+    header.schema_cookie = header.schema_cookie.wrapping_add(1);
+    pager.commit()
+}
+
+/// Run a CREATE INDEX statement: sort the keys of all rows of the table, build the index b-tree
+/// from them bottom-up and add it to sqlite_schema
+pub fn create_index(statement: CreateIndexStatement, pager: &mut Pager) -> anyhow::Result<()> {
+    let encoding = pager.header().text_encoding;
+    if let Some(existing) = get_tables(pager)?
+        .iter()
+        .find(|schema| schema.name.eq_ignore_ascii_case(&statement.name))
+    {
+        if statement.if_not_exists && existing.schema_type == "index" {
+            return Ok(());
+        }
+        bail!("{} {} already exists", existing.schema_type, existing.name);
+    }
+    if statement.name.to_lowercase().starts_with("sqlite_") {
+        bail!("Object name reserved for internal use: {}", statement.name);
+    }
+    let (table_schema, table) = find_table_schema(&statement.table, pager)?;
+    let column_names = table.column_names();
+    let row_id_alias = table.row_id_alias();
+    let mut column_indices = Vec::new();
+    for column in &statement.columns {
+        match table.column_position(column) {
+            Some(position) => column_indices.push(position),
+            None => bail!("No such column: {}", column),
+        }
+    }
+
+    // Index entries are the key columns followed by the rowid, sorted in that order
+    let mut keys = Vec::new();
+    visit_table_cells(pager, table_schema.rootpage, &mut |row_id, payload| {
+        let mut record = parse_records(payload, encoding)?;
+        record.resize(column_names.len(), Value::Null);
+        let mut key = index_key(&record, &column_indices, row_id_alias, row_id);
+        key.push(Value::Integer(row_id as i64));
+        keys.push(key);
+        Ok(())
+    })?;
+    keys.sort_by(|left, right| compare_index_key(left, right, encoding));
+
+    if statement.unique {
+        for pair in keys.windows(2) {
+            let columns = column_indices.len();
+            let (left, right) = (&pair[0][..columns], &pair[1][..columns]);
+            let has_null = left.iter().any(|field| matches!(field, Value::Null));
+            if !has_null && compare_index_key(left, right, encoding) == Ordering::Equal {
+                bail!("UNIQUE constraint failed: index {}", statement.name);
+            }
+        }
+    }
+
+    let mut cells = Vec::new();
+    for key in &keys {
+        cells.push(build_index_cell(
+            pager,
+            None,
+            &encode_record(key, encoding),
+        )?);
+    }
+    let rootpage = build_tree(pager, PageType::LeafIndex, cells)?;
+    add_schema_row(
+        &SqliteSchema {
+            schema_type: "index".to_owned(),
+            name: statement.name,
+            tbl_name: table_schema.name,
+            rootpage,
+            sql: statement.sql,
+        },
+        pager,
+    )?;
+
+    let header = pager.header_mut();
+    header.schema_cookie = header.schema_cookie.wrapping_add(1);
+    pager.commit()
+}
+
+/// Run a DROP TABLE statement: remove the table, its indexes and triggers from sqlite_schema
+/// and put their pages on the freelist
+pub fn drop_table(statement: DropTableStatement, pager: &mut Pager) -> anyhow::Result<()> {
+    let encoding = pager.header().text_encoding;
+    let mut schema_rows = Vec::new();
+    visit_table_cells(pager, 1, &mut |row_id, payload| {
+        schema_rows.push((row_id, SqliteSchema::from_bytes(payload, encoding)?));
+        Ok(())
+    })?;
+    let table_name = match schema_rows.iter().find(|(_, schema)| {
+        schema.schema_type == "table" && schema.name.eq_ignore_ascii_case(&statement.name)
+    }) {
+        Some((_, schema)) => schema.name.clone(),
+        None if statement.if_exists => return Ok(()),
+        None => bail!("No such table: {}", statement.name),
+    };
+    if table_name.to_lowercase().starts_with("sqlite_") {
+        bail!("Table {} may not be dropped", table_name);
+    }
+
+    for (row_id, schema) in &schema_rows {
+        if schema.tbl_name != table_name {
+            continue;
+        }
+        // Triggers and views have no b-tree
+        if schema.rootpage != 0 {
+            free_tree(pager, schema.rootpage)?;
+        }
+        delete_table_row(pager, 1, *row_id)?;
+    }
+
+    if let Some((_, sequence)) = schema_rows
+        .iter()
+        .find(|(_, schema)| schema.schema_type == "table" && schema.name == "sqlite_sequence")
+    {
+        let mut row_ids = Vec::new();
+        visit_table_cells(pager, sequence.rootpage, &mut |row_id, payload| {
+            if parse_records(payload, encoding)?
+                .first()
+                .map(Value::to_string)
+                == Some(table_name.clone())
+            {
+                row_ids.push(row_id);
+            }
+            Ok(())
+        })?;
+        for row_id in row_ids {
+            delete_table_row(pager, sequence.rootpage, row_id)?;
+        }
+    }
+
+    let header = pager.header_mut();
+    header.schema_cookie = header.schema_cookie.wrapping_add(1);
+    pager.commit()
+}
+
+/// Run a PRAGMA statement, returning the lines of its result
+pub fn pragma(statement: PragmaStatement, pager: &mut Pager) -> anyhow::Result<Vec<String>> {
+    let value = statement.value.map(|value| value.to_lowercase());
+    match statement.name.as_str() {
+        "wal_checkpoint" => {
+            // Without other connections to wait for, every mode copies all frames and restarts the WAL
+            if !matches!(
+                value.as_deref(),
+                None | Some("passive") | Some("full") | Some("restart") | Some("truncate")
+            ) {
+                bail!("Unknown checkpoint mode: {}", value.unwrap_or_default());
+            }
+            // Busy flag, frames in the WAL and frames checkpointed, like SQLite reports them
+            Ok(vec![match pager.checkpoint()? {
+                Some(frame_count) => format!("0|{}|{}", frame_count, frame_count),
+                None => "0|-1|-1".to_string(),
+            }])
+        }
+        "integrity_check" => {
+            let max_errors = match value {
+                Some(value) => match value.parse::<usize>() {
+                    Ok(max_errors) if max_errors > 0 => max_errors,
+                    _ => bail!("Unsupported integrity_check argument: {}", value),
+                },
+                None => integrity::DEFAULT_MAX_ERRORS,
+            };
+            integrity::integrity_check(pager, max_errors)
+        }
+        "journal_mode" => {
+            match value.as_deref() {
+                None => {}
+                Some("wal") => pager.set_wal_mode(true)?,
+                Some("delete") => pager.set_wal_mode(false)?,
+                Some(mode) => bail!("Unsupported journal mode: {}", mode),
+            }
+            let mode = if pager.header().is_wal_mode() {
+                "wal"
             } else {
-                records.push(record_slice);
+                "delete"
+            };
+            Ok(vec![mode.to_string()])
+        }
+        _ => bail!("Unsupported pragma: {}", statement.name),
+    }
+}
+
+/// Run VACUUM: copy every b-tree into a fresh database next to this one, densely packed and
+/// without free pages, then write its pages over the original file in a single commit, so the
+/// rollback journal or the WAL makes the swap crash-safe
+pub fn vacuum(pager: &mut Pager) -> anyhow::Result<()> {
+    if pager.in_transaction() {
+        bail!("Cannot VACUUM from within a transaction");
+    }
+    let vacuum_path = format!("{}-vacuum", pager.filepath());
+    let result = build_vacuumed_database(&vacuum_path, pager).and_then(|mut vacuumed| {
+        // The counters carry on from the original database, the schema cookie tells
+        // other connections that the root pages moved
+        let mut header = vacuumed.header().clone();
+        let original = pager.header();
+        header.write_version = original.write_version;
+        header.read_version = original.read_version;
+        header.file_change_counter = original.file_change_counter;
+        header.schema_cookie = original.schema_cookie.wrapping_add(1);
+        *pager.header_mut() = header;
+        for page_num in 1..=vacuumed.header().database_size as u64 {
+            pager.write_page(page_num, vacuumed.read_page(page_num)?.to_vec())?;
+        }
+        pager.commit()
+    });
+    let removed = std::fs::remove_file(&vacuum_path);
+    result?;
+    Ok(removed?)
+}
+
+/// Create the database at `path` with the header settings of `pager`'s database,
+/// and fill it with a copy of every b-tree and sqlite_schema row
+fn build_vacuumed_database(path: &str, pager: &mut Pager) -> anyhow::Result<Pager> {
+    let mut header = pager.header().clone();
+    header.write_version = 1;
+    header.read_version = 1;
+    header.database_size = 1;
+    header.freelist_trunk_page = 0;
+    header.freelist_count = 0;
+    let mut page1 = vec![0; header.page_size as usize];
+    page1[..HEADER_SIZE].copy_from_slice(&header.to_bytes());
+    // A journal left by an interrupted VACUUM belongs to an older copy
+    let stale_journal = journal_path(path);
+    if std::path::Path::new(&stale_journal).exists() {
+        std::fs::remove_file(&stale_journal)?;
+    }
+    std::fs::write(path, &page1)?;
+
+    let mut vacuumed = Pager::open(path)?;
+    let schema_page = build_page(&mut vacuumed, 1, PageType::LeafTable, &[], None)?;
+    vacuumed.write_page(1, schema_page)?;
+
+    for schema in get_tables(pager)? {
+        // Views and triggers have no b-tree
+        if schema.rootpage == 0 {
+            add_schema_row(&schema, &mut vacuumed)?;
+            continue;
+        }
+        let page = pager.read_page(schema.rootpage)?;
+        let leaf_type = match parse_page(&page, schema.rootpage)?.0.page_type {
+            PageType::LeafTable | PageType::InteriorTable => PageType::LeafTable,
+            PageType::LeafIndex | PageType::InteriorIndex => PageType::LeafIndex,
+        };
+        let mut cells = Vec::new();
+        match leaf_type {
+            PageType::LeafTable => {
+                visit_table_cells(pager, schema.rootpage, &mut |row_id, payload| {
+                    cells.push(build_table_leaf_cell(&mut vacuumed, row_id, payload)?);
+                    Ok(())
+                })?;
+            }
+            _ => {
+                // WITHOUT ROWID tables are stored in index b-trees as well
+                let every_entry = |_: &[u8]| Ok(Ordering::Equal);
+                search_index(pager, schema.rootpage, &every_entry, &mut |payload| {
+                    cells.push(build_index_cell(&mut vacuumed, None, payload)?);
+                    Ok(())
+                })?;
             }
         }
-        Ok(records)
-    } else {
-        Err(anyhow::anyhow!("Table {} not found.", table_name))
+        let rootpage = build_tree(&mut vacuumed, leaf_type, cells)?;
+        add_schema_row(&SqliteSchema { rootpage, ..schema }, &mut vacuumed)?;
+    }
+    vacuumed.commit()?;
+    Ok(vacuumed)
+}
+
+/// The value of an expression for a row with `record` and `row_id`
+fn evaluate(
+    expression: &Expression,
+    table: &TableDef,
+    row_id: u64,
+    record: &[Value],
+) -> anyhow::Result<Value> {
+    Ok(match expression {
+        Expression::Literal(value) => value.clone(),
+        Expression::Column(name) => match resolve_column(table, name)? {
+            Some(i) => match (&record[i], table.columns[i].affinity) {
+                (Value::Integer(num), Affinity::Real) => Value::Real(*num as f64),
+                (value, _) => value.clone(),
+            },
+            None => Value::Integer(row_id as i64),
+        },
+        Expression::Negate(operand) => ArithmeticOperator::Subtract.apply(
+            &Value::Integer(0),
+            &evaluate(operand, table, row_id, record)?,
+        ),
+        Expression::Concatenate(left, right) => match (
+            evaluate(left, table, row_id, record)?,
+            evaluate(right, table, row_id, record)?,
+        ) {
+            (Value::Null, _) | (_, Value::Null) => Value::Null,
+            (left, right) => Value::Text(format!("{}{}", left, right)),
+        },
+        Expression::Arithmetic(left, operator, right) => operator.apply(
+            &evaluate(left, table, row_id, record)?,
+            &evaluate(right, table, row_id, record)?,
+        ),
+    })
+}
+
+/// Run an UPDATE statement, rewriting the matching rows and the index entries whose key changes.
+/// Returns the number of updated rows.
+pub fn update_rows(statement: UpdateStatement, pager: &mut Pager) -> anyhow::Result<usize> {
+    let encoding = pager.header().text_encoding;
+    let (table_schema, table) = find_table_schema(&statement.table, pager)?;
+    let column_names = table.column_names();
+    let row_id_alias = table.row_id_alias();
+
+    let mut assignments = Vec::new();
+    for (column, expression) in statement.assignments {
+        let Some(position) = table.column_position(&column) else {
+            bail!("Field {} not found in table", column);
+        };
+        for name in expression.columns() {
+            resolve_column(&table, name)?;
+        }
+        assignments.push((position, expression));
+    }
+    let (condition, index) = resolve_condition(&table_schema, &table, statement.condition, pager)?;
+    let rows = find_rows(&table_schema, &table, condition, index, pager)?;
+
+    let indexes = get_table_indexes(&table_schema, &column_names, pager)?;
+    for (row_id, record) in &rows {
+        let mut old_record = record.clone();
+        old_record.resize(column_names.len(), Value::Null);
+        // Every expression reads the row as it was before the update
+        let mut new_record = old_record.clone();
+        let mut new_row_id = *row_id;
+        for (position, expression) in &assignments {
+            let value = evaluate(expression, &table, *row_id, &old_record)?
+                .apply_affinity(table.columns[*position].affinity);
+            if Some(*position) != row_id_alias {
+                new_record[*position] = value;
+                continue;
+            }
+            // Setting the INTEGER PRIMARY KEY moves the row to a new rowid, the column stays NULL
+            match value.as_i64() {
+                Some(value) => new_row_id = value as u64,
+                None => bail!(
+                    "datatype mismatch: INTEGER PRIMARY KEY {} can't be set to '{}'",
+                    column_names[*position],
+                    value
+                ),
+            }
+        }
+
+        for (index_schema, column_indices) in &indexes {
+            let key = index_key(&old_record, column_indices, row_id_alias, *row_id);
+            let new_key = index_key(&new_record, column_indices, row_id_alias, new_row_id);
+            if new_row_id == *row_id
+                && compare_index_key(&key, &new_key, encoding) == Ordering::Equal
+            {
+                continue;
+            }
+            remove_index_entry(index_schema, key, *row_id, pager)?;
+            add_index_entry(index_schema, new_key, new_row_id, pager)?;
+        }
+
+        let payload = encode_record(&new_record, encoding);
+        if new_row_id == *row_id {
+            update_table_row(pager, table_schema.rootpage, *row_id, &payload)?;
+        } else {
+            delete_table_row(pager, table_schema.rootpage, *row_id)?;
+            insert_table_row(pager, table_schema.rootpage, new_row_id, &payload)?;
+        }
     }
+    pager.commit()?;
+    Ok(rows.len())
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
 
     #[test]
@@ -205,4 +1407,716 @@ mod tests {
 
         assert_eq!(varint, 5634);
     }
+
+    /// A database file in its own temporary directory, removed when dropped
+    pub struct TestDb {
+        dir: std::path::PathBuf,
+        path: String,
+    }
+
+    impl TestDb {
+        fn new(test_name: &str, file_name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("{}-{}", test_name, std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let path = dir.join(file_name).to_str().unwrap().to_owned();
+            TestDb { dir, path }
+        }
+    }
+
+    impl std::ops::Deref for TestDb {
+        type Target = str;
+
+        fn deref(&self) -> &str {
+            &self.path
+        }
+    }
+
+    impl AsRef<std::path::Path> for TestDb {
+        fn as_ref(&self) -> &std::path::Path {
+            self.path.as_ref()
+        }
+    }
+
+    impl std::fmt::Display for TestDb {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            f.write_str(&self.path)
+        }
+    }
+
+    impl Drop for TestDb {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    /// Copy sample.db to a fresh temporary file that tests may modify
+    pub fn copy_sample_db(test_name: &str) -> TestDb {
+        let db = TestDb::new(test_name, "sample.db");
+        std::fs::copy("sample.db", &db).unwrap();
+        db
+    }
+
+    /// Create an empty database in a fresh temporary file, storing text in `encoding`
+    pub fn create_empty_db(test_name: &str, encoding: TextEncoding) -> TestDb {
+        let db = TestDb::new(test_name, "empty.db");
+        let sample = std::fs::read("sample.db").unwrap();
+        let mut header = crate::header::DatabaseHeader::from_bytes(&sample).unwrap();
+        header.database_size = 1;
+        header.freelist_trunk_page = 0;
+        header.freelist_count = 0;
+        header.text_encoding = encoding;
+
+        // Page 1 is the header followed by an empty sqlite_schema leaf
+        let mut page = vec![0; header.page_size as usize];
+        page[..HEADER_SIZE].copy_from_slice(&header.to_bytes());
+        page[HEADER_SIZE] = 0x0d;
+        page[HEADER_SIZE + 5..HEADER_SIZE + 7]
+            .copy_from_slice(&(header.page_size as u16).to_be_bytes());
+        std::fs::write(&db, page).unwrap();
+        db
+    }
+
+    /// The first column of every entry of an index, in index order
+    pub fn index_column(index_name: &str, pager: &mut Pager) -> anyhow::Result<Vec<Value>> {
+        let encoding = pager.header().text_encoding;
+        let Some(index_schema) = get_tables(pager)?
+            .into_iter()
+            .find(|schema| schema.name == index_name)
+        else {
+            bail!("No index {}", index_name);
+        };
+        let mut column = Vec::new();
+        search_index(
+            pager,
+            index_schema.rootpage,
+            &|_payload| Ok(Ordering::Equal),
+            &mut |payload| {
+                column.push(parse_records(payload, encoding)?.swap_remove(0));
+                Ok(())
+            },
+        )?;
+        Ok(column)
+    }
+
+    #[test]
+    fn test_insert_rows() -> anyhow::Result<()> {
+        let db_path = copy_sample_db("insert-rows");
+        let statement = crate::sql_parser::parse_insert_statement(
+            "INSERT INTO apples (name, color) VALUES ('Pink Lady', 'Pink')",
+        )?
+        .1;
+        insert_rows(statement, &mut Pager::open(&db_path)?)?;
+
+        let mut pager = Pager::open(&db_path)?;
+        let records = get_records_from_table(
+            "apples",
+            vec!["name", "color"],
+            Some(SelectCondition::Comparison(
+                "name".to_owned(),
+                ComparisonOperator::Equals,
+                Value::Text("Pink Lady".to_owned()),
+            )),
+            &mut pager,
+        )?;
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0][1].to_string(), "Pink");
+        assert_eq!(count_table_rows("apples", None, &mut pager)?, 5);
+        assert_eq!(
+            count_table_rows(
+                "apples",
+                Some(SelectCondition::Comparison(
+                    "id".to_owned(),
+                    ComparisonOperator::Greater,
+                    Value::Integer(2),
+                )),
+                &mut pager,
+            )?,
+            3
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_non_positive_rowids() -> anyhow::Result<()> {
+        let db_path = copy_sample_db("insert-non-positive-rowids");
+        let mut pager = Pager::open(&db_path)?;
+        let create = crate::sql_parser::parse_create_table_statement(
+            "CREATE TABLE n (id INTEGER PRIMARY KEY, v TEXT)",
+        )?
+        .1;
+        create_table(create, &mut pager)?;
+        for sql in [
+            "INSERT INTO n VALUES (-7, 'a')",
+            "INSERT INTO n (v) VALUES ('b')",
+            "INSERT INTO n VALUES (0, 'c')",
+            "INSERT INTO n (v) VALUES ('d')",
+        ] {
+            insert_rows(
+                crate::sql_parser::parse_insert_statement(sql)?.1,
+                &mut pager,
+            )?;
+        }
+
+        let rows = get_records_from_table("n", vec!["id", "v"], None, &mut pager)?;
+        assert_eq!(
+            rows.iter()
+                .map(|row| format!("{}|{}", row[0], row[1]))
+                .collect::<Vec<String>>(),
+            ["-7|a", "-6|b", "0|c", "1|d"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_autoincrement_sequence() -> anyhow::Result<()> {
+        let db_path = create_empty_db("autoincrement-sequence", TextEncoding::Utf8);
+        let mut pager = Pager::open(&db_path)?;
+        let sql = "CREATE TABLE a (id INTEGER PRIMARY KEY AUTOINCREMENT, v TEXT)";
+        create_table(
+            crate::sql_parser::parse_create_table_statement(sql)?.1,
+            &mut pager,
+        )?;
+        let sequence = |pager: &mut Pager| {
+            get_records_from_table("sqlite_sequence", vec!["name", "seq"], None, pager)
+        };
+        assert!(sequence(&mut pager)?.is_empty());
+
+        for sql in [
+            "INSERT INTO a VALUES (-5, 'x')",
+            "INSERT INTO a (v) VALUES ('y')",
+            "INSERT INTO a VALUES (10, 'z')",
+            "DELETE FROM a WHERE id = 10",
+            // The deleted rowid isn't reused
+            "INSERT INTO a (v) VALUES ('w')",
+            "UPDATE a SET id = 50 WHERE id = 11",
+            "INSERT INTO a (v) VALUES ('q')",
+        ] {
+            match sql.split(' ').next() {
+                Some("INSERT") => insert_rows(
+                    crate::sql_parser::parse_insert_statement(sql)?.1,
+                    &mut pager,
+                )?,
+                Some("DELETE") => delete_rows(
+                    crate::sql_parser::parse_delete_statement(sql)?.1,
+                    &mut pager,
+                )?,
+                _ => update_rows(
+                    crate::sql_parser::parse_update_statement(sql)?.1,
+                    &mut pager,
+                )?,
+            };
+        }
+        let ids = get_records_from_table("a", vec!["id"], None, &mut pager)?;
+        assert_eq!(
+            ids.iter()
+                .map(|row| row[0].to_string())
+                .collect::<Vec<String>>(),
+            ["-5", "1", "50", "51"]
+        );
+        assert_eq!(
+            sequence(&mut pager)?,
+            [[Value::Text("a".to_owned()), Value::Integer(51)]]
+        );
+
+        // Once the largest rowid was used, there are no more
+        let sql = "INSERT INTO a VALUES (9223372036854775807, 'max')";
+        insert_rows(
+            crate::sql_parser::parse_insert_statement(sql)?.1,
+            &mut pager,
+        )?;
+        let sql = "DELETE FROM a WHERE id > 51";
+        delete_rows(
+            crate::sql_parser::parse_delete_statement(sql)?.1,
+            &mut pager,
+        )?;
+        let sql = "INSERT INTO a (v) VALUES ('full')";
+        assert!(insert_rows(
+            crate::sql_parser::parse_insert_statement(sql)?.1,
+            &mut pager
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_non_integer_rowid() -> anyhow::Result<()> {
+        let db_path = copy_sample_db("insert-non-integer-rowid");
+        let mut pager = Pager::open(&db_path)?;
+        let insert = |sql: &'static str, pager: &mut Pager| {
+            insert_rows(crate::sql_parser::parse_insert_statement(sql)?.1, pager)
+        };
+        // Values that convert to integers are fine, others are a datatype mismatch
+        insert(
+            "INSERT INTO apples (id, name) VALUES ('8', 'a'), (9.0, 'b')",
+            &mut pager,
+        )?;
+        for sql in [
+            "INSERT INTO apples (id, name) VALUES ('abc', 'c')",
+            "INSERT INTO apples (id, name) VALUES (3.5, 'd')",
+        ] {
+            let err = insert(sql, &mut pager).unwrap_err();
+            assert!(err.to_string().starts_with("datatype mismatch"), "{}", err);
+        }
+        let rows = get_records_from_table("apples", vec!["id", "name"], None, &mut pager)?;
+        assert_eq!(
+            rows[rows.len() - 2..],
+            [
+                [Value::Integer(8), Value::Text("a".to_owned())],
+                [Value::Integer(9), Value::Text("b".to_owned())],
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_into_utf16_index() -> anyhow::Result<()> {
+        let db_path = create_empty_db("insert-utf16-index", TextEncoding::Utf16le);
+        let mut pager = Pager::open(&db_path)?;
+        let create = crate::sql_parser::parse_create_table_statement(
+            "CREATE TABLE t (id INTEGER PRIMARY KEY, name TEXT)",
+        )?
+        .1;
+        create_table(create, &mut pager)?;
+        let create =
+            crate::sql_parser::parse_create_index_statement("CREATE INDEX tn ON t (name)")?.1;
+        create_index(create, &mut pager)?;
+        let insert = crate::sql_parser::parse_insert_statement(
+            "INSERT INTO t (name) VALUES ('a'), ('\u{101}'), ('b'), ('\u{fc}n\u{ef}')",
+        )?
+        .1;
+        insert_rows(insert, &mut pager)?;
+
+        // UTF-16le text sorts by its little-endian bytes, U+0101 before 'a'
+        let names = index_column("tn", &mut pager)?;
+        assert_eq!(
+            names.iter().map(Value::to_string).collect::<Vec<String>>(),
+            ["\u{101}", "a", "b", "\u{fc}n\u{ef}"]
+        );
+        let condition = Some(SelectCondition::Comparison(
+            "name".to_owned(),
+            ComparisonOperator::Equals,
+            Value::Text("\u{fc}n\u{ef}".to_owned()),
+        ));
+        let rows = get_records_from_table("t", vec!["id"], condition, &mut pager)?;
+        assert_eq!(rows, [[Value::Integer(4)]]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_rows() -> anyhow::Result<()> {
+        let db_path = copy_sample_db("delete-rows");
+        let statement =
+            crate::sql_parser::parse_delete_statement("DELETE FROM apples WHERE color = 'Red'")?.1;
+        assert_eq!(delete_rows(statement, &mut Pager::open(&db_path)?)?, 1);
+
+        let mut pager = Pager::open(&db_path)?;
+        let names = get_records_from_table("apples", vec!["name"], None, &mut pager)?
+            .iter()
+            .map(|record| record[0].to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(names, ["Granny Smith", "Honeycrisp", "Golden Delicious"]);
+
+        // Any condition SELECT takes, here a range of the rowid
+        let statement =
+            crate::sql_parser::parse_delete_statement("DELETE FROM apples WHERE id >= 3")?.1;
+        assert_eq!(delete_rows(statement, &mut pager)?, 2);
+        let names = get_records_from_table("apples", vec!["name"], None, &mut pager)?;
+        assert_eq!(names, [[Value::Text("Granny Smith".to_owned())]]);
+        Ok(())
+    }
+
+    /// A database whose table `h` got a column `y`, indexed by `hy`, after its two rows were
+    /// written, as `ALTER TABLE h ADD COLUMN y TEXT` leaves it
+    fn create_short_record_db(test_name: &str) -> anyhow::Result<TestDb> {
+        let db_path = create_empty_db(test_name, TextEncoding::Utf8);
+        let mut pager = Pager::open(&db_path)?;
+        let sql = "CREATE TABLE h (id INTEGER PRIMARY KEY, x TEXT)";
+        create_table(
+            crate::sql_parser::parse_create_table_statement(sql)?.1,
+            &mut pager,
+        )?;
+        let sql = "INSERT INTO h VALUES (1, 'a'), (2, 'b')";
+        insert_rows(
+            crate::sql_parser::parse_insert_statement(sql)?.1,
+            &mut pager,
+        )?;
+        let table_schema = SqliteSchema {
+            sql: "CREATE TABLE h (id INTEGER PRIMARY KEY, x TEXT, y TEXT)".to_owned(),
+            ..lookup_table_schema("h", &mut pager)?
+        };
+        update_table_row(&mut pager, 1, 1, &table_schema.to_bytes(TextEncoding::Utf8))?;
+        pager.commit()?;
+        let sql = "CREATE INDEX hy ON h (y)";
+        create_index(
+            crate::sql_parser::parse_create_index_statement(sql)?.1,
+            &mut pager,
+        )?;
+        Ok(db_path)
+    }
+
+    #[test]
+    fn test_virtual_generated_column() -> anyhow::Result<()> {
+        // VIRTUAL columns don't change the records, a table can get one by rewriting its schema
+        let db_path = create_empty_db("virtual-generated-column", TextEncoding::Utf8);
+        let mut pager = Pager::open(&db_path)?;
+        let sql = "CREATE TABLE g (a INTEGER, c TEXT)";
+        create_table(
+            crate::sql_parser::parse_create_table_statement(sql)?.1,
+            &mut pager,
+        )?;
+        let sql = "INSERT INTO g VALUES (1, 'one'), (2, 'two')";
+        insert_rows(
+            crate::sql_parser::parse_insert_statement(sql)?.1,
+            &mut pager,
+        )?;
+        let table_schema = SqliteSchema {
+            sql: "CREATE TABLE g (a INTEGER, b INTEGER GENERATED ALWAYS AS (a*2) VIRTUAL, c TEXT)"
+                .to_owned(),
+            ..lookup_table_schema("g", &mut pager)?
+        };
+        update_table_row(&mut pager, 1, 1, &table_schema.to_bytes(TextEncoding::Utf8))?;
+        pager.commit()?;
+
+        let condition = SelectCondition::Comparison(
+            "c".to_owned(),
+            ComparisonOperator::Equals,
+            Value::Text("two".to_owned()),
+        );
+        assert_eq!(
+            get_records_from_table("g", vec!["a", "c"], Some(condition), &mut pager)?,
+            [[Value::Integer(2), Value::Text("two".to_owned())]]
+        );
+        assert!(get_records_from_table("g", vec!["b"], None, &mut pager).is_err());
+        let sql = "INSERT INTO g (a, c) VALUES (3, 'three')";
+        assert!(insert_rows(
+            crate::sql_parser::parse_insert_statement(sql)?.1,
+            &mut pager
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_short_records() -> anyhow::Result<()> {
+        let db_path = create_short_record_db("delete-short-records")?;
+        let mut pager = Pager::open(&db_path)?;
+        let statement = crate::sql_parser::parse_delete_statement("DELETE FROM h WHERE id = 1")?.1;
+        assert_eq!(delete_rows(statement, &mut pager)?, 1);
+        assert_eq!(index_column("hy", &mut pager)?, [Value::Null]);
+        assert_eq!(
+            integrity::integrity_check(&mut pager, integrity::DEFAULT_MAX_ERRORS)?,
+            ["ok"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_short_records() -> anyhow::Result<()> {
+        let db_path = create_short_record_db("update-short-records")?;
+        let mut pager = Pager::open(&db_path)?;
+        let statement = crate::sql_parser::parse_update_statement("UPDATE h SET x = 'z'")?.1;
+        assert_eq!(update_rows(statement, &mut pager)?, 2);
+        let statement =
+            crate::sql_parser::parse_update_statement("UPDATE h SET y = x || id WHERE id = 2")?.1;
+        assert_eq!(update_rows(statement, &mut pager)?, 1);
+        assert_eq!(
+            index_column("hy", &mut pager)?,
+            [Value::Null, Value::Text("z2".to_owned())]
+        );
+        assert_eq!(
+            integrity::integrity_check(&mut pager, integrity::DEFAULT_MAX_ERRORS)?,
+            ["ok"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_rows() -> anyhow::Result<()> {
+        let db_path = copy_sample_db("update-rows");
+        let long_color = "Green".repeat(1000);
+        let sql = format!(
+            "UPDATE apples SET color = '{}' WHERE name = 'Fuji'",
+            long_color
+        );
+        let statement = crate::sql_parser::parse_update_statement(&sql).unwrap().1;
+        assert_eq!(update_rows(statement, &mut Pager::open(&db_path)?)?, 1);
+
+        let mut pager = Pager::open(&db_path)?;
+        let colors = get_records_from_table("apples", vec!["color"], None, &mut pager)?
+            .iter()
+            .map(|record| record[0].to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(
+            colors,
+            ["Light Green", long_color.as_str(), "Blush Red", "Yellow"]
+        );
+
+        // Expressions read the row before the update, and the rowid may become negative
+        let statement = crate::sql_parser::parse_update_statement(
+            "UPDATE apples SET id = id - 10, name = name || ' ' || id WHERE id BETWEEN 3 AND 4",
+        )?
+        .1;
+        assert_eq!(update_rows(statement, &mut pager)?, 2);
+        let rows = get_records_from_table("apples", vec!["id", "name"], None, &mut pager)?
+            .iter()
+            .map(|record| format!("{}|{}", record[0], record[1]))
+            .collect::<Vec<String>>();
+        assert_eq!(
+            rows,
+            [
+                "-7|Honeycrisp 3",
+                "-6|Golden Delicious 4",
+                "1|Granny Smith",
+                "2|Fuji"
+            ]
+        );
+        let statement =
+            crate::sql_parser::parse_update_statement("UPDATE apples SET id = NULL WHERE id = -7")?
+                .1;
+        assert!(update_rows(statement, &mut pager).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_create_and_drop_table() -> anyhow::Result<()> {
+        let db_path = copy_sample_db("create-drop-table");
+        let mut pager = Pager::open(&db_path)?;
+        let schema_cookie = pager.header().schema_cookie;
+        let statement =
+            crate::sql_parser::parse_create_table_statement("CREATE TABLE pears (name text)")?.1;
+        create_table(statement, &mut pager)?;
+        assert_eq!(pager.header().schema_cookie, schema_cookie + 1);
+        assert_eq!(count_table_rows("pears", None, &mut pager)?, 0);
+
+        let statement = crate::sql_parser::parse_drop_table_statement("DROP TABLE apples")?.1;
+        drop_table(statement, &mut pager)?;
+        let mut pager = Pager::open(&db_path)?;
+        let names = get_tables(&mut pager)?
+            .into_iter()
+            .map(|schema| schema.name)
+            .collect::<Vec<String>>();
+        assert_eq!(names, ["sqlite_sequence", "oranges", "pears"]);
+        assert_eq!(pager.header().freelist_count, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_create_index() -> anyhow::Result<()> {
+        let db_path = copy_sample_db("create-index");
+        let mut pager = Pager::open(&db_path)?;
+        let statement =
+            crate::sql_parser::parse_create_index_statement("CREATE INDEX idx ON apples (color)")?
+                .1;
+        create_index(statement, &mut pager)?;
+
+        let mut pager = Pager::open(&db_path)?;
+        let index = find_index_on_column("apples", "color", &mut pager)?.unwrap();
+        let mut entries = Vec::new();
+        search_index(
+            &mut pager,
+            index.rootpage,
+            &|_payload| Ok(Ordering::Equal),
+            &mut |payload| {
+                let entry = parse_records(payload, TextEncoding::Utf8)?;
+                entries.push(format!("{}|{}", entry[0], entry[1]));
+                Ok(())
+            },
+        )?;
+        assert_eq!(
+            entries,
+            ["Blush Red|3", "Light Green|1", "Red|2", "Yellow|4"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_create_index_on_utf16_table() -> anyhow::Result<()> {
+        let db_path = create_empty_db("create-utf16-index", TextEncoding::Utf16be);
+        let mut pager = Pager::open(&db_path)?;
+        let create =
+            crate::sql_parser::parse_create_table_statement("CREATE TABLE t (name TEXT)")?.1;
+        create_table(create, &mut pager)?;
+        let insert = crate::sql_parser::parse_insert_statement(
+            "INSERT INTO t VALUES ('\u{e000}'), ('\u{1f600}'), ('a'), ('\u{ff5e}')",
+        )?
+        .1;
+        insert_rows(insert, &mut pager)?;
+        let create =
+            crate::sql_parser::parse_create_index_statement("CREATE INDEX tn ON t (name)")?.1;
+        create_index(create, &mut pager)?;
+
+        // In UTF-16 the surrogate pair of U+1F600 sorts before U+E000
+        let names = index_column("tn", &mut pager)?;
+        assert_eq!(
+            names.iter().map(Value::to_string).collect::<Vec<String>>(),
+            ["a", "\u{1f600}", "\u{e000}", "\u{ff5e}"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_vacuum_drops_free_pages() -> anyhow::Result<()> {
+        let db_path = copy_sample_db("vacuum");
+        let mut pager = Pager::open(&db_path)?;
+        let long_color = "Green".repeat(5000);
+        for color in [long_color.as_str(), "Green"] {
+            let sql = format!(
+                "UPDATE apples SET color = '{}' WHERE name = 'Granny Smith'",
+                color
+            );
+            let statement = crate::sql_parser::parse_update_statement(&sql).unwrap().1;
+            update_rows(statement, &mut pager)?;
+        }
+        let mut pager = Pager::open(&db_path)?;
+        assert!(pager.header().freelist_count > 0);
+        let before = get_records_from_table("apples", vec!["name", "color"], None, &mut pager)?;
+
+        vacuum(&mut pager)?;
+        let mut pager = Pager::open(&db_path)?;
+        assert_eq!(pager.header().freelist_count, 0);
+        assert_eq!(pager.header().database_size, 4);
+        let after = get_records_from_table("apples", vec!["name", "color"], None, &mut pager)?;
+        assert_eq!(format!("{:?}", after), format!("{:?}", before));
+        assert!(!std::path::Path::new(&format!("{}-vacuum", db_path)).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_select_rowid() -> anyhow::Result<()> {
+        let mut pager = Pager::open("sample.db")?;
+        let rows = get_records_from_table("apples", vec!["id", "rowid", "name"], None, &mut pager)?;
+        assert_eq!(
+            rows.iter()
+                .map(|row| format!("{}|{}|{}", row[0], row[1], row[2]))
+                .collect::<Vec<String>>()[..2],
+            ["1|1|Granny Smith", "2|2|Fuji"]
+        );
+        let condition = Some(SelectCondition::Comparison(
+            "_rowid_".to_owned(),
+            ComparisonOperator::Equals,
+            Value::Text("3".to_owned()),
+        ));
+        let rows = get_records_from_table("apples", vec!["oid", "name"], condition, &mut pager)?;
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0][0].to_string(), "3");
+        let condition = Some(SelectCondition::Comparison(
+            "id".to_owned(),
+            ComparisonOperator::Greater,
+            Value::Real(2.5),
+        ));
+        let rows = get_records_from_table("APPLES", vec!["name"], condition, &mut pager)?;
+        assert_eq!(rows.len(), 2);
+        assert!(get_records_from_table("apples", vec!["rowid_"], None, &mut pager).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_select_negative_rowids() -> anyhow::Result<()> {
+        let db_path = copy_sample_db("select-negative-rowids");
+        let mut pager = Pager::open(&db_path)?;
+        let encoding = pager.header().text_encoding;
+        for row_id in [-5_i64, -1, 0] {
+            let record = [Value::Null, Value::Text(row_id.to_string()), Value::Null];
+            insert_table_row(
+                &mut pager,
+                2,
+                row_id as u64,
+                &encode_record(&record, encoding),
+            )?;
+        }
+        let mut select = |operator, value| -> anyhow::Result<Vec<String>> {
+            let condition = Some(SelectCondition::Comparison(
+                "id".to_owned(),
+                operator,
+                Value::Integer(value),
+            ));
+            let rows = get_records_from_table("apples", vec!["id"], condition, &mut pager)?;
+            Ok(rows.iter().map(|row| row[0].to_string()).collect())
+        };
+        assert_eq!(select(ComparisonOperator::Equals, -5)?, ["-5"]);
+        assert_eq!(
+            select(ComparisonOperator::Greater, -2)?,
+            ["-1", "0", "1", "2", "3", "4"]
+        );
+        assert_eq!(
+            select(ComparisonOperator::Less, 3)?,
+            ["-5", "-1", "0", "1", "2"]
+        );
+        let condition = Some(SelectCondition::Between(
+            "rowid".to_owned(),
+            Value::Integer(-10),
+            Value::Integer(0),
+        ));
+        let rows = get_records_from_table("apples", vec!["name"], condition, &mut pager)?;
+        assert_eq!(rows.len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_without_rowid_table() -> anyhow::Result<()> {
+        let db_path = copy_sample_db("without-rowid");
+        let mut pager = Pager::open(&db_path)?;
+        let encoding = pager.header().text_encoding;
+        // Stored the way SQLite does: primary key columns first, in key order
+        let mut cells = Vec::new();
+        for (code, name, price) in [("a1", "Fuji", 3), ("b2", "Gala", 2), ("c3", "Pink Lady", 5)] {
+            let record = [
+                Value::Text(code.to_owned()),
+                Value::Text(name.to_owned()),
+                Value::Integer(price),
+            ];
+            cells.push(build_index_cell(
+                &mut pager,
+                None,
+                &encode_record(&record, encoding),
+            )?);
+        }
+        let rootpage = build_tree(&mut pager, PageType::LeafIndex, cells)?;
+        add_schema_row(
+            &SqliteSchema {
+                schema_type: "table".to_owned(),
+                name: "prices".to_owned(),
+                tbl_name: "prices".to_owned(),
+                rootpage,
+                sql: "CREATE TABLE prices (name text, price integer, code text, PRIMARY KEY (code)) WITHOUT ROWID".to_owned(),
+            },
+            &mut pager,
+        )?;
+        pager.commit()?;
+
+        let mut pager = Pager::open(&db_path)?;
+        assert_eq!(count_table_rows("prices", None, &mut pager)?, 3);
+        let rows = get_records_from_table("prices", vec!["name", "price"], None, &mut pager)?;
+        assert_eq!(
+            rows.iter()
+                .map(|row| format!("{}|{}", row[0], row[1]))
+                .collect::<Vec<String>>(),
+            vec!["Fuji|3", "Gala|2", "Pink Lady|5"]
+        );
+        let condition = Some(SelectCondition::Comparison(
+            "code".to_owned(),
+            ComparisonOperator::Equals,
+            Value::Text("b2".to_owned()),
+        ));
+        let rows = get_records_from_table("prices", vec!["name"], condition, &mut pager)?;
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0][0].to_string(), "Gala");
+
+        let statement = crate::sql_parser::parse_delete_statement("DELETE FROM prices")
+            .unwrap()
+            .1;
+        assert!(delete_rows(statement, &mut pager).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_varint_round_trip() {
+        assert_eq!(write_varint(5634), vec![0xAC, 0x02]);
+        assert_eq!(write_varint(0), vec![0]);
+        for value in [1, 127, 128, 16383, 16384, 1 << 56, u64::MAX] {
+            let bytes = write_varint(value);
+            assert_eq!(read_varint(&bytes).unwrap(), (value, &[][..]));
+        }
+        assert_eq!(write_varint(u64::MAX).len(), 9);
+    }
 }
@@ -0,0 +1,442 @@
+use crate::header::TextEncoding;
+use anyhow::{bail, Result};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::fmt;
+
+/// 2^63, the smallest real too large for an i64
+const I64_END: f64 = -(i64::MIN as f64);
+
+/// A value of one of SQLite's storage classes, whichever serial type it is stored with
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+/// A value borrowed from the record it was read from. UTF-8 text is borrowed as it is,
+/// UTF-16 text has to be converted.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValueRef<'a> {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(Cow<'a, str>),
+    Blob(&'a [u8]),
+}
+
+/// The type affinity of a column, which decides how values are converted when they are stored
+/// in the column or compared with it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Affinity {
+    Text,
+    Numeric,
+    Integer,
+    Real,
+    Blob,
+}
+
+impl Affinity {
+    /// The affinity of a declared column type, by SQLite's rules: INT, then CHAR, CLOB or TEXT,
+    /// then BLOB or no type at all, then REAL, FLOA or DOUB, and NUMERIC for anything else
+    pub fn from_declared_type(declared_type: &str) -> Self {
+        let declared_type = declared_type.to_uppercase();
+        let contains = |names: &[&str]| names.iter().any(|name| declared_type.contains(name));
+        if contains(&["INT"]) {
+            Self::Integer
+        } else if contains(&["CHAR", "CLOB", "TEXT"]) {
+            Self::Text
+        } else if declared_type.trim().is_empty() || contains(&["BLOB"]) {
+            Self::Blob
+        } else if contains(&["REAL", "FLOA", "DOUB"]) {
+            Self::Real
+        } else {
+            Self::Numeric
+        }
+    }
+}
+
+/// How query output shows BLOB values
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlobFormat {
+    /// The bytes as they are
+    Raw,
+    /// Uppercase hex digits, like SQL's hex()
+    Hex,
+    /// A blob literal, `X'..'`
+    Literal,
+}
+
+impl BlobFormat {
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name.to_lowercase().as_str() {
+            "raw" => Ok(Self::Raw),
+            "hex" => Ok(Self::Hex),
+            "literal" => Ok(Self::Literal),
+            _ => bail!("Unknown blob format {}, expected raw, hex or literal", name),
+        }
+    }
+}
+
+impl Value {
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Self::Integer(num) => Some(*num),
+            _ => None,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(self, Self::Null)
+    }
+
+    /// The number a value stands for in arithmetic: text and blobs read as the longest prefix
+    /// that is a number, 0 if there is none. NULL stays NULL.
+    pub fn to_numeric(&self) -> Self {
+        let numeric_prefix = |text: &str| {
+            let text = text.trim_start();
+            (1..=text.len())
+                .rev()
+                .filter(|&end| text.is_char_boundary(end))
+                .find_map(|end| parse_number(&text[..end]))
+                .unwrap_or(Self::Integer(0))
+        };
+        match self {
+            Self::Text(text) => numeric_prefix(text),
+            Self::Blob(blob) => numeric_prefix(&String::from_utf8_lossy(blob)),
+            value => value.clone(),
+        }
+    }
+
+    /// The value as a real, 0 for values that aren't numbers
+    pub fn as_f64(&self) -> f64 {
+        match self {
+            Self::Integer(num) => *num as f64,
+            Self::Real(num) => *num,
+            _ => 0.0,
+        }
+    }
+
+    pub fn as_value_ref(&self) -> ValueRef<'_> {
+        match self {
+            Self::Null => ValueRef::Null,
+            Self::Integer(num) => ValueRef::Integer(*num),
+            Self::Real(num) => ValueRef::Real(*num),
+            Self::Text(text) => ValueRef::Text(Cow::Borrowed(text)),
+            Self::Blob(blob) => ValueRef::Blob(blob),
+        }
+    }
+
+    /// Order two values the way SQLite sorts them, see `ValueRef::compare`
+    pub fn compare(&self, other: &Self, encoding: TextEncoding) -> Ordering {
+        self.as_value_ref().compare(&other.as_value_ref(), encoding)
+    }
+
+    /// Convert the value the way a column of `affinity` does: INTEGER, REAL and NUMERIC turn text
+    /// that reads as a number into that number, TEXT turns numbers into text and BLOB keeps it as is
+    pub fn apply_affinity(self, affinity: Affinity) -> Self {
+        match (affinity, self) {
+            (Affinity::Integer | Affinity::Numeric, Self::Text(text)) => {
+                match parse_number(&text) {
+                    Some(number) => number.integer_if_exact(),
+                    None => Self::Text(text),
+                }
+            }
+            (Affinity::Integer | Affinity::Numeric, real @ Self::Real(_)) => {
+                real.integer_if_exact()
+            }
+            (Affinity::Real, Self::Text(text)) => match parse_number(&text) {
+                Some(Self::Integer(num)) => Self::Real(num as f64),
+                Some(number) => number,
+                None => Self::Text(text),
+            },
+            (Affinity::Real, Self::Integer(num)) => Self::Real(num as f64),
+            (Affinity::Text, number @ (Self::Integer(_) | Self::Real(_))) => {
+                Self::Text(number.to_string())
+            }
+            (_, value) => value,
+        }
+    }
+
+    /// A real without a fractional part that fits in 64 bits as an integer, any other value as is
+    fn integer_if_exact(self) -> Self {
+        match self {
+            Self::Real(num) if num.fract() == 0.0 && (i64::MIN as f64..I64_END).contains(&num) => {
+                Self::Integer(num as i64)
+            }
+            value => value,
+        }
+    }
+
+    /// The bytes query output shows for this value, with blobs in `blob_format`
+    pub fn to_output(&self, blob_format: BlobFormat) -> Vec<u8> {
+        let hex = |blob: &[u8]| {
+            blob.iter()
+                .map(|byte| format!("{:02X}", byte))
+                .collect::<String>()
+        };
+        match (self, blob_format) {
+            (Self::Blob(blob), BlobFormat::Raw) => blob.clone(),
+            (Self::Blob(blob), BlobFormat::Hex) => hex(blob).into_bytes(),
+            (Self::Blob(blob), BlobFormat::Literal) => format!("X'{}'", hex(blob)).into_bytes(),
+            (value, _) => value.to_string().into_bytes(),
+        }
+    }
+}
+
+impl ValueRef<'_> {
+    pub fn to_value(&self) -> Value {
+        match self {
+            Self::Null => Value::Null,
+            Self::Integer(num) => Value::Integer(*num),
+            Self::Real(num) => Value::Real(*num),
+            Self::Text(text) => Value::Text(text.clone().into_owned()),
+            Self::Blob(blob) => Value::Blob(blob.to_vec()),
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(self, Self::Null)
+    }
+
+    /// Order two values the way SQLite sorts them: NULL, then numbers by value, then text
+    /// by its bytes in the database `encoding`, as the BINARY collation does, then blobs
+    pub fn compare(&self, other: &Self, encoding: TextEncoding) -> Ordering {
+        let storage_class = |value: &Self| match value {
+            Self::Null => 0,
+            Self::Integer(_) | Self::Real(_) => 1,
+            Self::Text(_) => 2,
+            Self::Blob(_) => 3,
+        };
+        match (self, other) {
+            (Self::Integer(left), Self::Integer(right)) => left.cmp(right),
+            (Self::Real(left), Self::Real(right)) => {
+                left.partial_cmp(right).unwrap_or(Ordering::Equal)
+            }
+            (Self::Integer(left), Self::Real(right)) => compare_integer_real(*left, *right),
+            (Self::Real(left), Self::Integer(right)) => {
+                compare_integer_real(*right, *left).reverse()
+            }
+            (Self::Text(left), Self::Text(right)) => compare_text(left, right, encoding),
+            (Self::Blob(left), Self::Blob(right)) => left.cmp(right),
+            (left, right) => storage_class(left).cmp(&storage_class(right)),
+        }
+    }
+}
+
+/// Order two texts by their bytes in `encoding`. UTF-16le bytes compare like code units with
+/// their bytes swapped, UTF-16be bytes like the code units themselves.
+fn compare_text(left: &str, right: &str, encoding: TextEncoding) -> Ordering {
+    match encoding {
+        TextEncoding::Utf8 => left.as_bytes().cmp(right.as_bytes()),
+        TextEncoding::Utf16le => left
+            .encode_utf16()
+            .map(u16::swap_bytes)
+            .cmp(right.encode_utf16().map(u16::swap_bytes)),
+        TextEncoding::Utf16be => left.encode_utf16().cmp(right.encode_utf16()),
+    }
+}
+
+/// Order an integer against a real without rounding either of them
+fn compare_integer_real(integer: i64, real: f64) -> Ordering {
+    if real.is_nan() {
+        return Ordering::Greater;
+    }
+    if real < i64::MIN as f64 {
+        return Ordering::Greater;
+    }
+    if real >= I64_END {
+        return Ordering::Less;
+    }
+    match integer.cmp(&(real as i64)) {
+        // Same integer part, the fractional part of the real decides
+        Ordering::Equal => real.trunc().partial_cmp(&real).unwrap_or(Ordering::Equal),
+        ordering => ordering,
+    }
+}
+
+/// The number a text reads as, ignoring surrounding spaces, if it is an integer or real literal
+fn parse_number(text: &str) -> Option<Value> {
+    let text = text.trim();
+    let is_number = text.chars().any(|c| c.is_ascii_digit())
+        && text
+            .chars()
+            .all(|c| c.is_ascii_digit() || matches!(c, '.' | 'e' | 'E' | '+' | '-'));
+    if !is_number {
+        return None;
+    }
+    match text.parse::<i64>() {
+        Ok(num) => Some(Value::Integer(num)),
+        Err(_) => text.parse::<f64>().ok().map(Value::Real),
+    }
+}
+
+/// Format a real the way SQLite prints it: 15 significant digits, at least one decimal,
+/// and an exponent for very large and very small numbers
+fn format_real(num: f64) -> String {
+    if !num.is_finite() {
+        return match num {
+            num if num.is_nan() => "NaN".to_owned(),
+            num if num > 0.0 => "Inf".to_owned(),
+            _ => "-Inf".to_owned(),
+        };
+    }
+    let trim_zeros = |digits: &str| match digits.find('.') {
+        Some(_) => {
+            let digits = digits.trim_end_matches('0');
+            match digits.strip_suffix('.') {
+                Some(digits) => format!("{}.0", digits),
+                None => digits.to_owned(),
+            }
+        }
+        None => format!("{}.0", digits),
+    };
+    let scientific = format!("{:.14e}", num);
+    let (mantissa, exponent) = scientific.split_once('e').unwrap_or((&scientific, "0"));
+    let exponent = exponent.parse::<i32>().unwrap_or_default();
+    if !(-4..15).contains(&exponent) {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", trim_zeros(mantissa), sign, exponent.abs())
+    } else {
+        trim_zeros(&format!("{:.*}", (14 - exponent) as usize, num))
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Null => Ok(()),
+            Self::Integer(num) => write!(f, "{}", num),
+            Self::Real(num) => write!(f, "{}", format_real(*num)),
+            Self::Text(text) => write!(f, "{}", text),
+            Self::Blob(blob) => write!(f, "{}", String::from_utf8_lossy(blob)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_storage_class_order() {
+        let ordered = [
+            Value::Null,
+            Value::Integer(-3),
+            Value::Real(2.5),
+            Value::Integer(3),
+            Value::Real(1e100),
+            Value::Text("Apple".to_owned()),
+            Value::Text("apple".to_owned()),
+            Value::Blob(vec![0]),
+        ];
+        let utf8 = TextEncoding::Utf8;
+        for pair in ordered.windows(2) {
+            assert_eq!(
+                pair[0].compare(&pair[1], utf8),
+                Ordering::Less,
+                "{:?}",
+                pair
+            );
+            assert_eq!(
+                pair[1].compare(&pair[0], utf8),
+                Ordering::Greater,
+                "{:?}",
+                pair
+            );
+        }
+        assert_eq!(
+            Value::Integer(7).compare(&Value::Real(7.0), utf8),
+            Ordering::Equal
+        );
+        // Beyond 2^53 an integer and the nearest real still differ
+        let big = (1 << 60) + 1;
+        assert_eq!(
+            Value::Integer(big).compare(&Value::Real(big as f64), utf8),
+            Ordering::Greater
+        );
+    }
+
+    #[test]
+    fn test_text_order_follows_encoding() {
+        // U+0101 is 01 01 in UTF-16le and sorts before 'a', 61 00, unlike in UTF-8 and UTF-16be
+        let (a, a_macron) = (
+            Value::Text("a".to_owned()),
+            Value::Text("\u{101}".to_owned()),
+        );
+        assert_eq!(a.compare(&a_macron, TextEncoding::Utf8), Ordering::Less);
+        assert_eq!(a.compare(&a_macron, TextEncoding::Utf16be), Ordering::Less);
+        assert_eq!(
+            a.compare(&a_macron, TextEncoding::Utf16le),
+            Ordering::Greater
+        );
+        // Characters beyond the BMP are surrogate pairs, which sort before U+E000 in UTF-16
+        let (emoji, private) = (
+            Value::Text("\u{1F600}".to_owned()),
+            Value::Text("\u{E000}".to_owned()),
+        );
+        assert_eq!(
+            emoji.compare(&private, TextEncoding::Utf8),
+            Ordering::Greater
+        );
+        assert_eq!(
+            emoji.compare(&private, TextEncoding::Utf16be),
+            Ordering::Less
+        );
+    }
+
+    #[test]
+    fn test_affinity() {
+        assert_eq!(Affinity::from_declared_type("INTEGER"), Affinity::Integer);
+        assert_eq!(Affinity::from_declared_type("varchar(20)"), Affinity::Text);
+        assert_eq!(Affinity::from_declared_type(""), Affinity::Blob);
+        assert_eq!(
+            Affinity::from_declared_type("DOUBLE PRECISION"),
+            Affinity::Real
+        );
+        assert_eq!(
+            Affinity::from_declared_type("DECIMAL(10,2)"),
+            Affinity::Numeric
+        );
+        // "POINT" contains "INT"
+        assert_eq!(
+            Affinity::from_declared_type("FLOATING POINT"),
+            Affinity::Integer
+        );
+
+        let text = |text: &str| Value::Text(text.to_owned());
+        assert_eq!(
+            text(" 7 ").apply_affinity(Affinity::Integer),
+            Value::Integer(7)
+        );
+        assert_eq!(
+            text("2.0").apply_affinity(Affinity::Numeric),
+            Value::Integer(2)
+        );
+        assert_eq!(
+            text("9.5").apply_affinity(Affinity::Numeric),
+            Value::Real(9.5)
+        );
+        assert_eq!(text("7").apply_affinity(Affinity::Real), Value::Real(7.0));
+        assert_eq!(text("7").apply_affinity(Affinity::Blob), text("7"));
+        assert_eq!(text("1e").apply_affinity(Affinity::Integer), text("1e"));
+        assert_eq!(text("inf").apply_affinity(Affinity::Real), text("inf"));
+        assert_eq!(Value::Integer(7).apply_affinity(Affinity::Text), text("7"));
+        assert_eq!(Value::Real(0.5).apply_affinity(Affinity::Text), text("0.5"));
+    }
+
+    #[test]
+    fn test_format_real() {
+        assert_eq!(Value::Real(9.5).to_string(), "9.5");
+        assert_eq!(Value::Real(10.0).to_string(), "10.0");
+        assert_eq!(Value::Real(-0.1).to_string(), "-0.1");
+        assert_eq!(Value::Real(1.0 / 3.0).to_string(), "0.333333333333333");
+        assert_eq!(Value::Real(1e14).to_string(), "100000000000000.0");
+        assert_eq!(Value::Real(1e15).to_string(), "1.0e+15");
+        assert_eq!(Value::Real(1.5e-7).to_string(), "1.5e-07");
+        assert_eq!(Value::Null.to_string(), "");
+    }
+}
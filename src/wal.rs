@@ -0,0 +1,451 @@
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const WAL_HEADER_SIZE: usize = 32;
+pub const FRAME_HEADER_SIZE: usize = 24;
+/// The last bit of the magic number tells whether checksums read the data as big-endian words
+const WAL_MAGIC_LITTLE_ENDIAN: u32 = 0x377f0682;
+const WAL_MAGIC_BIG_ENDIAN: u32 = 0x377f0683;
+const WAL_FORMAT_VERSION: u32 = 3007000;
+
+#[derive(Debug, Clone)]
+pub struct WalHeader {
+    pub big_endian_checksum: bool,
+    pub page_size: u32,
+    pub checkpoint_sequence: u32,
+    pub salt: (u32, u32),
+    pub checksum: (u32, u32),
+}
+
+impl WalHeader {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let read_u32 =
+            |offset: usize| u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap());
+
+        let big_endian_checksum = match read_u32(0) {
+            WAL_MAGIC_LITTLE_ENDIAN => false,
+            WAL_MAGIC_BIG_ENDIAN => true,
+            magic => bail!("Invalid WAL magic number {:#010x}", magic),
+        };
+        if read_u32(4) != WAL_FORMAT_VERSION {
+            bail!("Unsupported WAL format version {}", read_u32(4));
+        }
+        let header = Self {
+            big_endian_checksum,
+            page_size: match read_u32(8) {
+                1 => 65536,
+                size => size,
+            },
+            checkpoint_sequence: read_u32(12),
+            salt: (read_u32(16), read_u32(20)),
+            checksum: (read_u32(24), read_u32(28)),
+        };
+        if checksum(big_endian_checksum, &bytes[..24], (0, 0)) != header.checksum {
+            bail!("WAL header checksum mismatch");
+        }
+        Ok(header)
+    }
+
+    /// The header bytes, with the checksum computed over the first 24 of them
+    pub fn to_bytes(&self) -> [u8; WAL_HEADER_SIZE] {
+        let magic = match self.big_endian_checksum {
+            true => WAL_MAGIC_BIG_ENDIAN,
+            false => WAL_MAGIC_LITTLE_ENDIAN,
+        };
+        let mut bytes = [0; WAL_HEADER_SIZE];
+        for (i, value) in [
+            magic,
+            WAL_FORMAT_VERSION,
+            self.page_size,
+            self.checkpoint_sequence,
+            self.salt.0,
+            self.salt.1,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            bytes[i * 4..i * 4 + 4].copy_from_slice(&value.to_be_bytes());
+        }
+        let checksum = checksum(self.big_endian_checksum, &bytes[..24], (0, 0));
+        bytes[24..28].copy_from_slice(&checksum.0.to_be_bytes());
+        bytes[28..].copy_from_slice(&checksum.1.to_be_bytes());
+        bytes
+    }
+}
+
+fn random_salt() -> u32 {
+    let clock = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    clock.subsec_nanos() ^ clock.as_secs() as u32 ^ std::process::id()
+}
+
+/// The committed frames of a `<db>-wal` file, newer copies of pages than the ones in the database file.
+/// Transactions are committed by appending their pages as frames, the last one marked as the commit frame.
+pub struct Wal {
+    file: File,
+    header: WalHeader,
+    /// Offset of the page content of the latest committed frame of each page
+    frames: HashMap<u64, u64>,
+    /// Database size in pages recorded by the last commit frame
+    database_size: Option<u32>,
+    /// Number of frames up to the last commit frame, new frames are written after them
+    frame_count: u32,
+    /// Checksum of the last commit frame, which the checksum of the next frame continues from
+    running_checksum: (u32, u32),
+}
+
+impl Wal {
+    /// Open the WAL file next to the database, if there is one with a valid header
+    pub fn open(db_filepath: &str) -> Result<Option<Self>> {
+        let path = format!("{}-wal", db_filepath);
+        let mut file = match OpenOptions::new().read(true).write(true).open(&path) {
+            Ok(file) => file,
+            Err(_) => match File::open(&path) {
+                Ok(file) => file,
+                Err(_) => return Ok(None),
+            },
+        };
+        let mut header_bytes = [0; WAL_HEADER_SIZE];
+        if file.read_exact(&mut header_bytes).is_err() {
+            // An empty WAL holds no frames
+            return Ok(None);
+        }
+        let header = match WalHeader::from_bytes(&header_bytes) {
+            Ok(header) => header,
+            Err(_) => return Ok(None),
+        };
+
+        let mut wal = Self {
+            file,
+            running_checksum: header.checksum,
+            header,
+            frames: HashMap::new(),
+            database_size: None,
+            frame_count: 0,
+        };
+        wal.build_index()?;
+        Ok(Some(wal))
+    }
+
+    /// Start a new, empty WAL file next to the database, replacing whatever is there
+    pub fn create(db_filepath: &str, page_size: u32) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(format!("{}-wal", db_filepath))?;
+        let header = WalHeader {
+            big_endian_checksum: cfg!(target_endian = "big"),
+            page_size,
+            checkpoint_sequence: 0,
+            salt: (random_salt(), random_salt().rotate_left(16)),
+            checksum: (0, 0),
+        };
+        let mut wal = Self {
+            file,
+            running_checksum: (0, 0),
+            header,
+            frames: HashMap::new(),
+            database_size: None,
+            frame_count: 0,
+        };
+        wal.write_header()?;
+        Ok(wal)
+    }
+
+    /// Start the WAL over once its frames have been copied to the database file.
+    /// The new salt invalidates the old frames that are not overwritten right away.
+    pub fn reset(&mut self) -> Result<()> {
+        self.header.checkpoint_sequence = self.header.checkpoint_sequence.wrapping_add(1);
+        self.header.salt = (self.header.salt.0.wrapping_add(1), random_salt());
+        self.frames.clear();
+        self.database_size = None;
+        self.frame_count = 0;
+        self.write_header()
+    }
+
+    fn write_header(&mut self) -> Result<()> {
+        let bytes = self.header.to_bytes();
+        self.header = WalHeader::from_bytes(&bytes)?;
+        self.running_checksum = self.header.checksum;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&bytes)?;
+        self.file.set_len(WAL_HEADER_SIZE as u64)?;
+        self.file.sync_all()?;
+        Ok(())
+    }
+
+    /// Commit a transaction by appending a frame for each of its pages, the last one being
+    /// the commit frame that records the database size in pages after the transaction.
+    /// Frames of a transaction that never committed are overwritten.
+    pub fn append_transaction(&mut self, pages: &[(u64, &[u8])], database_size: u32) -> Result<()> {
+        // Pages past the end of the database don't need to be written
+        let pages = pages
+            .iter()
+            .filter(|(page_num, _)| *page_num <= database_size as u64)
+            .collect::<Vec<_>>();
+        if pages.is_empty() {
+            return Ok(());
+        }
+        let page_size = self.header.page_size as usize;
+        let start = self.frame_offset(self.frame_count);
+        let mut frames = Vec::with_capacity(pages.len() * (FRAME_HEADER_SIZE + page_size));
+        let mut running_checksum = self.running_checksum;
+        for (i, (page_num, page)) in pages.iter().enumerate() {
+            if page.len() != page_size {
+                bail!("Expected a page of {} bytes, got {}", page_size, page.len());
+            }
+            let commit_size = if i + 1 == pages.len() {
+                database_size
+            } else {
+                0
+            };
+            let mut frame_header = [0; FRAME_HEADER_SIZE];
+            for (j, value) in [
+                *page_num as u32,
+                commit_size,
+                self.header.salt.0,
+                self.header.salt.1,
+            ]
+            .into_iter()
+            .enumerate()
+            {
+                frame_header[j * 4..j * 4 + 4].copy_from_slice(&value.to_be_bytes());
+            }
+            running_checksum = checksum(
+                self.header.big_endian_checksum,
+                &frame_header[..8],
+                running_checksum,
+            );
+            running_checksum = checksum(self.header.big_endian_checksum, page, running_checksum);
+            frame_header[16..20].copy_from_slice(&running_checksum.0.to_be_bytes());
+            frame_header[20..].copy_from_slice(&running_checksum.1.to_be_bytes());
+            frames.extend_from_slice(&frame_header);
+            frames.extend_from_slice(page);
+        }
+
+        self.file.seek(SeekFrom::Start(start))?;
+        self.file.write_all(&frames)?;
+        self.file.sync_all()?;
+
+        for (i, (page_num, _)) in pages.iter().enumerate() {
+            let offset = self.frame_offset(self.frame_count + i as u32);
+            self.frames
+                .insert(*page_num, offset + FRAME_HEADER_SIZE as u64);
+        }
+        self.frame_count += pages.len() as u32;
+        self.database_size = Some(database_size);
+        self.running_checksum = running_checksum;
+        Ok(())
+    }
+
+    fn frame_offset(&self, frame: u32) -> u64 {
+        let frame_size = FRAME_HEADER_SIZE as u64 + self.header.page_size as u64;
+        WAL_HEADER_SIZE as u64 + frame as u64 * frame_size
+    }
+
+    /// Read frames until the first one whose salt or checksum doesn't match, indexing the
+    /// frames of every transaction that ended in a commit frame
+    fn build_index(&mut self) -> Result<()> {
+        let page_size = self.header.page_size as usize;
+        let mut frame = vec![0; FRAME_HEADER_SIZE + page_size];
+        let mut running_checksum = self.header.checksum;
+        let mut uncommitted = HashMap::new();
+        let mut offset = WAL_HEADER_SIZE as u64;
+        let mut frame_count = 0;
+
+        self.file.seek(SeekFrom::Start(offset))?;
+        while self.file.read_exact(&mut frame).is_ok() {
+            let read_u32 =
+                |offset: usize| u32::from_be_bytes(frame[offset..offset + 4].try_into().unwrap());
+            let page_num = read_u32(0);
+            let database_size = read_u32(4);
+            if (read_u32(8), read_u32(12)) != self.header.salt {
+                break;
+            }
+            running_checksum = checksum(
+                self.header.big_endian_checksum,
+                &frame[..8],
+                running_checksum,
+            );
+            running_checksum = checksum(
+                self.header.big_endian_checksum,
+                &frame[FRAME_HEADER_SIZE..],
+                running_checksum,
+            );
+            if (read_u32(16), read_u32(20)) != running_checksum {
+                break;
+            }
+
+            uncommitted.insert(page_num as u64, offset + FRAME_HEADER_SIZE as u64);
+            frame_count += 1;
+            // Commit frames record the database size after the transaction
+            if database_size != 0 {
+                self.frames.extend(uncommitted.drain());
+                self.database_size = Some(database_size);
+                self.frame_count = frame_count;
+                self.running_checksum = running_checksum;
+            }
+            offset += frame.len() as u64;
+        }
+        Ok(())
+    }
+
+    pub fn header(&self) -> &WalHeader {
+        &self.header
+    }
+
+    pub fn database_size(&self) -> Option<u32> {
+        self.database_size
+    }
+
+    pub fn contains(&self, page_num: u64) -> bool {
+        self.frames.contains_key(&page_num)
+    }
+
+    pub fn frame_count(&self) -> u32 {
+        self.frame_count
+    }
+
+    /// Whether the WAL file still holds the frames this WAL read, rather than frames another
+    /// connection committed or a WAL it started over since
+    pub fn is_current(&self, db_filepath: &str) -> Result<bool> {
+        Ok(match Wal::open(db_filepath)? {
+            Some(on_disk) => {
+                on_disk.header.salt == self.header.salt
+                    && on_disk.header.checkpoint_sequence == self.header.checkpoint_sequence
+                    && on_disk.frame_count == self.frame_count
+            }
+            None => false,
+        })
+    }
+
+    /// The pages that have a committed frame, in ascending order
+    pub fn page_numbers(&self) -> Vec<u64> {
+        let mut page_numbers = self.frames.keys().copied().collect::<Vec<_>>();
+        page_numbers.sort_unstable();
+        page_numbers
+    }
+
+    /// The latest committed copy of a page, if the WAL has one
+    pub fn read_page(&mut self, page_num: u64) -> Result<Option<Vec<u8>>> {
+        let offset = match self.frames.get(&page_num) {
+            Some(offset) => *offset,
+            None => return Ok(None),
+        };
+        let mut page = vec![0; self.header.page_size as usize];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut page)?;
+        Ok(Some(page))
+    }
+}
+
+/// The WAL checksum over `data`, a multiple of 8 bytes, continuing from `(s0, s1)`
+pub fn checksum(big_endian: bool, data: &[u8], (mut s0, mut s1): (u32, u32)) -> (u32, u32) {
+    let to_u32 = if big_endian {
+        u32::from_be_bytes
+    } else {
+        u32::from_le_bytes
+    };
+    for words in data.chunks_exact(8) {
+        let x0 = to_u32(words[..4].try_into().unwrap());
+        let x1 = to_u32(words[4..].try_into().unwrap());
+        s0 = s0.wrapping_add(x0).wrapping_add(s1);
+        s1 = s1.wrapping_add(x1).wrapping_add(s0);
+    }
+    (s0, s1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pager::Pager;
+    use crate::util::tests::copy_sample_db;
+
+    /// A WAL file with one frame per page, each transaction committed unless `commit` is false
+    fn build_wal(page_size: u32, frames: &[(u32, Vec<u8>, bool)]) -> Vec<u8> {
+        let mut wal = Vec::new();
+        for value in [WAL_MAGIC_BIG_ENDIAN, WAL_FORMAT_VERSION, page_size, 0, 7, 9] {
+            wal.extend_from_slice(&value.to_be_bytes());
+        }
+        let mut running = checksum(true, &wal, (0, 0));
+        wal.extend_from_slice(&running.0.to_be_bytes());
+        wal.extend_from_slice(&running.1.to_be_bytes());
+
+        for (page_num, page, commit) in frames {
+            let mut frame_header = Vec::new();
+            let database_size: u32 = if *commit { 4 } else { 0 };
+            for value in [*page_num, database_size, 7, 9] {
+                frame_header.extend_from_slice(&value.to_be_bytes());
+            }
+            running = checksum(true, &frame_header[..8], running);
+            running = checksum(true, page, running);
+            frame_header.extend_from_slice(&running.0.to_be_bytes());
+            frame_header.extend_from_slice(&running.1.to_be_bytes());
+            wal.extend_from_slice(&frame_header);
+            wal.extend_from_slice(page);
+        }
+        wal
+    }
+
+    #[test]
+    fn test_checksum() {
+        let data = [0, 0, 0, 1, 0, 0, 0, 2];
+        assert_eq!(checksum(true, &data, (0, 0)), (1, 3));
+        assert_eq!(checksum(false, &data, (0, 0)), (1 << 24, 3 << 24));
+    }
+
+    #[test]
+    fn test_pager_reads_committed_wal_frames() -> Result<()> {
+        let db_path = copy_sample_db("wal-committed-frames");
+        let original = std::fs::read(&db_path)?;
+        let mut committed = original[4096..8192].to_vec();
+        committed[4000] = 0xaa;
+        let mut uncommitted = original[8192..12288].to_vec();
+        uncommitted[4000] = 0xbb;
+        std::fs::write(
+            format!("{}-wal", db_path),
+            build_wal(4096, &[(2, committed, true), (3, uncommitted, false)]),
+        )?;
+
+        let mut pager = Pager::open(&db_path)?;
+        assert_eq!(pager.read_page(2)?[4000], 0xaa);
+        assert_eq!(pager.read_page(3)?[4000], original[8192 + 4000]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_commit_and_checkpoint_in_wal_mode() -> Result<()> {
+        let db_path = crate::util::tests::copy_sample_db("wal-commit");
+        let original = std::fs::read(&db_path)?;
+        let mut pager = Pager::open(&db_path)?;
+        pager.set_wal_mode(true)?;
+
+        let mut page = pager.read_page(2)?.to_vec();
+        page[4000] = 0xaa;
+        pager.write_page(2, page)?;
+        pager.commit()?;
+        // The database file is only touched by the checkpoint
+        assert_eq!(std::fs::read(&db_path)?[4096 + 4000], original[4096 + 4000]);
+        let mut reader = Pager::open(&db_path)?;
+        assert_eq!(reader.read_page(2)?[4000], 0xaa);
+        assert!(reader.header().is_wal_mode());
+
+        assert_eq!(pager.checkpoint()?, Some(2));
+        assert_eq!(std::fs::read(&db_path)?[4096 + 4000], 0xaa);
+        assert_eq!(Wal::open(&db_path)?.unwrap().frame_count(), 0);
+        assert_eq!(Pager::open(&db_path)?.read_page(2)?[4000], 0xaa);
+
+        // The checkpoint started the WAL over, the reader can't commit on top of the frames it read
+        let page = reader.read_page(3)?.to_vec();
+        reader.write_page(3, page)?;
+        assert!(reader.commit().is_err());
+        assert!(reader.checkpoint().is_err());
+        Ok(())
+    }
+}